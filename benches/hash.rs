@@ -0,0 +1,128 @@
+//! Throughput benchmarks for hashing file trees.
+//!
+//! The synthetic trees are generated once under the system temp directory
+//! and reused between runs. Set `BS_BENCH_SCALE` to multiply the number of
+//! files in each tree, or `BS_BENCH_ROOT` to also benchmark an existing tree.
+//! Criterion counts throughput in 32 bits, so a tree of 4 GiB or more is
+//! skipped, e.g. `few_large_files` from a scale of 16 on.
+//! Compare changes with criterion's baselines:
+//!
+//!     cargo bench -- --save-baseline before
+//!     cargo bench -- --baseline before
+
+#[macro_use]
+extern crate criterion;
+extern crate bs;
+
+use bs::{Algorithm, AlgorithmChoice, Hasher};
+use criterion::{Benchmark, Criterion, Throughput};
+use std::env;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+const KIB: usize = 1024;
+const MIB: usize = 1024 * KIB;
+
+fn scale() -> usize {
+    env::var("BS_BENCH_SCALE").ok().and_then(|s| s.parse().ok()).unwrap_or(1)
+}
+
+fn write_file(path: &Path, size: usize, seed: usize) {
+    let data: Vec<u8> = (0..size).map(|i| (i.wrapping_mul(31) ^ seed) as u8).collect();
+    File::create(path).and_then(|mut f| f.write_all(&data)).unwrap();
+}
+
+// creates the tree with the generator unless it already exists and returns
+// its root and the number of bytes in it
+fn tree<F: Fn(&Path) -> usize>(name: &str, generate: F) -> (PathBuf, usize) {
+    let root = env::temp_dir().join(format!("bs-bench-{}-x{}", name, scale()));
+    let size_file = PathBuf::from(format!("{}.size", root.display()));
+    if let Ok(size) = fs::read_to_string(&size_file) {
+        return (root, size.trim().parse().unwrap());
+    }
+    let _ = fs::remove_dir_all(&root);
+    fs::create_dir_all(&root).unwrap();
+    let size = generate(&root);
+    fs::write(&size_file, format!("{}", size)).unwrap();
+    (root, size)
+}
+
+fn many_small_files(root: &Path) -> usize {
+    let files = 10_000 * scale();
+    for i in 0..files {
+        let dir = root.join(format!("{:03}", i % 100));
+        fs::create_dir_all(&dir).unwrap();
+        write_file(&dir.join(format!("{}.bin", i)), KIB, i);
+    }
+    files * KIB
+}
+
+fn few_large_files(root: &Path) -> usize {
+    let files = 4 * scale();
+    for i in 0..files {
+        write_file(&root.join(format!("{}.bin", i)), 64 * MIB, i);
+    }
+    files * 64 * MIB
+}
+
+fn deep_nesting(root: &Path) -> usize {
+    let depth = 64;
+    let mut size = 0;
+    for branch in 0..4 * scale() {
+        let mut dir = root.join(format!("branch{}", branch));
+        for level in 0..depth {
+            dir = dir.join(format!("level{}", level));
+            fs::create_dir_all(&dir).unwrap();
+            for i in 0..4 {
+                write_file(&dir.join(format!("{}.bin", i)), 4 * KIB, level * 4 + i);
+                size += 4 * KIB;
+            }
+        }
+    }
+    size
+}
+
+fn bench_tree(c: &mut Criterion, name: &str, root: PathBuf, size: usize, algorithm: Algorithm) {
+    // the throughput would wrap around and be reported wrong
+    if size > u32::max_value() as usize {
+        eprintln!("Skipping {}: {} bytes is too many to count the throughput of, lower BS_BENCH_SCALE", name, size);
+        return;
+    }
+    c.bench(name, Benchmark::new(algorithm.name(), move |b| {
+        b.iter(|| {
+            Hasher::new()
+                .progress(false)
+                .algorithm(AlgorithmChoice::Fixed(algorithm))
+                .hash(vec![root.clone()])
+                .unwrap()
+        })
+    })
+    .sample_size(10)
+    .throughput(Throughput::Bytes(size as u32)));
+}
+
+fn hash_trees(c: &mut Criterion) {
+    let trees = vec![
+        ("many_small_files", tree("many-small-files", many_small_files)),
+        ("few_large_files", tree("few-large-files", few_large_files)),
+        ("deep_nesting", tree("deep-nesting", deep_nesting)),
+    ];
+    for (name, (root, size)) in trees {
+        for &algorithm in &[Algorithm::Sha512_256, Algorithm::Blake3] {
+            bench_tree(c, name, root.clone(), size, algorithm);
+        }
+    }
+
+    // the throughput of an existing tree isn't known up front so it is only
+    // timed
+    if let Ok(root) = env::var("BS_BENCH_ROOT") {
+        let root = PathBuf::from(root);
+        c.bench_function("custom_root", move |b| {
+            b.iter(|| Hasher::new().progress(false).hash(vec![root.clone()]).unwrap())
+        });
+    }
+}
+
+criterion_group!(benches, hash_trees);
+criterion_main!(benches);
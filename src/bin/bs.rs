@@ -1,9 +1,15 @@
 extern crate bs;
+extern crate glob;
+extern crate humantime;
+extern crate num_cpus;
 extern crate structopt;
-extern crate sodiumoxide;
 
-use bs::{sign, verify};
-use std::fs::File;
+use bs::capabilities::Capabilities;
+use bs::tofu::{TofuStatus, TofuStore};
+use bs::{diff, reencode, rotate, sign, verify, AlgorithmChoice, AlgorithmSet, Compression, DigestEncoding, HardlinkMode,
+         ProgressTemplate, ReadStrategy, SignOptions, SignatureSink, SortBy, SymlinkMode, VerifyKey, VerifyOptions, VerifySummary};
+use glob::Pattern;
+use std::fs::{self, File};
 use std::io::{self, Write};
 use std::path::{Path, PathBuf};
 use structopt::StructOpt;
@@ -24,6 +30,17 @@ struct Opt {
     #[structopt(long = "status-fd")]
     fd: Option<u32>,
 
+    /// unlock secret keys even if the DIDDir is open to other users
+    #[structopt(long = "insecure-permissions")]
+    insecure_permissions: bool,
+
+    /// read the passphrase that seals the secret keys from this file, e.g. a
+    /// mounted secret, one trailing newline is trimmed. It is used first,
+    /// then the built-in passphrase "test", which anyone can unlock the keys
+    /// with. There is no --passphrase-fd and BS_PASSPHRASE is never read
+    #[structopt(long = "passphrase-file", parse(from_os_str))]
+    passphrase_file: Option<PathBuf>,
+
     /// the subcommand operation
     #[structopt(subcommand)]
     cmd: Command
@@ -43,15 +60,249 @@ enum Command {
         #[structopt(long = "id")]
         id: Option<String>,
 
-        /// The format of the signature output, either "lds" or "jwt"
+        /// Sign with the Ed25519 key in this age-encrypted file instead of
+        /// an identity, needs the "age" feature. The passphrase is prompted
+        /// for unless it is encrypted to the --age-identity.
+        #[structopt(long = "age-key", parse(from_os_str))]
+        age_key: Option<PathBuf>,
+
+        /// The age identity file to decrypt the --age-key with.
+        #[structopt(long = "age-identity", parse(from_os_str))]
+        age_identity: Option<PathBuf>,
+
+        /// The format of the signature output, "slsa" for a DSSE signed SLSA
+        /// provenance statement, "minisign" for a minisign signature over the
+        /// manifest in <output>.minisig, "ssh" for an OpenSSH signature over
+        /// the manifest in <output>.sshsig, "ndjson" for a manifest with one
+        /// line per file for streaming, or the manifest if unspecified.
         #[structopt(long = "format")]
         fmt: Option<String>,
 
+        /// The namespace of an SSH signature, "file" if unspecified. It has
+        /// to match the -n given to ssh-keygen -Y verify.
+        #[structopt(long = "ssh-namespace")]
+        ssh_namespace: Option<String>,
+
         /// The file to save the signature in or stdout if unspecified.
         #[structopt(short = "o", parse(from_os_str))]
         output: Option<PathBuf>,
 
+        /// Write just the files to the output and the signature to a
+        /// separate <output>.sig file.
+        #[structopt(long = "detached-sig")]
+        detached: bool,
+
+        /// A leading path prefix to strip from every path in the manifest.
+        #[structopt(long = "trim-prefix", parse(from_os_str))]
+        trim_prefix: Option<PathBuf>,
+
+        /// Resolve the files under this directory and record them relative
+        /// to it. Unlike --trim-prefix nothing outside of it is read, a path
+        /// that leaves it through ".." or a symlink is an error.
+        #[structopt(long = "root", parse(from_os_str))]
+        root: Option<PathBuf>,
+
+        /// The number of times to retry reading a file after a transient IO
+        /// error, defaults to 3.
+        #[structopt(long = "retries")]
+        retries: Option<u32>,
+
+        /// The URL of an RFC 3161 Time Stamping Authority to timestamp the
+        /// signature with (requires the "tsa" feature).
+        #[structopt(long = "tsa")]
+        tsa: Option<String>,
+
+        /// Error if a file is given or found more than once.
+        #[structopt(long = "strict")]
+        strict: bool,
+
+        /// Error if a fifo, socket or device is given or found instead of
+        /// leaving it out.
+        #[structopt(long = "strict-file-types")]
+        strict_file_types: bool,
+
+        /// Hash a file again if its size or modification time changed while
+        /// it was hashed, and skip it as changed during hashing if it is
+        /// still changing.
+        #[structopt(long = "verify-stable")]
+        verify_stable: bool,
+
+        /// Error if two files with different contents have the same digest,
+        /// files with identical contents may still share one.
+        #[structopt(long = "unique-content")]
+        unique_content: bool,
+
+        /// The number of threads to hash files with, 0 leaves one core free.
+        /// Defaults to the number of CPUs.
+        #[structopt(long = "threads")]
+        threads: Option<usize>,
+
+        /// Only count the files that would be signed and their size.
+        #[structopt(long = "count-only")]
+        count_only: bool,
+
+        /// Don't print a summary of the files hashed when done.
+        #[structopt(long = "quiet", short = "q")]
+        quiet: bool,
+
+        /// How progress is shown, "default", "minimal", "detailed", "none" or
+        /// an indicatif template such as "{bar} {pos}/{len}".
+        #[structopt(long = "progress-template", default_value = "default")]
+        progress_template: ProgressTemplate,
+
+        /// The digest algorithm, "sha512_256", "blake3" or "auto" to use
+        /// BLAKE3 for files of 16 MiB or more and SHA-512/256 otherwise.
+        #[structopt(long = "algorithm", default_value = "sha512_256")]
+        algorithm: AlgorithmChoice,
+
+        /// Refuse to sign unless the digest algorithm is in the comma
+        /// separated list, e.g. "sha512_256,blake3".
+        #[structopt(long = "allowed-algorithms")]
+        allowed_algorithms: Option<AlgorithmSet>,
+
+        /// How to handle symlinks, "skip" them, hash the "content" of the
+        /// files they point to or record their "target" in the manifest.
+        #[structopt(long = "symlinks", default_value = "skip")]
+        symlinks: SymlinkMode,
+
+        /// How to handle hardlinks to the same file, "hash-each" path on its
+        /// own or "share" one digest between them. Sharing is only supported
+        /// on unix.
+        #[structopt(long = "hardlink-mode", default_value = "hash-each")]
+        hardlink_mode: HardlinkMode,
+
+        /// How to read the files, "parallel" for SSDs or "sequential" to
+        /// read one at a time in path order, which is faster on spinning
+        /// disks.
+        #[structopt(long = "read-strategy", default_value = "parallel")]
+        read_strategy: ReadStrategy,
+
+        /// Hash the uncompressed contents of files compressed with "gzip" or
+        /// "zstd" (requires the "decompress" feature).
+        #[structopt(long = "decompress", default_value = "none")]
+        decompress: Compression,
+
+        /// How many directories deep to recurse into the given directories,
+        /// 0 only signs the files directly in them. Deeper directories are
+        /// skipped. Unlimited if unspecified.
+        #[structopt(long = "max-depth")]
+        max_depth: Option<usize>,
+
+        /// Only sign the files modified at or after this RFC 3339 time, e.g.
+        /// 2021-06-01T00:00:00Z. Older files are skipped. With --extend the
+        /// prior manifest keeps the entries of the unchanged files.
+        #[structopt(long = "modified-since")]
+        modified_since: Option<humantime::Timestamp>,
+
+        /// How digests are encoded in the manifest, "b64", "b64url", "hex"
+        /// or "base32".
+        #[structopt(long = "digest-encoding", default_value = "b64url")]
+        digest_encoding: DigestEncoding,
+
+        /// The order of the files in the manifest, by "path", "hash" or
+        /// "size".
+        #[structopt(long = "sort-by", default_value = "path")]
+        sort_by: SortBy,
+
+        /// Sign only the root of a Merkle tree over the files and write an
+        /// inclusion proof for every file to <output>.proofs.
+        #[structopt(long = "merkle")]
+        merkle: bool,
+
+        /// Record the alias of the signing identity next to the signature.
+        /// It isn't signed, so verify shows it only as a hint.
+        #[structopt(long = "include-signer-name")]
+        include_signer_name: bool,
+
+        /// Also sign the directory structure, every directory, file and
+        /// symlink under the given paths with its permission bits, so verify
+        /// notices added, removed and permission-changed entries.
+        #[structopt(long = "include-tree")]
+        include_tree: bool,
+
+        /// Also sign the unix permission bits of each file, so verify
+        /// notices a file whose contents match but whose mode changed. Modes
+        /// aren't recorded or checked off unix.
+        #[structopt(long = "include-mode")]
+        include_mode: bool,
+
+        /// Also sign the size of each file, so verify --size-precheck can
+        /// flag a resized file as modified without hashing it.
+        #[structopt(long = "include-size")]
+        include_size: bool,
+
+        /// Record every path in Unicode NFC so a tree signed on macOS, which
+        /// hands out decomposed names, matches one signed elsewhere. This
+        /// changes the signed bytes of such paths.
+        #[structopt(long = "normalize-paths")]
+        normalize_paths: bool,
+
+        /// Also sign a single SHA-512/256 digest over all of the paths and
+        /// their digests, for comparing manifests by one value.
+        #[structopt(long = "manifest-digest")]
+        manifest_digest: bool,
+
+        /// Also sign the empty directories, verify then checks that they
+        /// still exist and are empty.
+        #[structopt(long = "hash-empty-dirs")]
+        hash_empty_dirs: bool,
+
+        /// Comma-separated globs every signed path has to match, e.g.
+        /// "src/**,docs/**". Signing fails on a path outside of them and
+        /// they are signed so verify fails on one too.
+        #[structopt(long = "policy", raw(number_of_values = "1"))]
+        policy: Vec<String>,
+
+        /// Record the files that couldn't be read and why in the signed
+        /// manifest, verify lists them so it is clear they weren't checked.
+        #[structopt(long = "record-errors")]
+        record_errors: bool,
+
+        /// The context the manifest is signed for, e.g. "release". It is
+        /// recorded in the manifest and verify must be given the same one.
+        #[structopt(long = "context")]
+        context: Option<String>,
+
+        /// A manifest to add the files to, its files are kept and it is
+        /// signed again without its old signatures.
+        #[structopt(long = "extend", parse(from_os_str))]
+        extend: Option<PathBuf>,
+
+        /// Replace the digests of files that are already in the --extend
+        /// manifest instead of erroring.
+        #[structopt(long = "overwrite")]
+        overwrite: bool,
+
+        /// Hash at a lower priority so the machine stays responsive, only on
+        /// unix.
+        #[structopt(long = "nice")]
+        nice: bool,
+
+        /// Write the manifest as minified JSON instead of indented, it is
+        /// signed the same either way.
+        #[structopt(long = "compact")]
+        compact: bool,
+
+        /// Sync the output to disk before exiting so it survives a crash,
+        /// not just an interrupted write.
+        #[structopt(long = "fsync")]
+        fsync: bool,
+
+        /// Record the hashes in this journal file as they are done and reuse
+        /// the ones already in it, so an interrupted run can be resumed by
+        /// running it again. The file is removed once the signature is
+        /// written.
+        #[structopt(long = "resume", parse(from_os_str))]
+        resume: Option<PathBuf>,
+
+        /// Run the command given after -- and sign its stdout under the
+        /// command line instead of signing files. Nothing is signed if the
+        /// command fails.
+        #[structopt(long = "exec")]
+        exec: bool,
+
         /// List of files to sign or '-' if signing data passed through stdin.
+        /// With --exec, the command and its arguments.
         #[structopt(name = "FILES", parse(from_os_str))]
         files: Vec<PathBuf>,
     },
@@ -59,37 +310,876 @@ enum Command {
     #[structopt(name = "verify")]
     /// Verify the given signature
     Verify {
-        /// the manifest file to verify
+        /// DIDDir root path or default if unspecified.
+        #[structopt(long = "diddir")]
+        dir: Option<String>,
+
+        /// The pkid of the signer to trust instead of the DIDDir identities,
+        /// or a keyring file with one pkid per line.
+        #[structopt(long = "key")]
+        key: Option<String>,
+
+        /// The detached signature file for the manifest.
+        #[structopt(long = "sig", parse(from_os_str))]
+        sig: Option<PathBuf>,
+
+        /// A bare 64 byte Ed25519 signature file for the manifest, checked
+        /// with the --key pkid.
+        #[structopt(long = "sig-raw", parse(from_os_str))]
+        sig_raw: Option<PathBuf>,
+
+        /// A minisign signature file for the manifest.
+        #[structopt(long = "minisig", parse(from_os_str))]
+        minisig: Option<PathBuf>,
+
+        /// The minisign public key file to verify the minisign signature with.
+        #[structopt(long = "minisign-key", parse(from_os_str))]
+        minisign_key: Option<PathBuf>,
+
+        /// A path prefix to add to every path in the manifest.
+        #[structopt(long = "prepend", parse(from_os_str))]
+        prepend: Option<PathBuf>,
+
+        /// The encoding of the manifest digests, "b64", "b64url", "hex" or
+        /// "base32", guessed from each digest if unspecified.
+        #[structopt(long = "digest-encoding")]
+        digest_encoding: Option<DigestEncoding>,
+
+        /// Decompress the listed files, "gzip" or "zstd", before hashing
+        /// them to match a manifest signed over the uncompressed contents
+        /// (requires the "decompress" feature).
+        #[structopt(long = "decompress", default_value = "none")]
+        decompress: Compression,
+
+        /// How progress is shown, "default", "minimal", "detailed", "none" or
+        /// an indicatif template such as "{bar} {pos}/{len}".
+        #[structopt(long = "progress-template", default_value = "default")]
+        progress_template: ProgressTemplate,
+
+        /// Only check the files matching the glob, can be given more than
+        /// once. The signatures are still checked over the whole manifest.
+        #[structopt(long = "only", raw(number_of_values = "1"))]
+        only: Vec<Pattern>,
+
+        /// Find the files of a manifest signed with --normalize-paths even if
+        /// their names are stored in another Unicode normalization.
+        #[structopt(long = "normalize-paths")]
+        normalize_paths: bool,
+
+        /// Compare the file sizes signed with --include-size before hashing,
+        /// a file whose size changed is modified without being hashed.
+        #[structopt(long = "size-precheck")]
+        size_precheck: bool,
+
+        /// Hash stdin as the contents of this manifest path instead of
+        /// reading the file, for checking piped data against a manifest.
+        #[structopt(long = "stdin-as")]
+        stdin_as: Option<String>,
+
+        /// A file of revoked pkids, one per line. A valid signature by a
+        /// revoked key doesn't count, the manifest needs another signer.
+        #[structopt(long = "revoked", parse(from_os_str))]
+        revoked: Option<PathBuf>,
+
+        /// Pass even if up to this many files don't match the manifest, the
+        /// mismatched files are listed.
+        #[structopt(long = "allow-mismatch", default_value = "0")]
+        allow_mismatch: usize,
+
+        /// Stop hashing at the first file that fails, more than
+        /// --allow-mismatch of them, the rest are left unchecked.
+        #[structopt(long = "fail-fast")]
+        fail_fast: bool,
+
+        /// Hash every file and report all the failures, the default.
+        #[structopt(long = "collect-all", raw(conflicts_with = "\"fail_fast\""))]
+        collect_all: bool,
+
+        /// Reject manifests with digests from algorithms outside of the
+        /// comma separated list, e.g. "sha512_256,blake3".
+        #[structopt(long = "allowed-algorithms")]
+        allowed_algorithms: Option<AlgorithmSet>,
+
+        /// The manifest is a signed Merkle root, check the files in the
+        /// --proof file against it.
+        #[structopt(long = "merkle")]
+        merkle: bool,
+
+        /// The inclusion proofs written by sign --merkle, or just the proof
+        /// for a single file.
+        #[structopt(long = "proof", parse(from_os_str))]
+        proof: Option<PathBuf>,
+
+        /// The context the manifest must have been signed for, manifests
+        /// signed for a context only verify with it.
+        #[structopt(long = "context")]
+        context: Option<String>,
+
+        /// Trust the first signer seen for --source and fail if a later
+        /// manifest isn't signed by it. The store is a file of
+        /// "<source> <pkid>" lines, created if it doesn't exist.
+        #[structopt(long = "tofu", parse(from_os_str))]
+        tofu: Option<PathBuf>,
+
+        /// The name the --tofu store records the signer under, e.g. the
+        /// project the manifests come from.
+        #[structopt(long = "source")]
+        source: Option<String>,
+
+        /// The number of manifests to verify at once, 0 leaves one core free.
+        /// Defaults to the number of CPUs.
+        #[structopt(long = "jobs", short = "j")]
+        jobs: Option<usize>,
+
+        /// Check the manifest's timestamp token (requires the "tsa" feature).
+        #[structopt(long = "check-tsa")]
+        check_tsa: bool,
+
+        /// Write a CycloneDX BOM of the verified files to the file, signed
+        /// by the valid signers (requires the "sbom" feature).
+        #[structopt(long = "sbom", parse(from_os_str))]
+        sbom: Option<PathBuf>,
+
+        /// Print the signed bytes of the manifest instead of verifying it.
+        #[structopt(long = "dump-payload")]
+        dump_payload: bool,
+
+        /// Print the dumped payload in hex.
+        #[structopt(long = "hex")]
+        hex: bool,
+
+        /// the manifest file(s) or https URL(s) to verify
+        #[structopt(name = "MANIFEST", parse(from_os_str), raw(required = "true"))]
+        manifests: Vec<PathBuf>
+    },
+
+    #[structopt(name = "rotate")]
+    /// Generate a new key that supersedes the given identity
+    Rotate {
+        /// DIDDir root path or default if unspecified.
+        #[structopt(long = "diddir")]
+        dir: Option<String>,
+
+        /// DID for the identity to rotate out.
+        #[structopt(long = "id")]
+        id: Option<String>,
+
+        /// The file to save the rotation statement signed by the old key in.
+        #[structopt(short = "o", parse(from_os_str))]
+        output: PathBuf,
+
+        /// Also save the new identity in this file, e.g. as a backup. It is
+        /// stored in the DIDDir and takes over the alias either way.
+        #[structopt(long = "identity-out", parse(from_os_str))]
+        identity_out: Option<PathBuf>,
+    },
+
+    #[structopt(name = "keygen")]
+    /// Generate a new identity
+    Keygen {
+        /// Derive the key from a new 24 word BIP-39 mnemonic that is printed
+        /// as a paper backup and has to be typed back in to confirm it.
+        #[structopt(long = "mnemonic")]
+        mnemonic: bool,
+
+        /// The file to save the new identity in, import it into the DIDDir.
+        #[structopt(short = "o", parse(from_os_str))]
+        output: PathBuf,
+    },
+
+    #[structopt(name = "import")]
+    /// Recreate an identity from its backup
+    Import {
+        /// Read the BIP-39 mnemonic of a `keygen --mnemonic` identity from
+        /// stdin.
+        #[structopt(long = "mnemonic")]
+        mnemonic: bool,
+
+        /// The file to save the identity in, import it into the DIDDir.
+        #[structopt(short = "o", parse(from_os_str))]
+        output: PathBuf,
+    },
+
+    #[structopt(name = "export")]
+    /// Export an identity's key for use with other tools
+    Export {
+        /// DIDDir root path or default if unspecified.
+        #[structopt(long = "diddir")]
+        dir: Option<String>,
+
+        /// DID for the identity to export.
+        #[structopt(long = "id")]
+        id: Option<String>,
+
+        /// The key format, "jwk" or "ssh" for an OpenSSH public key line.
+        /// Private keys are only exported as JWKs.
+        #[structopt(long = "format", default_value = "jwk")]
+        fmt: String,
+
+        /// Export the private key instead of the public key. It is written
+        /// unencrypted, so it needs an output file and is only readable by
+        /// you.
+        #[structopt(long = "private")]
+        private: bool,
+
+        /// The file to save the key in or stdout if unspecified.
+        #[structopt(short = "o", parse(from_os_str))]
+        output: Option<PathBuf>,
+    },
+
+    #[structopt(name = "fingerprint")]
+    /// Print the pkid and fingerprint of an Ed25519 public key
+    Fingerprint {
+        /// A file holding the raw 32 byte public key.
+        #[structopt(long = "key-file", parse(from_os_str))]
+        key_file: Option<PathBuf>,
+
+        /// The public key in hex.
+        #[structopt(long = "key-hex")]
+        key_hex: Option<String>,
+
+        /// The public key as a pkid.
+        #[structopt(long = "pkid")]
+        pkid: Option<String>,
+    },
+
+    #[structopt(name = "reencode")]
+    /// Re-encode a manifest's digests without hashing the files again
+    Reencode {
+        /// DIDDir root path or default if unspecified.
+        #[structopt(long = "diddir")]
+        dir: Option<String>,
+
+        /// DID for the identity to sign the re-encoded manifest with, the
+        /// old signatures no longer verify.
+        #[structopt(long = "id")]
+        id: Option<String>,
+
+        /// Keep the old signatures instead of signing again, they no longer
+        /// verify.
+        #[structopt(long = "keep-signatures")]
+        keep_signatures: bool,
+
+        /// The encoding to re-encode the digests in, "b64", "b64url", "hex"
+        /// or "base32".
+        #[structopt(long = "digest-encoding")]
+        digest_encoding: DigestEncoding,
+
+        /// The encoding the digests are in, guessed from each digest if
+        /// unspecified.
+        #[structopt(long = "from-encoding")]
+        from_encoding: Option<DigestEncoding>,
+
+        /// The file to save the manifest in or stdout if unspecified.
+        #[structopt(short = "o", parse(from_os_str))]
+        output: Option<PathBuf>,
+
+        /// The manifest to re-encode.
         #[structopt(name = "MANIFEST", parse(from_os_str))]
-        manifest: PathBuf
+        manifest: PathBuf,
+    },
+
+    #[structopt(name = "version")]
+    /// Print the version and what this build supports
+    Version {
+        /// Print the version, algorithms, formats and features as JSON.
+        #[structopt(long = "json")]
+        json: bool,
+    },
+
+    #[structopt(name = "signers")]
+    /// List the pkids of a manifest's signers for adding to a keyring
+    Signers {
+        /// The detached signature file for the manifest.
+        #[structopt(long = "sig", parse(from_os_str))]
+        sig: Option<PathBuf>,
+
+        /// Print the pkids as a JSON list.
+        #[structopt(long = "json")]
+        json: bool,
+
+        /// the manifest file
+        #[structopt(name = "MANIFEST", parse(from_os_str))]
+        manifest: PathBuf,
+    },
+
+    #[structopt(name = "check")]
+    /// Check that a manifest is well-formed without keys or files
+    Check {
+        /// The detached signature file for the manifest.
+        #[structopt(long = "sig", parse(from_os_str))]
+        sig: Option<PathBuf>,
+
+        /// the manifest file
+        #[structopt(name = "MANIFEST", parse(from_os_str))]
+        manifest: PathBuf,
+    },
+
+    #[structopt(name = "diff")]
+    /// Show the files and signers that changed between two manifests
+    Diff {
+        /// The output format, "table" or "json".
+        #[structopt(long = "output-format", default_value = "table")]
+        output_format: String,
+
+        /// the old manifest file
+        #[structopt(name = "OLD", parse(from_os_str))]
+        old: PathBuf,
+
+        /// the new manifest file
+        #[structopt(name = "NEW", parse(from_os_str))]
+        new: PathBuf,
+    }
+}
+
+// writes to a temporary file next to the target and renames it into place so
+// a failed or interrupted write never leaves a truncated file behind. with
+// sync the data and the rename are on disk before it returns, so the file
+// also survives a crash right after
+fn write_atomic(path: &Path, data: &[u8], sync: bool) -> io::Result<()> {
+    replace_atomic(path, sync, false, |f| f.write_all(data))
+}
+
+// files with secrets in them are only readable by the owner from the moment
+// they are created, not just once they are renamed into place
+fn write_private(path: &Path, data: &[u8]) -> io::Result<()> {
+    replace_atomic(path, false, true, |f| f.write_all(data))
+}
+
+#[cfg(unix)]
+fn create_tmp(tmp: &Path, private: bool) -> io::Result<File> {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    // a file left behind by an earlier run would keep its permissions
+    let _ = fs::remove_file(tmp);
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    if private {
+        options.mode(0o600);
+    }
+    options.open(tmp)
+}
+
+#[cfg(not(unix))]
+fn create_tmp(tmp: &Path, _private: bool) -> io::Result<File> {
+    File::create(tmp)
+}
+
+fn replace_atomic<F: FnOnce(&mut File) -> io::Result<()>>(path: &Path, sync: bool, private: bool, write: F)
+    -> io::Result<()> {
+    let mut tmp = path.as_os_str().to_os_string();
+    tmp.push(format!(".tmp{}", std::process::id()));
+    let tmp = PathBuf::from(tmp);
+
+    let result = create_tmp(&tmp, private)
+        .and_then(|mut f| {
+            write(&mut f)?;
+            f.flush()?;
+            if sync {
+                f.sync_all()?;
+            }
+            Ok(())
+        })
+        .and_then(|_| fs::rename(&tmp, path));
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    result?;
+    if sync {
+        sync_parent(path)?;
+    }
+    Ok(())
+}
+
+// the rename is only durable once the directory holding the file is synced,
+// directories can't be opened for that off unix
+#[cfg(unix)]
+fn sync_parent(path: &Path) -> io::Result<()> {
+    match path.parent() {
+        Some(dir) if dir != Path::new("") => File::open(dir)?.sync_all(),
+        _ => File::open(".")?.sync_all()
+    }
+}
+
+#[cfg(not(unix))]
+fn sync_parent(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+// identity files hold the sealed secret key so only the owner may read them
+fn write_identity(path: &Path, identity: &str) -> io::Result<()> {
+    write_private(path, identity.as_bytes())
+}
+
+fn read_mnemonic(prompt: &str) -> io::Result<String> {
+    eprint!("{}", prompt);
+    io::stderr().flush()?;
+    let mut phrase = String::new();
+    io::stdin().read_line(&mut phrase)?;
+    Ok(phrase)
+}
+
+// writes the manifest to a file and detached signatures next to it
+struct FileSink {
+    path: PathBuf,
+    sync: bool,
+}
+
+impl SignatureSink for FileSink {
+    fn write_manifest(&mut self, manifest: &[u8]) -> bs::Result<()> {
+        Ok(write_atomic(&self.path, manifest, self.sync)?)
+    }
+
+    fn write_signature(&mut self, signature: &[u8], suffix: &str) -> bs::Result<()> {
+        let mut sig_path = self.path.as_os_str().to_os_string();
+        sig_path.push(suffix);
+        Ok(write_atomic(Path::new(&sig_path), signature, self.sync)?)
+    }
+}
+
+// writes the manifest to stdout, there is nowhere for a detached signature
+// to go
+struct StdoutSink;
+
+impl SignatureSink for StdoutSink {
+    fn write_manifest(&mut self, manifest: &[u8]) -> bs::Result<()> {
+        let stdout = io::stdout();
+        let mut stdout = stdout.lock();
+        stdout.write_all(manifest)?;
+        Ok(stdout.flush()?)
+    }
+
+    fn write_signature(&mut self, _signature: &[u8], _suffix: &str) -> bs::Result<()> {
+        Err(bs::Error::Syntax("detached signatures require an output file".to_string()))
     }
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     // initialize sodiumoxide
-    sodiumoxide::init().unwrap();
+    bs::init()?;
 
     // parse the command line flags
     let opt = Opt::from_args();
+    bs::set_passphrase_file(opt.passphrase_file.clone());
     match opt.cmd {
-        Command::Sign { dir, id, fmt, output, files } => {
-            let signature = sign::sign(opt.verbose, &opt.fd, &dir, &id, files)?;
+        Command::Sign { dir, id, age_key, age_identity, fmt, ssh_namespace, output, detached, trim_prefix, root,
+                        retries, tsa, strict, strict_file_types, verify_stable, unique_content, threads, count_only,
+                        quiet, progress_template, algorithm, allowed_algorithms, symlinks, hardlink_mode, read_strategy,
+                        decompress, max_depth, modified_since, digest_encoding, sort_by, merkle, include_signer_name,
+                        include_tree, include_mode, include_size, normalize_paths, manifest_digest, hash_empty_dirs,
+                        policy, record_errors, context, extend, overwrite, nice, compact, fsync, resume, exec,
+                        files } => {
+            // minisign and ssh signatures always go next to the manifest
+            let beside = match fmt.as_ref().map(String::as_str) {
+                Some("minisign") | Some("ssh") => true,
+                _ => false
+            };
+            if (detached || beside || merkle) && output.is_none() && !count_only {
+                return Err(From::from("detached signatures require an output file"));
+            }
+            let opts = SignOptions {
+                format: fmt,
+                ssh_namespace: ssh_namespace,
+                detached: detached,
+                trim_prefix: trim_prefix,
+                root: root,
+                retries: retries,
+                tsa: tsa,
+                strict: strict,
+                strict_file_types: strict_file_types,
+                verify_stable: verify_stable,
+                unique_content: unique_content,
+                threads: threads,
+                quiet: quiet,
+                progress_template: progress_template,
+                algorithm: algorithm,
+                allowed_algorithms: allowed_algorithms,
+                symlink_mode: symlinks,
+                hardlink_mode: hardlink_mode,
+                read_strategy: read_strategy,
+                decompress: decompress,
+                max_depth: max_depth,
+                modified_since: modified_since.map(Into::into),
+                digest_encoding: digest_encoding,
+                sort_by: sort_by,
+                merkle: merkle,
+                include_signer_name: include_signer_name,
+                include_tree: include_tree,
+                include_mode: include_mode,
+                include_size: include_size,
+                normalize_paths: normalize_paths,
+                manifest_digest: manifest_digest,
+                hash_empty_dirs: hash_empty_dirs,
+                record_errors: record_errors,
+                context: context,
+                extend: extend,
+                overwrite: overwrite,
+                exec: if exec {
+                    Some(files.iter().map(|f| f.to_string_lossy().into_owned()).collect())
+                } else {
+                    None
+                },
+                resume: resume,
+                nice: nice,
+                compact: compact,
+                insecure_permissions: opt.insecure_permissions,
+                policy: policy.iter()
+                    .flat_map(|p| p.split(','))
+                    .map(str::trim)
+                    .filter(|p| !p.is_empty())
+                    .map(String::from)
+                    .collect(),
+                age_key: age_key,
+                age_identity: age_identity,
+            };
+            if count_only {
+                println!("{}", sign::count(&opts, files)?);
+                return Ok(());
+            }
+            let files = if exec { Vec::new() } else { files };
+            let signed = sign::sign(opt.verbose, &opt.fd, &dir, &id, &opts, files)?;
 
             // output the signature to a file or stdout
-            let mut out_writer = match output {
-                Some(p) => {
-                    let path = Path::new(&p);
-                    Box::new(File::create(&path).unwrap()) as Box<Write>
+            match output {
+                Some(p) => signed.write_to(&mut FileSink { path: p, sync: fsync })?,
+                None => signed.write_to(&mut StdoutSink)?
+            }
+
+            // the run is complete so there is nothing left to resume
+            if let Some(ref journal) = opts.resume.as_ref().filter(|journal| journal.exists()) {
+                fs::remove_file(journal)?;
+            }
+        },
+        Command::Verify { dir, key, sig, sig_raw, minisig, minisign_key, prepend, digest_encoding, decompress, progress_template, only, normalize_paths, size_precheck, stdin_as, revoked, allow_mismatch, fail_fast, collect_all, allowed_algorithms, merkle, proof, context, tofu, source, jobs, check_tsa, sbom, dump_payload, hex, manifests } => {
+            if (sig.is_some() || sig_raw.is_some() || minisig.is_some() || proof.is_some()) && manifests.len() > 1 {
+                return Err(From::from("detached signatures and proofs can only be used with a single manifest"));
+            }
+            if sig.is_some() && sig_raw.is_some() {
+                return Err(From::from("give either --sig or --sig-raw"));
+            }
+            if stdin_as.is_some() && (manifests.len() > 1 || merkle) {
+                return Err(From::from("stdin can only stand in for a file of a single manifest"));
+            }
+            if sbom.is_some() && (manifests.len() > 1 || merkle || !cfg!(feature = "sbom")) {
+                return Err(From::from("an SBOM is written for a single manifest and requires the \"sbom\" feature"));
+            }
+            if tofu.is_some() != source.is_some() {
+                return Err(From::from("--tofu and --source must be given together"));
+            }
+            if let Some(ref source) = source {
+                bs::tofu::check_source(source)?;
+            }
+            let mut store = match tofu {
+                Some(ref path) => Some(TofuStore::load(path)?),
+                None => None
+            };
+            let opts = VerifyOptions {
+                sig: sig,
+                sig_raw: sig_raw,
+                prepend: prepend,
+                check_tsa: check_tsa,
+                minisig: minisig,
+                minisign_key: minisign_key,
+                digest_encoding: digest_encoding,
+                only: only,
+                allow_mismatch: allow_mismatch,
+                allowed_algorithms: allowed_algorithms,
+                merkle: merkle,
+                proof: proof,
+                decompress: decompress,
+                progress_template: progress_template,
+                context: context,
+                normalize_paths: normalize_paths,
+                size_precheck: size_precheck,
+                stdin_as: stdin_as,
+                revoked: revoked,
+                fail_fast: fail_fast && !collect_all,
+            };
+
+            if dump_payload {
+                let mut stdout = io::stdout();
+                for manifest in &manifests {
+                    let payload = verify::signed_payload(&opts, manifest)?;
+                    if hex {
+                        let hex: String = payload.as_bytes().iter().map(|b| format!("{:02x}", b)).collect();
+                        writeln!(stdout, "{}", hex)?;
+                    } else {
+                        stdout.write_all(payload.as_bytes())?;
+                    }
+                }
+                return Ok(());
+            }
+
+            // a key that names a file is a keyring
+            let key = match key {
+                Some(ref path) if Path::new(path).is_file() => Some(fs::read_to_string(path)?),
+                key => key
+            };
+
+            let jobs = jobs.map(bs::thread_count).unwrap_or_else(num_cpus::get);
+            let results = verify::verify_all(&dir, &key, &opts, &manifests, jobs)?;
+
+            // print a summary line for each manifest
+            let mut failed = 0;
+            let mut summary = VerifySummary::default();
+            for (manifest, result) in &results {
+                match result {
+                    Ok(report) if opt.verbose => {
+                        for file in &report.files {
+                            println!("  {}: {}", file.status, file.path);
+                        }
+                        for sig in &report.signatures {
+                            let entry = match sig.keyring_line {
+                                Some(line) => format!(" [keyring line {}]", line),
+                                None => String::new()
+                            };
+                            match sig.name {
+                                Some(ref name) => {
+                                    println!("  {} signature: {}{} ({}, unverified)", sig.status, sig.signer, entry, name)
+                                },
+                                None => println!("  {} signature: {}{}", sig.status, sig.signer, entry)
+                            }
+                        }
+                    },
+                    Ok(report) => {
+                        for file in report.mismatches() {
+                            println!("  {}: {}", file.status, file.path);
+                        }
+                        for file in report.signer_errors() {
+                            println!("  {}: {}", file.status, file.path);
+                        }
+                        for signer in report.unknown_signers() {
+                            println!("  Unknown signer: {}", signer);
+                        }
+                    },
+                    Err(_) => {}
+                }
+                let mut checked = match result {
+                    Ok(report) => report.check(),
+                    Err(e) => Err(e.clone())
+                };
+
+                // only a manifest that verified is trusted on first use
+                if let (true, Ok(report), Some(store), Some(source)) = (checked.is_ok(), result, store.as_mut(), source.as_ref()) {
+                    match store.check(source, report) {
+                        Ok(TofuStatus::FirstSeen(pkid)) => {
+                            eprintln!("Trusting {} for {} on first use", pkid, source);
+                        },
+                        Ok(TofuStatus::Matched(_)) => {},
+                        Err(e) => {
+                            eprintln!("WARNING: THE SIGNER FOR {} HAS CHANGED", source);
+                            eprintln!("WARNING: {}", e);
+                            checked = Err(e);
+                        }
+                    }
+                }
+                summary.add(result.as_ref().ok(), &checked);
+                #[cfg(feature = "sbom")]
+                {
+                    if let (Ok(report), Ok(()), Some(path)) = (result, &checked, &sbom) {
+                        let m = verify::load_manifest(&opts, manifest)?;
+                        let bom = bs::sbom::cyclonedx(&m, report, opts.digest_encoding)?;
+                        write_atomic(path, bom.as_bytes(), false)?;
+                    }
+                }
+                match checked {
+                    Ok(()) => println!("OK: {}", manifest.display()),
+                    Err(e) => {
+                        failed += 1;
+                        println!("FAILED: {}: {}", manifest.display(), e);
+                    }
+                }
+            }
+            if let (Some(store), Some(path)) = (store, tofu) {
+                if store.is_changed() {
+                    write_atomic(&path, store.contents().as_bytes(), false)?;
+                }
+            }
+            eprintln!("{}", summary);
+            if failed > 0 {
+                eprintln!("Error: {} of {} manifests failed verification", failed, results.len());
+                std::process::exit(summary.worst.exit_code());
+            }
+        },
+        Command::Rotate { dir, id, output, identity_out } => {
+            let rotation = rotate::rotate(&dir, &id, opt.insecure_permissions)?;
+            if let Some(ref path) = identity_out {
+                write_identity(path, &rotation.identity)?;
+            }
+            write_atomic(&output, rotation.statement.as_bytes(), false)?;
+            eprintln!("New identity {} stored in the DIDDir", rotation.pkid);
+            if let Some(alias) = rotation.alias {
+                eprintln!("Alias {} moved to {}", alias, rotation.pkid);
+            }
+        },
+        Command::Keygen { mnemonic, output } => {
+            let (pkid, identity) = if mnemonic {
+                let (pkid, identity, phrase) = bs::generate_mnemonic()?;
+                eprintln!("Write down this mnemonic, it recreates the key with `bs import --mnemonic`:\n\n{}\n", phrase);
+                let confirmed = read_mnemonic("Type the mnemonic again to confirm it: ")?;
+                if bs::from_mnemonic(&confirmed).ok().map(|(p, _)| p) != Some(pkid.clone()) {
+                    return Err(From::from("the mnemonic doesn't match, no identity was saved"));
                 }
-                None => Box::new(io::stdout()) as Box<Write>,
+                (pkid, identity)
+            } else {
+                bs::generate()?
             };
-            out_writer.write(signature.as_bytes())?;
+            write_identity(&output, &identity)?;
+            eprintln!("New identity {} saved in {}", pkid, output.display());
         },
-        Command::Verify { manifest } => {
-            verify::verify(opt.verbose, &opt.fd, &manifest)?;
+        Command::Import { mnemonic, output } => {
+            if !mnemonic {
+                return Err(From::from("only --mnemonic identities can be imported"));
+            }
+            let (pkid, identity) = bs::from_mnemonic(&read_mnemonic("Mnemonic: ")?)?;
+            write_identity(&output, &identity)?;
+            eprintln!("Identity {} saved in {}", pkid, output.display());
+        },
+        Command::Export { dir, id, fmt, private, output } => {
+            if private && output.is_none() {
+                return Err(From::from("a private key export requires an output file"));
+            }
+            let key = match fmt.as_str() {
+                "jwk" => bs::export_jwk(&dir, &id, private, opt.insecure_permissions)?,
+                "ssh" if !private => bs::export_ssh(&dir, &id, opt.insecure_permissions)?,
+                other => return Err(From::from(format!("unsupported key format: {}", other)))
+            };
+            match output {
+                // unlike in identity files the private key isn't sealed
+                Some(p) if private => write_private(&p, key.as_bytes())?,
+                Some(p) => write_atomic(&p, key.as_bytes(), false)?,
+                None => io::stdout().write_all(key.as_bytes())?
+            }
+        },
+        Command::Fingerprint { key_file, key_hex, pkid } => {
+            let vk = match (key_file, key_hex, pkid) {
+                (Some(path), None, None) => VerifyKey::from_bytes(&fs::read(&path)?)?,
+                (None, Some(hex), None) => VerifyKey::from_bytes(&DigestEncoding::Hex.decode(hex.trim())?)?,
+                (None, None, Some(pkid)) => bs::verify_key_from_pkid(pkid.trim())?,
+                _ => return Err(From::from("give one of --key-file, --key-hex or --pkid"))
+            };
+            println!("{}", vk.pkid());
+            println!("Fingerprint: {}", vk.fingerprint());
+        },
+        Command::Reencode { dir, id, keep_signatures, digest_encoding, from_encoding, output, manifest } => {
+            let contents = fs::read_to_string(&manifest)?;
+            let reencoded = reencode::reencode(&dir, &id, &contents, from_encoding, digest_encoding, keep_signatures,
+                                                 opt.insecure_permissions)?;
+            match output {
+                Some(p) => write_atomic(&p, reencoded.manifest.as_bytes(), false)?,
+                None => io::stdout().write_all(reencoded.manifest.as_bytes())?
+            }
+            eprintln!("Re-encoded {} digests", reencoded.changed);
+            if reencoded.stale_signatures {
+                eprintln!("WARNING: the kept signatures no longer verify, sign the manifest again");
+            }
+        },
+        Command::Version { json } => {
+            let caps = Capabilities::get();
+            if json {
+                io::stdout().write_all(caps.to_json().as_bytes())?;
+            } else {
+                println!("bs {} ({} manifests)", caps.version, caps.manifest_version);
+                println!("Algorithms: {}", caps.algorithms.join(", "));
+                println!("Formats: {}", caps.formats.join(", "));
+                println!("Features: {}", if caps.features.is_empty() { "none".to_string() } else { caps.features.join(", ") });
+            }
+        },
+        Command::Signers { sig, json, manifest } => {
+            let opts = VerifyOptions { sig: sig, ..Default::default() };
+            let signers = verify::signers(&opts, &manifest)?;
+            if json {
+                io::stdout().write_all(verify::signers_json(&signers).as_bytes())?;
+            } else {
+                for pkid in &signers {
+                    println!("{}", pkid);
+                }
+            }
+        },
+        Command::Check { sig, manifest } => {
+            let opts = VerifyOptions { sig: sig, ..Default::default() };
+            let m = verify::check_manifest(&opts, &manifest)?;
+            println!("{}: {} files, {} signatures, well-formed", manifest.display(), m.files.len(), m.signatures.len());
+        },
+        Command::Diff { output_format, old, new } => {
+            let d = diff::diff_files(&old, &new)?;
+            let out = match output_format.as_str() {
+                "table" => d.to_table(),
+                "json" => d.to_json(),
+                other => return Err(From::from(format!("unsupported output format: {}", other)))
+            };
+            io::stdout().write_all(out.as_bytes())?;
         },
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("bs-bin-test-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    // a disk that fills up, it takes the first few bytes and then no more
+    struct ShortWrites<'a> {
+        inner: &'a mut File,
+        left: usize,
+    }
+
+    impl<'a> Write for ShortWrites<'a> {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let len = buf.len().min(self.left);
+            self.left -= len;
+            self.inner.write(&buf[..len])
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.inner.flush()
+        }
+    }
+
+    #[test]
+    fn replaces_the_file() {
+        let path = scratch_dir("replace").join("manifest.json");
+        fs::write(&path, b"old").unwrap();
+        write_atomic(&path, b"new manifest", false).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"new manifest");
+    }
+
+    #[test]
+    fn short_write_leaves_the_old_file() {
+        let dir = scratch_dir("short-write");
+        let path = dir.join("manifest.json");
+        fs::write(&path, b"old").unwrap();
+
+        let result = replace_atomic(&path, false, false, |f| ShortWrites { inner: f, left: 4 }.write_all(b"new manifest"));
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::WriteZero);
+        assert_eq!(fs::read(&path).unwrap(), b"old");
+        // the temporary file is cleaned up
+        let entries: Vec<_> = fs::read_dir(&dir).unwrap().collect();
+        assert_eq!(entries.len(), 1);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn private_files_are_never_readable_by_others() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = scratch_dir("private").join("key.jwk");
+        // looked at while the temporary file is still being written
+        let result = replace_atomic(&path, false, true, |f| {
+            let mode = f.metadata()?.permissions().mode();
+            assert_eq!(mode & 0o077, 0, "the temporary file is {:04o}", mode & 0o777);
+            f.write_all(b"secret")
+        });
+        result.unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+    }
+}
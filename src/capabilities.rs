@@ -0,0 +1,56 @@
+use crate::manifest::MANIFEST_VERSION;
+use serde_json::json;
+
+/// What this build of bs supports, for tools that shell out to it.
+pub struct Capabilities {
+    pub version: &'static str,
+    pub manifest_version: &'static str,
+    pub algorithms: Vec<&'static str>,
+    pub digest_encodings: Vec<&'static str>,
+    pub formats: Vec<&'static str>,
+    pub compression: Vec<&'static str>,
+    pub features: Vec<&'static str>,
+}
+
+impl Capabilities {
+    /// The capabilities compiled into this binary.
+    pub fn get() -> Self {
+        let mut compression = vec!["none"];
+        if cfg!(feature = "decompress") {
+            compression.extend(&["gzip", "zstd"]);
+        }
+
+        let features: Vec<&'static str> = [
+            ("remote", cfg!(feature = "remote")),
+            ("tsa", cfg!(feature = "tsa")),
+            ("async", cfg!(feature = "async")),
+            ("decompress", cfg!(feature = "decompress")),
+            ("sbom", cfg!(feature = "sbom")),
+            ("age", cfg!(feature = "age")),
+            ("mnemonic", cfg!(feature = "mnemonic"))
+        ].iter().filter(|(_, enabled)| *enabled).map(|(name, _)| *name).collect();
+
+        Capabilities {
+            version: env!("CARGO_PKG_VERSION"),
+            manifest_version: MANIFEST_VERSION,
+            algorithms: vec!["sha512_256", "blake3"],
+            digest_encodings: vec!["b64", "b64url", "hex", "base32"],
+            // a plain manifest is the default, merkle is signed with --merkle
+            formats: vec!["manifest", "ndjson", "slsa", "minisign", "ssh", "merkle"],
+            compression: compression,
+            features: features
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        format!("{:#}\n", json!({
+            "version": self.version,
+            "manifest_version": self.manifest_version,
+            "algorithms": self.algorithms,
+            "digest_encodings": self.digest_encodings,
+            "formats": self.formats,
+            "compression": self.compression,
+            "features": self.features
+        }))
+    }
+}
@@ -0,0 +1,107 @@
+use crate::Result;
+use crate::manifest::{self, Manifest};
+use serde_json::json;
+use std::convert::TryFrom;
+use std::path::PathBuf;
+
+/// The differences between two manifests.
+#[derive(Clone, Debug, Default)]
+pub struct ManifestDiff {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    pub changed: Vec<String>,
+    pub signers_added: Vec<String>,
+    pub signers_removed: Vec<String>,
+}
+
+// digests are compared decoded so the same digest in two encodings matches
+fn same_digest(old: &str, new: &str) -> bool {
+    match (manifest::sigil_algorithm(old), manifest::sigil_algorithm(new)) {
+        (Ok(a), Ok(b)) if a != b => return false,
+        _ => {}
+    }
+    match (manifest::sigil_digest(old, None), manifest::sigil_digest(new, None)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => old == new
+    }
+}
+
+fn lookup<'a>(entries: &'a [(String, String)], key: &str) -> Option<&'a String> {
+    entries.iter().find(|(k, _)| k == key).map(|(_, v)| v)
+}
+
+/// Compares the files and signers of two manifests.
+pub fn diff(old: &Manifest, new: &Manifest) -> ManifestDiff {
+    let mut d = ManifestDiff::default();
+    for (path, digest) in &new.files {
+        match lookup(&old.files, path) {
+            Some(old_digest) if same_digest(old_digest, digest) => {},
+            Some(_) => d.changed.push(path.to_owned()),
+            None => d.added.push(path.to_owned())
+        }
+    }
+    for (path, _) in &old.files {
+        if lookup(&new.files, path).is_none() {
+            d.removed.push(path.to_owned());
+        }
+    }
+    for (pkid, _) in &new.signatures {
+        if lookup(&old.signatures, pkid).is_none() {
+            d.signers_added.push(pkid.to_owned());
+        }
+    }
+    for (pkid, _) in &old.signatures {
+        if lookup(&new.signatures, pkid).is_none() {
+            d.signers_removed.push(pkid.to_owned());
+        }
+    }
+    d
+}
+
+/// Compares two manifest files.
+pub fn diff_files(old: &PathBuf, new: &PathBuf) -> Result<ManifestDiff> {
+    let old = Manifest::try_from(std::fs::read_to_string(old)?.as_str())?;
+    let new = Manifest::try_from(std::fs::read_to_string(new)?.as_str())?;
+    Ok(diff(&old, &new))
+}
+
+impl ManifestDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty() &&
+            self.signers_added.is_empty() && self.signers_removed.is_empty()
+    }
+
+    /// One line per difference, the kind of change followed by the path or
+    /// signer.
+    pub fn to_table(&self) -> String {
+        let mut table = String::new();
+        let rows = vec![
+            ("added", &self.added),
+            ("removed", &self.removed),
+            ("changed", &self.changed),
+            ("+signer", &self.signers_added),
+            ("-signer", &self.signers_removed),
+        ];
+        for (kind, entries) in rows {
+            for entry in entries {
+                table.push_str(&format!("{:<8} {}\n", kind, entry));
+            }
+        }
+        table
+    }
+
+    pub fn to_json(&self) -> String {
+        let json = json!({
+            "files": {
+                "added": self.added,
+                "removed": self.removed,
+                "changed": self.changed
+            },
+            "signers": {
+                "added": self.signers_added,
+                "removed": self.signers_removed
+            }
+        });
+        format!("{:#}\n", json)
+    }
+}
@@ -0,0 +1,178 @@
+use base64::{decode_config, encode_config, STANDARD, URL_SAFE};
+use crate::{Error, Result};
+use std::str::FromStr;
+
+// RFC 4648 base32 in lower case without padding, as IPFS uses it
+static BASE32_ALPHABET: &'static [u8] = b"abcdefghijklmnopqrstuvwxyz234567";
+
+/// How digests are rendered in manifest sigils.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DigestEncoding {
+    Base64,
+    Base64Url,
+    Hex,
+    Base32
+}
+
+impl Default for DigestEncoding {
+    fn default() -> Self {
+        DigestEncoding::Base64Url
+    }
+}
+
+impl FromStr for DigestEncoding {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "b64" => Ok(DigestEncoding::Base64),
+            "b64url" => Ok(DigestEncoding::Base64Url),
+            "hex" => Ok(DigestEncoding::Hex),
+            "base32" => Ok(DigestEncoding::Base32),
+            _ => Err(Error::InvalidMeta(format!("unknown digest encoding: {}", s)))
+        }
+    }
+}
+
+fn invalid(encoding: &str, data: &str) -> Error {
+    Error::InvalidEncoding(format!("not valid {}: {}", encoding, data))
+}
+
+fn base32_encode(data: &[u8]) -> String {
+    let mut out = String::new();
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+    for &b in data {
+        buffer = ((buffer << 8) | u32::from(b)) & 0xffff;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            out.push(BASE32_ALPHABET[((buffer >> bits) & 31) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        out.push(BASE32_ALPHABET[((buffer << (5 - bits)) & 31) as usize] as char);
+    }
+    out
+}
+
+fn base32_decode(data: &str) -> Result<Vec<u8>> {
+    let mut out = Vec::new();
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+    for c in data.trim_end_matches('=').bytes() {
+        let c = c.to_ascii_lowercase();
+        let value = match BASE32_ALPHABET.iter().position(|&a| a == c) {
+            Some(value) => value as u32,
+            None => return Err(invalid("base32", data))
+        };
+        buffer = ((buffer << 5) | value) & 0xffff;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((buffer >> bits) as u8);
+        }
+    }
+    Ok(out)
+}
+
+fn hex_decode(data: &str) -> Result<Vec<u8>> {
+    if data.len() % 2 != 0 {
+        return Err(invalid("hex", data));
+    }
+    let mut out = Vec::new();
+    for i in (0..data.len()).step_by(2) {
+        match data.get(i..i + 2).and_then(|b| u8::from_str_radix(b, 16).ok()) {
+            Some(b) => out.push(b),
+            None => return Err(invalid("hex", data))
+        }
+    }
+    Ok(out)
+}
+
+impl DigestEncoding {
+    pub fn encode(&self, data: &[u8]) -> String {
+        match self {
+            DigestEncoding::Base64 => encode_config(data, STANDARD),
+            DigestEncoding::Base64Url => encode_config(data, URL_SAFE),
+            DigestEncoding::Hex => data.iter().map(|b| format!("{:02x}", b)).collect(),
+            DigestEncoding::Base32 => base32_encode(data)
+        }
+    }
+
+    pub fn decode(&self, data: &str) -> Result<Vec<u8>> {
+        match self {
+            DigestEncoding::Base64 => Ok(decode_config(data, STANDARD)?),
+            DigestEncoding::Base64Url => Ok(decode_config(data, URL_SAFE)?),
+            DigestEncoding::Hex => hex_decode(data),
+            DigestEncoding::Base32 => base32_decode(data)
+        }
+    }
+
+    /// Guesses the encoding of a 32 byte digest from its length and alphabet.
+    /// The two base64 alphabets only differ in two characters so a digest
+    /// without either decodes the same with both.
+    pub fn detect(data: &str) -> Self {
+        match data.len() {
+            64 if data.chars().all(|c| c.is_ascii_hexdigit()) => DigestEncoding::Hex,
+            52 => DigestEncoding::Base32,
+            _ if data.contains('+') || data.contains('/') => DigestEncoding::Base64,
+            _ => DigestEncoding::Base64Url
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::Algorithm;
+    use crate::manifest::{digest_sigil, sigil_digest};
+
+    static ENCODINGS: [DigestEncoding; 4] =
+        [DigestEncoding::Base64, DigestEncoding::Base64Url, DigestEncoding::Hex, DigestEncoding::Base32];
+
+    // a digest with bytes that hit both characters the base64 alphabets
+    // differ in
+    fn digest() -> Vec<u8> {
+        (0..32u8).map(|i| i.wrapping_mul(37) ^ 0xfb).collect()
+    }
+
+    #[test]
+    fn round_trips() {
+        for encoding in &ENCODINGS {
+            for len in &[0, 1, 2, 3, 4, 5, 31, 32, 33] {
+                let data: Vec<u8> = digest().into_iter().cycle().take(*len).collect();
+                assert_eq!(encoding.decode(&encoding.encode(&data)).unwrap(), data, "{:?} of {} bytes", encoding, len);
+            }
+        }
+    }
+
+    #[test]
+    fn sigils_round_trip_with_a_guessed_encoding() {
+        for encoding in &ENCODINGS {
+            let sigil = digest_sigil(&digest(), Algorithm::Blake3, *encoding);
+            assert_eq!(sigil_digest(&sigil, None).unwrap(), digest(), "{}", sigil);
+            assert_eq!(sigil_digest(&sigil, Some(*encoding)).unwrap(), digest(), "{}", sigil);
+        }
+    }
+
+    #[test]
+    fn known_encodings() {
+        assert_eq!(DigestEncoding::Hex.encode(b"\xde\xad\xbe\xef"), "deadbeef");
+        assert_eq!(DigestEncoding::Hex.decode("DEADbeef").unwrap(), b"\xde\xad\xbe\xef");
+        // RFC 4648 test vectors, lower case and unpadded
+        assert_eq!(DigestEncoding::Base32.encode(b"f"), "my");
+        assert_eq!(DigestEncoding::Base32.encode(b"foobar"), "mzxw6ytboi");
+        assert_eq!(DigestEncoding::Base32.decode("MZXW6YTBOI======").unwrap(), b"foobar");
+        assert_eq!(DigestEncoding::Base64.encode(b"\xfb\xff"), "+/8=");
+        assert_eq!(DigestEncoding::Base64Url.encode(b"\xfb\xff"), "-_8=");
+    }
+
+    #[test]
+    fn rejects_invalid_digests() {
+        assert!(DigestEncoding::Hex.decode("abc").is_err());
+        assert!(DigestEncoding::Hex.decode("zz").is_err());
+        assert!(DigestEncoding::Base32.decode("m1").is_err());
+        assert!(DigestEncoding::Base64Url.decode("+/8=").is_err());
+    }
+}
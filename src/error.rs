@@ -13,6 +13,10 @@ pub enum Error {
     IoError(String),
     Base64EncodingError(String),
     NotUrlSafeBase64(String),
+    DigestMismatch(String),
+    SignatureInvalid(String),
+    TimestampInvalid(String),
+    DuplicatePath(String),
 }
 
 pub type Result<T> = result::Result<T, Error>;
@@ -26,7 +30,11 @@ impl std::error::Error for Error {
             Error::InvalidEncoding(ref err) |
             Error::IoError(ref err) |
             Error::Base64EncodingError(ref err) |
-            Error::NotUrlSafeBase64(ref err) => err,
+            Error::NotUrlSafeBase64(ref err) |
+            Error::DigestMismatch(ref err) |
+            Error::SignatureInvalid(ref err) |
+            Error::TimestampInvalid(ref err) |
+            Error::DuplicatePath(ref err) => err,
         }
     }
 
@@ -44,7 +52,11 @@ impl fmt::Display for Error {
             Error::InvalidEncoding(ref err) |
             Error::IoError(ref err) |
             Error::Base64EncodingError(ref err) |
-            Error::NotUrlSafeBase64(ref err) => err.fmt(f),
+            Error::NotUrlSafeBase64(ref err) |
+            Error::DigestMismatch(ref err) |
+            Error::SignatureInvalid(ref err) |
+            Error::TimestampInvalid(ref err) |
+            Error::DuplicatePath(ref err) => err.fmt(f),
         }
     }
 }
@@ -58,7 +70,11 @@ impl fmt::Debug for Error {
             Error::InvalidEncoding(ref err) |
             Error::IoError(ref err) |
             Error::Base64EncodingError(ref err) |
-            Error::NotUrlSafeBase64(ref err) => f.debug_tuple(err).finish()
+            Error::NotUrlSafeBase64(ref err) |
+            Error::DigestMismatch(ref err) |
+            Error::SignatureInvalid(ref err) |
+            Error::TimestampInvalid(ref err) |
+            Error::DuplicatePath(ref err) => f.debug_tuple(err).finish()
         }
     }
 }
@@ -110,6 +126,13 @@ impl convert::From<serde_json::error::Error> for Error {
     }
 }
 
+#[cfg(any(feature = "remote", feature = "tsa"))]
+impl convert::From<reqwest::Error> for Error {
+    fn from(error: reqwest::Error) -> Self {
+        Error::IoError(format!("{}", error))
+    }
+}
+
 impl convert::From<()> for Error {
     fn from(_: ()) -> Self {
         Error::Syntax("unknown error".to_string())
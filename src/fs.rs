@@ -1,245 +1,1995 @@
-use indicatif::{ProgressBar, ProgressStyle};
+use crate::{Error, Result};
+use crate::journal::Journal;
+use indicatif::{HumanBytes, ProgressBar, ProgressStyle};
 use num_cpus;
 use rayon;
 use sha2::{Sha512Trunc256, Digest};
-use std::collections::{BinaryHeap, BTreeSet};
+use std::collections::{BinaryHeap, BTreeMap, BTreeSet, HashMap};
 use std::cmp::Ordering;
+use std::any::Any;
+use std::fmt;
 use std::fs::File;
-use std::io::{BufReader, BufRead};
-use std::path::PathBuf;
-use std::sync::mpsc::{self, Sender, SyncSender, Receiver};
+use std::io::{self, BufReader, BufRead, Read};
+use std::path::{Path, PathBuf};
+use std::panic::{self, AssertUnwindSafe};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering as AtomicOrdering};
+use std::sync::mpsc::{self, SyncSender, Receiver, TryRecvError};
+use std::thread;
+use std::time::{Duration, SystemTime};
+use tracing::{debug, info, info_span, warn};
+use unicode_normalization::UnicodeNormalization;
+#[cfg(feature = "async")]
+use tokio_stream::{wrappers::UnboundedReceiverStream, Stream};
+
+/// The digest algorithms files can be hashed with.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Algorithm {
+    Sha512_256,
+    Blake3
+}
+
+impl Algorithm {
+    /// The name used as the digest sigil suffix in manifests.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Algorithm::Sha512_256 => "sha512_256",
+            Algorithm::Blake3 => "blake3"
+        }
+    }
+}
+
+impl FromStr for Algorithm {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "sha512_256" => Ok(Algorithm::Sha512_256),
+            "blake3" => Ok(Algorithm::Blake3),
+            _ => Err(Error::InvalidMeta(format!("unknown digest algorithm: {}", s)))
+        }
+    }
+}
+
+/// Computes the digest of everything a reader returns. The built-in
+/// algorithms implement it and an embedder can supply its own
+/// implementation of one of them, e.g. backed by a hardware accelerator,
+/// with `Hasher::file_hasher`. The digest must be 32 bytes.
+pub trait FileHasher: Send + Sync {
+    fn hash_reader(&self, r: &mut dyn Read) -> Result<Vec<u8>>;
+}
+
+impl FileHasher for Algorithm {
+    fn hash_reader(&self, r: &mut dyn Read) -> Result<Vec<u8>> {
+        Ok(digest_reader(*self, r)?.0)
+    }
+}
+
+/// A set of digest algorithms, parsed from a comma separated list of names.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AlgorithmSet(pub Vec<Algorithm>);
+
+impl AlgorithmSet {
+    pub fn contains(&self, algorithm: Algorithm) -> bool {
+        self.0.contains(&algorithm)
+    }
+
+    /// Errors unless the algorithm is in the set.
+    pub fn check(&self, algorithm: Algorithm) -> Result<()> {
+        if self.contains(algorithm) {
+            Ok(())
+        } else {
+            Err(Error::InvalidMeta(format!("the {} digest algorithm is not allowed", algorithm.name())))
+        }
+    }
+}
+
+impl FromStr for AlgorithmSet {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut algorithms = Vec::new();
+        for name in s.split(',').map(str::trim).filter(|name| !name.is_empty()) {
+            algorithms.push(Algorithm::from_str(name)?);
+        }
+        if algorithms.is_empty() {
+            return Err(Error::Syntax("no digest algorithms are allowed".to_string()));
+        }
+        Ok(AlgorithmSet(algorithms))
+    }
+}
+
+/// The number of threads to use for a requested number, 0 leaves one core
+/// free for the rest of the machine.
+pub fn thread_count(requested: usize) -> usize {
+    if requested == 0 {
+        std::cmp::max(1, num_cpus::get().saturating_sub(1))
+    } else {
+        requested
+    }
+}
+
+/// The path that stands for stdin, it is recorded as is in the manifest.
+pub static STDIN_PATH: &'static str = "-";
+
+/// Files at least this big are hashed with BLAKE3 in auto mode.
+pub const AUTO_BLAKE3_THRESHOLD: u64 = 16 * 1024 * 1024;
+
+/// How the digest algorithm is picked for each file. `Auto` hashes files
+/// smaller than the threshold with SHA-512/256 and larger ones with the
+/// faster BLAKE3, the choice is recorded per file in the manifest.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AlgorithmChoice {
+    Fixed(Algorithm),
+    Auto(u64)
+}
+
+impl AlgorithmChoice {
+    fn pick(&self, size: u64) -> Algorithm {
+        match *self {
+            AlgorithmChoice::Fixed(alg) => alg,
+            AlgorithmChoice::Auto(threshold) if size >= threshold => Algorithm::Blake3,
+            AlgorithmChoice::Auto(_) => Algorithm::Sha512_256
+        }
+    }
+}
+
+impl Default for AlgorithmChoice {
+    fn default() -> Self {
+        AlgorithmChoice::Fixed(Algorithm::Sha512_256)
+    }
+}
+
+impl FromStr for AlgorithmChoice {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "auto" => Ok(AlgorithmChoice::Auto(AUTO_BLAKE3_THRESHOLD)),
+            _ => Ok(AlgorithmChoice::Fixed(Algorithm::from_str(s)?))
+        }
+    }
+}
+
+/// How symlinks found while hashing are handled.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SymlinkMode {
+    /// Leave symlinks out of the manifest.
+    Skip,
+    /// Hash the contents of the file a symlink points to. Symlinks to
+    /// directories are still skipped so link loops can't recurse forever.
+    Content,
+    /// Record where the symlink points instead of hashing anything.
+    Target
+}
+
+impl Default for SymlinkMode {
+    fn default() -> Self {
+        SymlinkMode::Skip
+    }
+}
+
+impl FromStr for SymlinkMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "skip" => Ok(SymlinkMode::Skip),
+            "content" => Ok(SymlinkMode::Content),
+            "target" => Ok(SymlinkMode::Target),
+            _ => Err(Error::InvalidMeta(format!("unknown symlink mode: {}", s)))
+        }
+    }
+}
+
+/// How paths that are hardlinks to the same file are hashed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HardlinkMode {
+    /// Hash every path on its own.
+    HashEach,
+    /// Hash the file once and record its digest for each of its paths, only
+    /// on unix.
+    Share
+}
+
+impl Default for HardlinkMode {
+    fn default() -> Self {
+        HardlinkMode::HashEach
+    }
+}
+
+impl FromStr for HardlinkMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "hash-each" => Ok(HardlinkMode::HashEach),
+            "share" => Ok(HardlinkMode::Share),
+            _ => Err(Error::InvalidMeta(format!("unknown hardlink mode: {}", s)))
+        }
+    }
+}
+
+/// How the files are read once they are found.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ReadStrategy {
+    /// Read several files at once, one per thread. Fastest on SSDs.
+    Parallel,
+    /// Read one file at a time in path order so a spinning disk's head
+    /// doesn't have to jump between files.
+    Sequential
+}
+
+impl Default for ReadStrategy {
+    fn default() -> Self {
+        ReadStrategy::Parallel
+    }
+}
+
+impl FromStr for ReadStrategy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "parallel" => Ok(ReadStrategy::Parallel),
+            "sequential" => Ok(ReadStrategy::Sequential),
+            _ => Err(Error::InvalidMeta(format!("unknown read strategy: {}", s)))
+        }
+    }
+}
+
+static DEFAULT_TEMPLATE: &'static str = "[ETA: {eta_precise}] [{bar}] {prefix} {wide_msg}";
+static MINIMAL_TEMPLATE: &'static str = "{spinner} {prefix}";
+static DETAILED_TEMPLATE: &'static str =
+    "[{elapsed_precise}] [ETA: {eta_precise}] [{bar}] {bytes}/{total_bytes} {prefix} {wide_msg}";
+
+// the keys indicatif knows, anything else renders as nothing
+static TEMPLATE_KEYS: &'static [&'static str] = &[
+    "bar", "wide_bar", "spinner", "msg", "wide_msg", "prefix", "pos", "len", "bytes", "total_bytes", "percent",
+    "elapsed", "elapsed_precise", "eta", "eta_precise"
+];
+
+/// How progress is shown while hashing: an indicatif template or one of the
+/// "default", "minimal", "detailed" and "none" presets. The bar's position
+/// and length are in bytes and its prefix is the file count.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ProgressTemplate(Option<String>);
+
+impl ProgressTemplate {
+    /// The template, `None` if nothing is shown.
+    pub fn template(&self) -> Option<&str> {
+        self.0.as_ref().map(String::as_str)
+    }
+
+    /// Errors on a template indicatif would render wrong instead of letting it
+    /// panic or show garbage.
+    pub fn check(template: &str) -> Result<()> {
+        let invalid = |reason: &str| {
+            Err(Error::Syntax(format!("invalid progress template \"{}\": {}", template, reason)))
+        };
+        let mut wide = 0;
+        let mut rest = template;
+        while let Some(open) = rest.find(|c: char| c == '{' || c == '}') {
+            if rest[open..].starts_with('}') {
+                return invalid("unmatched \"}\"");
+            }
+            let close = match rest[open..].find('}') {
+                Some(close) => open + close,
+                None => return invalid("unmatched \"{\"")
+            };
+            let field = &rest[open + 1..close];
+            if field.contains('{') {
+                return invalid("nested \"{\"");
+            }
+            let key = field.splitn(2, ':').next().unwrap_or("");
+            if !TEMPLATE_KEYS.contains(&key) {
+                return invalid(&format!("unknown key \"{}\"", key));
+            }
+            if key.starts_with("wide_") {
+                wide += 1;
+            }
+            rest = &rest[close + 1..];
+        }
+        if wide > 1 {
+            return invalid("only one wide_bar or wide_msg fits on a line");
+        }
+        Ok(())
+    }
+}
+
+impl Default for ProgressTemplate {
+    fn default() -> Self {
+        ProgressTemplate(Some(DEFAULT_TEMPLATE.to_string()))
+    }
+}
+
+impl FromStr for ProgressTemplate {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "default" => Ok(ProgressTemplate::default()),
+            "minimal" => Ok(ProgressTemplate(Some(MINIMAL_TEMPLATE.to_string()))),
+            "detailed" => Ok(ProgressTemplate(Some(DETAILED_TEMPLATE.to_string()))),
+            "none" => Ok(ProgressTemplate(None)),
+            template => {
+                ProgressTemplate::check(template)?;
+                Ok(ProgressTemplate(Some(template.to_string())))
+            }
+        }
+    }
+}
+
+/// How the files are compressed, they are decompressed while they are read
+/// so the digests are over the uncompressed contents. Anything but `None`
+/// requires the "decompress" feature.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Compression::None
+    }
+}
+
+impl FromStr for Compression {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "none" => Ok(Compression::None),
+            "gzip" => Ok(Compression::Gzip),
+            "zstd" => Ok(Compression::Zstd),
+            _ => Err(Error::InvalidMeta(format!("unknown compression: {}", s)))
+        }
+    }
+}
 
 #[derive(Clone)]
 pub struct Hash {
     pub path: PathBuf,
-    pub hash: [u8; 32]
+    pub hash: [u8; 32],
+    pub size: u64,
+    pub algorithm: Algorithm,
+    /// Where the symlink points when it was recorded with
+    /// `SymlinkMode::Target`, the hash is all zeros then.
+    pub target: Option<PathBuf>
 }
 
 impl Hash {
-    pub fn new(p: &PathBuf, h: &[u8]) -> Self {
-        let mut hash = Hash { path: p.to_path_buf(), hash: [0u8; 32] };
+    pub fn new(p: &PathBuf, h: &[u8], size: u64, algorithm: Algorithm) -> Self {
+        let mut hash = Hash {
+            path: p.to_path_buf(),
+            hash: [0u8; 32],
+            size: size,
+            algorithm: algorithm,
+            target: None
+        };
         hash.hash.copy_from_slice(h);
         hash
     }
+
+    pub fn symlink(p: &PathBuf, target: &PathBuf) -> Self {
+        Hash {
+            path: p.to_path_buf(),
+            hash: [0u8; 32],
+            size: 0,
+            algorithm: Algorithm::Sha512_256,
+            target: Some(target.to_path_buf())
+        }
+    }
+}
+
+/// A file that was found but could not be hashed.
+#[derive(Clone)]
+pub struct Skipped {
+    pub path: PathBuf,
+    pub reason: String
+}
+
+/// The results of hashing, the hashes and the files that were skipped.
+pub struct Hashed {
+    pub hashes: Vec<Hash>,
+    pub skipped: Vec<Skipped>,
+    /// The empty directories found, if they were asked for.
+    pub empty_dirs: Vec<PathBuf>
+}
+
+impl Hashed {
+    pub fn new(hashes: Vec<Hash>) -> Self {
+        Hashed { hashes: hashes, skipped: Vec::new(), empty_dirs: Vec::new() }
+    }
+}
+
+/// The number of files found and their total size.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Count {
+    pub files: u64,
+    pub bytes: u64
+}
+
+impl fmt::Display for Count {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} files ({})", self.files, HumanBytes(self.bytes))
+    }
+}
+
+/// What kind of entry a path in the directory structure is.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EntryKind {
+    Dir,
+    File,
+    Symlink,
+    /// A fifo, socket or device.
+    Other
+}
+
+impl EntryKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            EntryKind::Dir => "dir",
+            EntryKind::File => "file",
+            EntryKind::Symlink => "symlink",
+            EntryKind::Other => "other"
+        }
+    }
+
+    pub fn from_meta(meta: &std::fs::Metadata) -> Self {
+        let file_type = meta.file_type();
+        if file_type.is_symlink() {
+            EntryKind::Symlink
+        } else if file_type.is_dir() {
+            EntryKind::Dir
+        } else if file_type.is_file() {
+            EntryKind::File
+        } else {
+            EntryKind::Other
+        }
+    }
+}
+
+impl FromStr for EntryKind {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "dir" => Ok(EntryKind::Dir),
+            "file" => Ok(EntryKind::File),
+            "symlink" => Ok(EntryKind::Symlink),
+            "other" => Ok(EntryKind::Other),
+            _ => Err(Error::InvalidMeta(format!("unknown tree entry kind: {}", s)))
+        }
+    }
+}
+
+/// A path in the directory structure with its permission bits.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TreeEntry {
+    pub path: PathBuf,
+    pub kind: EntryKind,
+    pub mode: u32
+}
+
+/// The permission bits of an entry, without the file type.
+#[cfg(unix)]
+pub fn entry_mode(meta: &std::fs::Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    meta.permissions().mode() & 0o7777
+}
+
+/// Only the read-only flag is known off unix, it is mapped to the usual
+/// unix permission bits.
+#[cfg(not(unix))]
+pub fn entry_mode(meta: &std::fs::Metadata) -> u32 {
+    match (meta.is_dir(), meta.permissions().readonly()) {
+        (true, false) => 0o755,
+        (true, true) => 0o555,
+        (false, false) => 0o644,
+        (false, true) => 0o444
+    }
+}
+
+fn walk_tree(path: &PathBuf, entries: &mut Vec<TreeEntry>) -> Result<()> {
+    let meta = long_path(path).symlink_metadata()?;
+    let kind = EntryKind::from_meta(&meta);
+    entries.push(TreeEntry { path: path.to_path_buf(), kind: kind, mode: entry_mode(&meta) });
+    if kind == EntryKind::Dir {
+        for entry in long_path(path).read_dir()? {
+            walk_tree(&path.join(entry?.file_name()), entries)?;
+        }
+    }
+    Ok(())
+}
+
+/// Lists every entry under the paths, including the paths themselves, sorted
+/// by path. Symlinks are listed but not followed.
+pub fn tree(paths: &[PathBuf]) -> Result<Vec<TreeEntry>> {
+    let mut entries = Vec::new();
+    for path in paths {
+        walk_tree(path, &mut entries)?;
+    }
+    entries.sort_by(|a, b| a.path.cmp(&b.path));
+    entries.dedup_by(|a, b| a.path == b.path);
+    Ok(entries)
+}
+
+/// Stops a running hash when it is cancelled. The workers leave the files
+/// they haven't started on alone and the hash returns with what it has so
+/// far. Clones share the one token.
+#[derive(Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        CancelToken::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, AtomicOrdering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(AtomicOrdering::SeqCst)
+    }
+}
+
+// where the coordinator puts the results as they come in
+pub(crate) trait Sink {
+    fn hashed(&mut self, hash: Hash);
+    fn skipped(&mut self, skipped: Skipped);
+    fn empty_dir(&mut self, dir: PathBuf);
+}
+
+impl Sink for Hashed {
+    fn hashed(&mut self, hash: Hash) {
+        self.hashes.push(hash);
+    }
+
+    fn skipped(&mut self, skipped: Skipped) {
+        self.skipped.push(skipped);
+    }
+
+    fn empty_dir(&mut self, dir: PathBuf) {
+        self.empty_dirs.push(dir);
+    }
+}
+
+// keeps the hashes in path order as they come in so they don't have to be
+// sorted once they are all done
+#[derive(Default)]
+struct PathOrderedSink {
+    hashes: BTreeMap<PathBuf, Hash>,
+    skipped: Vec<Skipped>,
+    empty_dirs: Vec<PathBuf>,
+}
+
+impl Sink for PathOrderedSink {
+    fn hashed(&mut self, hash: Hash) {
+        self.hashes.insert(hash.path.to_path_buf(), hash);
+    }
+
+    fn skipped(&mut self, skipped: Skipped) {
+        self.skipped.push(skipped);
+    }
+
+    fn empty_dir(&mut self, dir: PathBuf) {
+        self.empty_dirs.push(dir);
+    }
+}
+
+#[cfg(feature = "async")]
+struct ChannelSink(tokio::sync::mpsc::UnboundedSender<Result<Hash>>);
+
+#[cfg(feature = "async")]
+impl Sink for ChannelSink {
+    // a closed channel means the stream was dropped, the rest of the results
+    // are thrown away
+    fn hashed(&mut self, hash: Hash) {
+        let _ = self.0.send(Ok(hash));
+    }
+
+    fn skipped(&mut self, skipped: Skipped) {
+        let _ = self.0.send(Err(Error::IoError(format!("{}: {}", skipped.path.display(), skipped.reason))));
+    }
+
+    // the stream only has files
+    fn empty_dir(&mut self, _dir: PathBuf) {}
 }
 
 #[derive(Clone)]
 enum JobType {
-    Digest(u64, PathBuf),
-    Scan(u64, PathBuf),
+    // the job number, the file and its size
+    Digest(u64, PathBuf, u64),
+    // the job number, the directory and how many directories below the
+    // given paths it is
+    Scan(u64, PathBuf, usize),
     Hash(u64, Hash),
-    Done(u64)
+    Skip(u64, Skipped),
+    // an empty directory that was found while scanning
+    Empty(u64, PathBuf),
+    Done(u64),
+    // a worker panicked on the job, the rest of the run can't be trusted
+    Died(u64, String)
 }
 
-pub fn hash(paths: Vec<PathBuf>) -> Vec<Hash> {
+/// Configures and runs the recursive hashing of files.
+#[derive(Clone)]
+pub struct Hasher {
+    retries: u32,
+    backoff: Duration,
+    strict: bool,
+    algorithm: AlgorithmChoice,
+    symlink_mode: SymlinkMode,
+    hardlink_mode: HardlinkMode,
+    progress: bool,
+    progress_template: ProgressTemplate,
+    max_depth: Option<usize>,
+    modified_since: Option<SystemTime>,
+    strict_file_types: bool,
+    verify_stable: bool,
+    unique_content: bool,
+    threads: usize,
+    read_strategy: ReadStrategy,
+    decompress: Compression,
+    empty_dirs: bool,
+    journal: Option<PathBuf>,
+    nice: bool,
+    root: Option<PathBuf>,
+    file_hashers: Vec<(Algorithm, Arc<dyn FileHasher>)>,
+    cancel: Option<CancelToken>,
+    count_only: bool,
+    stdin: Option<StdinReader>
+}
 
-    fn classify_paths(paths: Vec<PathBuf>) -> Vec<JobType> {
-        let mut jobs = Vec::new();
-        for p in paths {
-            if let Ok(meta) = p.symlink_metadata() {
-                if meta.is_file() {
-                    jobs.push(JobType::Digest(0, p.to_path_buf()));
-                } else if meta.is_dir() {
-                    jobs.push(JobType::Scan(0, p.to_path_buf()));
-                }
+// the stream "-" is read from, it is taken by the one job that reads it
+type StdinReader = Arc<Mutex<Option<Box<dyn Read + Send>>>>;
+
+impl Hasher {
+    pub fn new() -> Self {
+        Hasher {
+            retries: 3,
+            backoff: Duration::from_millis(100),
+            strict: false,
+            algorithm: AlgorithmChoice::default(),
+            symlink_mode: SymlinkMode::default(),
+            hardlink_mode: HardlinkMode::default(),
+            progress: true,
+            progress_template: ProgressTemplate::default(),
+            max_depth: None,
+            modified_since: None,
+            strict_file_types: false,
+            verify_stable: false,
+            unique_content: false,
+            threads: num_cpus::get(),
+            read_strategy: ReadStrategy::default(),
+            decompress: Compression::default(),
+            empty_dirs: false,
+            journal: None,
+            nice: false,
+            root: None,
+            file_hashers: Vec::new(),
+            cancel: None,
+            count_only: false,
+            stdin: None
+        }
+    }
+
+    /// The digest algorithm to hash files with.
+    pub fn algorithm(mut self, algorithm: AlgorithmChoice) -> Self {
+        self.algorithm = algorithm;
+        self
+    }
+
+    /// The number of times to retry reading a file after a transient IO error.
+    pub fn retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// The delay before the first retry, it doubles with each attempt.
+    pub fn backoff(mut self, backoff: Duration) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// How symlinks are handled, they are skipped by default.
+    pub fn symlink_mode(mut self, mode: SymlinkMode) -> Self {
+        self.symlink_mode = mode;
+        self
+    }
+
+    /// Show a progress bar while hashing, turn it off when the output isn't
+    /// for a person, e.g. in benchmarks.
+    pub fn progress(mut self, progress: bool) -> Self {
+        self.progress = progress;
+        self
+    }
+
+    /// How the progress bar looks, when it is shown.
+    pub fn progress_template(mut self, template: ProgressTemplate) -> Self {
+        self.progress_template = template;
+        self
+    }
+
+    /// Error on files that are given or found more than once instead of
+    /// silently hashing them once.
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// How many directories deep to recurse below the given ones, deeper
+    /// directories are skipped. A limit of 0 only hashes the files directly
+    /// in the given directories, there is no limit by default.
+    pub fn max_depth(mut self, max_depth: Option<usize>) -> Self {
+        self.max_depth = max_depth;
+        self
+    }
+
+    /// Error on fifos, sockets and devices instead of silently leaving them
+    /// out.
+    pub fn strict_file_types(mut self, strict: bool) -> Self {
+        self.strict_file_types = strict;
+        self
+    }
+
+    /// Check that the size and modification time of each file didn't change
+    /// while it was hashed, hashing it once more if they did. Files that
+    /// keep changing are skipped.
+    pub fn verify_stable(mut self, verify_stable: bool) -> Self {
+        self.verify_stable = verify_stable;
+        self
+    }
+
+    /// Error if two files with different contents have the same digest.
+    /// Files with the same contents are expected to share a digest.
+    pub fn unique_content(mut self, unique_content: bool) -> Self {
+        self.unique_content = unique_content;
+        self
+    }
+
+    /// The number of threads that read and digest files, 0 for all cores
+    /// but one. It is one per core by default.
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.threads = thread_count(threads);
+        self
+    }
+
+    /// How the files are read, in parallel by default.
+    pub fn read_strategy(mut self, strategy: ReadStrategy) -> Self {
+        self.read_strategy = strategy;
+        self
+    }
+
+    /// Decompress the files while reading them and hash the uncompressed
+    /// contents, the sizes are the uncompressed ones too.
+    pub fn decompress(mut self, compression: Compression) -> Self {
+        self.decompress = compression;
+        self
+    }
+
+    /// Report the directories found without any entries.
+    pub fn empty_dirs(mut self, empty_dirs: bool) -> Self {
+        self.empty_dirs = empty_dirs;
+        self
+    }
+
+    /// Record each hash in the journal file as soon as it is done and reuse
+    /// the hashes already in it, see `Journal`.
+    pub fn journal(mut self, journal: Option<PathBuf>) -> Self {
+        self.journal = journal;
+        self
+    }
+
+    /// Lower the priority of the hashing threads so a big job doesn't starve
+    /// the rest of the machine, only on unix.
+    pub fn nice(mut self, nice: bool) -> Self {
+        self.nice = nice;
+        self
+    }
+
+    /// Leave out the files last modified before the time, they are reported
+    /// as skipped. A symlink counts by its target's time unless the link
+    /// itself is recorded.
+    pub fn modified_since(mut self, since: Option<SystemTime>) -> Self {
+        self.modified_since = since;
+        self
+    }
+
+    /// How paths that are hardlinks to the same file are hashed, off unix
+    /// every path is always hashed on its own.
+    pub fn hardlink_mode(mut self, mode: HardlinkMode) -> Self {
+        self.hardlink_mode = mode;
+        self
+    }
+
+    /// Refuse to read files through symlinks that point outside of the
+    /// directory, the paths given to hash have to be under it already. The
+    /// directory has to be canonical.
+    pub fn root(mut self, root: Option<PathBuf>) -> Self {
+        self.root = root;
+        self
+    }
+
+    /// Compute the digests of an algorithm with a custom implementation
+    /// instead of the built-in one. The manifest still records the
+    /// algorithm, so the implementation has to compute exactly its digests.
+    pub fn file_hasher(mut self, algorithm: Algorithm, file_hasher: Arc<dyn FileHasher>) -> Self {
+        self.file_hashers.retain(|(a, _)| *a != algorithm);
+        self.file_hashers.push((algorithm, file_hasher));
+        self
+    }
+
+    /// Stop hashing once the token is cancelled, the files that weren't
+    /// hashed by then are left out of the result.
+    pub fn cancel(mut self, cancel: Option<CancelToken>) -> Self {
+        self.cancel = cancel;
+        self
+    }
+
+    /// Read the "-" path from the reader instead of as a file, the way bs
+    /// reads stdin. It is hashed in the same run as the other paths, so it
+    /// is checked for duplicates and the same contents along with them.
+    pub fn stdin<R: Read + Send + 'static>(mut self, reader: R) -> Self {
+        self.stdin = Some(Arc::new(Mutex::new(Some(Box::new(reader)))));
+        self
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.cancel.as_ref().map_or(false, |cancel| cancel.is_cancelled())
+    }
+
+    // a journal can only be resumed with the options that change the hashes
+    // it was written with
+    fn journal_options(&self) -> String {
+        format!("{:?} {:?} {:?}", self.algorithm, self.symlink_mode, self.decompress)
+    }
+
+    // reading sequentially only needs the one worker
+    fn workers(&self) -> usize {
+        match self.read_strategy {
+            ReadStrategy::Parallel => self.threads,
+            ReadStrategy::Sequential => 1
+        }
+    }
+
+    pub fn hash(&self, paths: Vec<PathBuf>) -> Result<Hashed> {
+        let mut hashed = Hashed::new(Vec::new());
+        self.hash_into(paths, &mut hashed)?;
+        Ok(hashed)
+    }
+
+    /// Like `hash` but the hashes are in path order. They are put in order
+    /// as they are done so there is no sort over all of them at the end.
+    pub fn hash_sorted(&self, paths: Vec<PathBuf>) -> Result<Hashed> {
+        let mut sink = PathOrderedSink::default();
+        self.hash_into(paths, &mut sink)?;
+        Ok(Hashed {
+            hashes: sink.hashes.into_iter().map(|(_, hash)| hash).collect(),
+            skipped: sink.skipped,
+            empty_dirs: sink.empty_dirs
+        })
+    }
+
+    /// Hashes the files on a blocking thread and streams the hashes as they
+    /// are done, files that couldn't be read come through as errors. Only
+    /// the waiting is async, the files are still read and digested on
+    /// threads with blocking IO. It must be called from within a tokio
+    /// runtime.
+    #[cfg(feature = "async")]
+    pub fn hash_async(self, paths: Vec<PathBuf>) -> impl Stream<Item = Result<Hash>> {
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        tokio::task::spawn_blocking(move || {
+            let mut sink = ChannelSink(tx);
+            if let Err(e) = self.hash_into(paths, &mut sink) {
+                let _ = sink.0.send(Err(e));
+            }
+        });
+        UnboundedReceiverStream::new(rx)
+    }
+
+    /// Hashes a stream as it is read so memory use is constant no matter how
+    /// much data there is, it is recorded under the given path. The size
+    /// isn't known up front so auto mode picks BLAKE3.
+    pub fn hash_reader<R: Read>(&self, path: &PathBuf, reader: R) -> Result<Hash> {
+        let algorithm = self.algorithm.pick(u64::max_value());
+        let (digest, size) = digest_with(self, algorithm, reader)?;
+        Ok(Hash::new(path, &digest, size, algorithm))
+    }
+
+    pub(crate) fn hash_into<S: Sink + Send>(&self, paths: Vec<PathBuf>, sink: &mut S) -> Result<Count> {
+        // one thread for each worker and one more for the coordinator
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(self.workers() + 1).build().unwrap();
+        pool.install(|| coordinator(self, paths, sink))
+    }
+
+    /// Finds the files that would be hashed without reading them and counts
+    /// them and their bytes.
+    pub fn count(&self, paths: Vec<PathBuf>) -> Result<Count> {
+        let mut counter = self.clone();
+        counter.count_only = true;
+        counter.hash_into(paths, &mut Hashed::new(Vec::new()))
+    }
+}
+
+pub fn hash(paths: Vec<PathBuf>) -> Result<Vec<Hash>> {
+    Ok(Hasher::new().hash(paths)?.hashes)
+}
+
+/// Hashes the files without a progress bar and streams the results, see
+/// `Hasher::hash_async`.
+#[cfg(feature = "async")]
+pub fn hash_async(paths: Vec<PathBuf>) -> impl Stream<Item = Result<Hash>> {
+    Hasher::new().progress(false).hash_async(paths)
+}
+
+#[cfg(unix)]
+fn special_file_type(file_type: std::fs::FileType) -> &'static str {
+    use std::os::unix::fs::FileTypeExt;
+
+    if file_type.is_fifo() {
+        "fifo"
+    } else if file_type.is_socket() {
+        "socket"
+    } else if file_type.is_block_device() {
+        "block device"
+    } else if file_type.is_char_device() {
+        "character device"
+    } else {
+        "special file"
+    }
+}
+
+#[cfg(not(unix))]
+fn special_file_type(_file_type: std::fs::FileType) -> &'static str {
+    "special file"
+}
+
+// special files are left out unless the file types are strict, then they are
+// skipped as jobs without a number so the coordinator can tell them apart
+// from files that failed to hash
+fn classify_paths(hasher: &Hasher, paths: Vec<PathBuf>, depth: usize) -> Vec<JobType> {
+    paths.into_iter().filter_map(|p| classify_path(hasher, p, depth)).collect()
+}
+
+fn classify_path(hasher: &Hasher, p: PathBuf, depth: usize) -> Option<JobType> {
+    if is_stdin(hasher, &p) {
+        return Some(JobType::Digest(0, p, 0));
+    }
+    let meta = long_path(&p).symlink_metadata().ok()?;
+    if meta.is_file() {
+        Some(JobType::Digest(0, p, meta.len()))
+    } else if meta.is_dir() {
+        Some(JobType::Scan(0, p, depth))
+    } else if meta.file_type().is_symlink() {
+        let target = long_path(&p).metadata().ok().filter(|m| m.is_file());
+        if let (SymlinkMode::Content, Some(_), Some(root)) = (hasher.symlink_mode, &target, &hasher.root) {
+            // reading through the link would read a file outside the root
+            if !std::fs::canonicalize(&p).map(|t| t.starts_with(root)).unwrap_or(false) {
+                let reason = format!("points outside of the root {}", root.display());
+                return Some(JobType::Skip(0, Skipped { path: p, reason: reason }));
             }
         }
-        jobs
-    }
-
-    fn worker(rx: Receiver<JobType>, tx: Sender<JobType>) {
-        'worker: loop {
-            if let Ok(job) = rx.recv() {
-                match job {
-                    JobType::Digest(job_no, path) => {
-                        // digest the file
-                        if let Ok(file) = File::open(&path) {
-                            let mut hasher = Sha512Trunc256::new();
-                            let mut reader = BufReader::with_capacity(8192, file);
-                            'digest: loop {
-                                let len = {
-                                    let buf = reader.fill_buf().unwrap();
-                                    hasher.input(buf);
-                                    buf.len()
-                                };
-                                if len == 0 {
-                                    break 'digest;
-                                }
-                                reader.consume(len);
-                            }
-                            tx.send(JobType::Hash(job_no, Hash::new(&path, hasher.result().as_slice()))).unwrap();
-                        } else {
-                            tx.send(JobType::Done(job_no)).unwrap();
-                        }
+        match (hasher.symlink_mode, target) {
+            (SymlinkMode::Skip, _) => None,
+            (SymlinkMode::Content, Some(m)) => Some(JobType::Digest(0, p, m.len())),
+            (SymlinkMode::Content, None) => None,
+            (SymlinkMode::Target, _) => Some(JobType::Digest(0, p, 0))
+        }
+    } else if hasher.strict_file_types {
+        let reason = format!("is a {}", special_file_type(meta.file_type()));
+        Some(JobType::Skip(0, Skipped { path: p, reason: reason }))
+    } else {
+        None
+    }
+}
+
+// windows limits normal paths to MAX_PATH characters, the \\?\ verbatim form
+// lifts the limit but skips all normalization so the path is made absolute
+// and its . and .. components are resolved here first
+#[cfg(windows)]
+fn long_path(path: &Path) -> PathBuf {
+    use std::ffi::OsString;
+    use std::path::{Component, Prefix};
+
+    let absolute = match std::env::current_dir() {
+        Ok(dir) => dir.join(path),
+        Err(_) => return path.to_path_buf()
+    };
+
+    let mut prefix = None;
+    let mut parts = Vec::new();
+    for component in absolute.components() {
+        match component {
+            Component::Prefix(p) => {
+                match p.kind() {
+                    Prefix::Disk(disk) => {
+                        prefix = Some(format!(r"\\?\{}:", disk as char));
                     },
-                    JobType::Scan(job_no, dir) => {
-                        let dir_iter = dir.read_dir().expect(&format!("read_dir failed: {:?}", dir));
-                        let paths: Vec<PathBuf> = dir_iter.map(|res| res.unwrap().path()).collect();
-                        let jobs = classify_paths(paths);
-                        for j in jobs {
-                            tx.send(j).unwrap();
-                        }
-                        tx.send(JobType::Done(job_no)).unwrap();
+                    Prefix::UNC(server, share) => {
+                        prefix = Some(format!(r"\\?\UNC\{}\{}", server.to_string_lossy(), share.to_string_lossy()));
                     },
-                    JobType::Hash(_, _) |
-                    JobType::Done(_) => {}
+                    Prefix::Verbatim(_) |
+                    Prefix::VerbatimUNC(_, _) |
+                    Prefix::VerbatimDisk(_) |
+                    Prefix::DeviceNS(_) => return path.to_path_buf()
                 }
-            } else {
+            },
+            Component::RootDir |
+            Component::CurDir => {},
+            Component::ParentDir => { parts.pop(); },
+            Component::Normal(part) => parts.push(part)
+        }
+    }
+
+    let mut long = match prefix {
+        Some(prefix) => OsString::from(prefix),
+        None => return path.to_path_buf()
+    };
+    if parts.is_empty() {
+        long.push(r"\");
+    }
+    for part in parts {
+        long.push(r"\");
+        long.push(part);
+    }
+    PathBuf::from(long)
+}
+
+#[cfg(not(windows))]
+fn long_path(path: &Path) -> PathBuf {
+    path.to_path_buf()
+}
+
+fn is_stdin(hasher: &Hasher, path: &Path) -> bool {
+    hasher.stdin.is_some() && path == Path::new(STDIN_PATH)
+}
+
+fn is_recorded_symlink(hasher: &Hasher, path: &Path) -> bool {
+    !is_stdin(hasher, path) && hasher.symlink_mode == SymlinkMode::Target &&
+        long_path(path).symlink_metadata().map(|m| m.file_type().is_symlink()).unwrap_or(false)
+}
+
+// why a file is left out for being older than the modification time it has
+// to be newer than, a file whose time can't be read is hashed
+fn unmodified(hasher: &Hasher, path: &Path) -> Option<String> {
+    let since = hasher.modified_since?;
+    if is_stdin(hasher, path) {
+        return None;
+    }
+    let meta = if is_recorded_symlink(hasher, path) {
+        long_path(path).symlink_metadata()
+    } else {
+        long_path(path).metadata()
+    };
+    match meta.and_then(|m| m.modified()) {
+        Ok(modified) if modified < since => Some(format!("not modified since {}", humantime::format_rfc3339(since))),
+        _ => None
+    }
+}
+
+fn is_retriable(err: &io::Error) -> bool {
+    match err.kind() {
+        io::ErrorKind::Interrupted |
+        io::ErrorKind::WouldBlock |
+        io::ErrorKind::TimedOut => true,
+        _ => false
+    }
+}
+
+enum Digester {
+    Sha512_256(Sha512Trunc256),
+    Blake3(blake3::Hasher)
+}
+
+impl Digester {
+    fn new(algorithm: Algorithm) -> Self {
+        match algorithm {
+            Algorithm::Sha512_256 => Digester::Sha512_256(Sha512Trunc256::new()),
+            Algorithm::Blake3 => Digester::Blake3(blake3::Hasher::new())
+        }
+    }
+
+    fn input(&mut self, buf: &[u8]) {
+        match self {
+            Digester::Sha512_256(h) => h.input(buf),
+            Digester::Blake3(h) => { h.update(buf); }
+        }
+    }
+
+    fn result(self) -> Vec<u8> {
+        match self {
+            Digester::Sha512_256(h) => h.result().as_slice().to_vec(),
+            Digester::Blake3(h) => h.finalize().as_bytes().to_vec()
+        }
+    }
+}
+
+// returns the digest and the number of bytes read
+fn digest_reader<R: Read>(algorithm: Algorithm, reader: R) -> io::Result<(Vec<u8>, u64)> {
+    let mut hasher = Digester::new(algorithm);
+    let mut size: u64 = 0;
+    let mut reader = BufReader::with_capacity(8192, reader);
+    'digest: loop {
+        let len = {
+            let buf = reader.fill_buf()?;
+            hasher.input(buf);
+            buf.len()
+        };
+        if len == 0 {
+            break 'digest;
+        }
+        size += len as u64;
+        reader.consume(len);
+    }
+    Ok((hasher.result(), size))
+}
+
+// counts the bytes read for a custom file hasher
+struct CountingReader<R> {
+    inner: R,
+    count: u64
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.count += len as u64;
+        Ok(len)
+    }
+}
+
+// digests with the custom file hasher for the algorithm if there is one
+fn digest_with<R: Read>(hasher: &Hasher, algorithm: Algorithm, reader: R) -> io::Result<(Vec<u8>, u64)> {
+    let file_hasher = match hasher.file_hashers.iter().find(|(a, _)| *a == algorithm) {
+        Some((_, file_hasher)) => file_hasher,
+        None => return digest_reader(algorithm, reader)
+    };
+    let mut counting = CountingReader { inner: reader, count: 0 };
+    let digest = file_hasher.hash_reader(&mut counting)
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("{}", e)))?;
+    if digest.len() != 32 {
+        return Err(io::Error::new(io::ErrorKind::Other,
+                                  format!("the {} file hasher returned a {} byte digest", algorithm.name(), digest.len())));
+    }
+    Ok((digest, counting.count))
+}
+
+fn digest_once(hasher: &Hasher, path: &PathBuf) -> io::Result<Hash> {
+    if is_recorded_symlink(hasher, path) {
+        return Ok(Hash::symlink(path, &std::fs::read_link(long_path(path))?));
+    }
+    let file = File::open(long_path(path))?;
+    let algorithm = hasher.algorithm.pick(file.metadata()?.len());
+    let (digest, size) = digest_with(hasher, algorithm, decoder(hasher.decompress, file)?)?;
+    Ok(Hash::new(path, &digest, size, algorithm))
+}
+
+#[cfg(feature = "decompress")]
+fn decoder(compression: Compression, file: File) -> io::Result<Box<dyn Read>> {
+    match compression {
+        Compression::None => Ok(Box::new(file)),
+        // gzip files may have more than one member, they are concatenated
+        Compression::Gzip => Ok(Box::new(flate2::read::MultiGzDecoder::new(file))),
+        Compression::Zstd => Ok(Box::new(zstd::stream::read::Decoder::new(file)?))
+    }
+}
+
+#[cfg(not(feature = "decompress"))]
+fn decoder(compression: Compression, file: File) -> io::Result<Box<dyn Read>> {
+    match compression {
+        Compression::None => Ok(Box::new(file)),
+        _ => Err(io::Error::new(io::ErrorKind::Other, "decompressing requires the \"decompress\" feature"))
+    }
+}
+
+fn digest_retrying(hasher: &Hasher, path: &PathBuf) -> io::Result<Hash> {
+    retrying(hasher, path, || digest_once(hasher, path))
+}
+
+// retries transient errors with an exponential backoff, the read starts over
+// with a fresh file each time
+fn retrying<T>(hasher: &Hasher, path: &Path, mut read: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+    let mut attempt = 0;
+    loop {
+        match read() {
+            Ok(value) => return Ok(value),
+            Err(ref e) if attempt < hasher.retries && is_retriable(e) => {
+                debug!(path = %path.display(), attempt = attempt, error = %e, "retrying read");
+                thread::sleep(hasher.backoff * 2u32.pow(attempt));
+                attempt += 1;
+            },
+            Err(e) => return Err(e)
+        }
+    }
+}
+
+fn snapshot(path: &PathBuf) -> io::Result<(u64, Option<SystemTime>)> {
+    let meta = long_path(path).metadata()?;
+    Ok((meta.len(), meta.modified().ok()))
+}
+
+fn digest_file(hasher: &Hasher, path: &PathBuf) -> io::Result<Hash> {
+    // the stream can only be read once, so there is no retrying it or
+    // checking that it stayed the same
+    if is_stdin(hasher, path) {
+        let reader = hasher.stdin.as_ref().and_then(|stdin| stdin.lock().ok()).and_then(|mut stdin| stdin.take());
+        let reader = reader.ok_or_else(|| io::Error::new(io::ErrorKind::Other, "stdin was already read"))?;
+        let algorithm = hasher.algorithm.pick(u64::max_value());
+        let (digest, size) = digest_with(hasher, algorithm, reader)?;
+        return Ok(Hash::new(path, &digest, size, algorithm));
+    }
+    if !hasher.verify_stable || is_recorded_symlink(hasher, path) {
+        return digest_retrying(hasher, path);
+    }
+
+    // a file written to while it was read is hashed once more, if it is
+    // still changing there is no consistent snapshot to record
+    for attempt in 0..2 {
+        let before = snapshot(path)?;
+        let hash = digest_retrying(hasher, path)?;
+        // a decompressed size can't be compared to the file's
+        let same_size = hasher.decompress != Compression::None || hash.size == before.0;
+        if same_size && snapshot(path)? == before {
+            return Ok(hash);
+        }
+        debug!(path = %path.display(), attempt = attempt, "file changed during hashing");
+    }
+    Err(io::Error::new(io::ErrorKind::Other, "changed during hashing"))
+}
+
+// compared the way they were hashed, two archives of the same data can have
+// different bytes but the same decompressed contents
+fn same_contents(hasher: &Hasher, a: &PathBuf, b: &PathBuf) -> io::Result<bool> {
+    let mut a = BufReader::new(decoder(hasher.decompress, File::open(long_path(a))?)?);
+    let mut b = BufReader::new(decoder(hasher.decompress, File::open(long_path(b))?)?);
+    loop {
+        let (len, same) = {
+            let abuf = a.fill_buf()?;
+            let bbuf = b.fill_buf()?;
+            let len = abuf.len().min(bbuf.len());
+            (len, abuf[..len] == bbuf[..len] && (len > 0 || abuf.len() == bbuf.len()))
+        };
+        if !same {
+            return Ok(false);
+        }
+        if len == 0 {
+            return Ok(true);
+        }
+        a.consume(len);
+        b.consume(len);
+    }
+}
+
+// how much the workers' niceness is raised by, like nice(1) does by default
+#[cfg(unix)]
+const NICE_INCREMENT: i32 = 10;
+
+// on linux this only affects the calling thread, elsewhere the whole process.
+// getpriority returns -1 on errors but -1 is also a valid niceness, going on
+// is right either way since setpriority fails too if it was an error
+#[cfg(unix)]
+fn lower_priority() {
+    let niced = unsafe {
+        let current = libc::getpriority(libc::PRIO_PROCESS, 0);
+        libc::setpriority(libc::PRIO_PROCESS, 0, std::cmp::min(current + NICE_INCREMENT, 19))
+    };
+    if niced != 0 {
+        warn!(error = %io::Error::last_os_error(), "couldn't lower the thread priority");
+    }
+}
+
+#[cfg(not(unix))]
+fn lower_priority() {}
+
+fn panic_reason(cause: &Box<dyn Any + Send>) -> String {
+    match cause.downcast_ref::<&str>() {
+        Some(reason) => reason.to_string(),
+        None => match cause.downcast_ref::<String>() {
+            Some(reason) => reason.to_owned(),
+            None => "unknown panic".to_string()
+        }
+    }
+}
+
+fn run_job(hasher: &Hasher, journal: &Option<Arc<Journal>>, job: JobType, tx: &SyncSender<JobType>) {
+    match job {
+        JobType::Digest(job_no, path, _) if hasher.is_cancelled() => {
+            // the coordinator throws the results away once it is cancelled
+            let skipped = Skipped { path: path, reason: "cancelled".to_string() };
+            tx.send(JobType::Skip(job_no, skipped)).unwrap();
+        },
+        JobType::Digest(job_no, path, _) => {
+            // the file is looked at before it is read so a journaled
+            // hash is never newer than the file
+            let before = match journal {
+                Some(_) => snapshot(&path).ok(),
+                None => None
+            };
+            let resumed = match (journal, before) {
+                (Some(journal), Some((size, Some(modified)))) => journal.lookup(&path, size, modified),
+                _ => None
+            };
+
+            // digest the file
+            let digested = match resumed {
+                Some(hash) => Ok(hash),
+                None => digest_file(hasher, &path)
+            };
+            match digested {
+                Ok(hash) => {
+                    if let (Some(journal), Some((size, Some(modified)))) = (journal, before) {
+                        journal.record(&hash, size, modified);
+                    }
+                    tx.send(JobType::Hash(job_no, hash)).unwrap();
+                },
+                Err(e) => {
+                    let skipped = Skipped { path: path, reason: format!("{}", e) };
+                    tx.send(JobType::Skip(job_no, skipped)).unwrap();
+                }
+            }
+        },
+        JobType::Scan(job_no, _, _) if hasher.is_cancelled() => {
+            tx.send(JobType::Done(job_no)).unwrap();
+        },
+        JobType::Scan(job_no, dir, depth) => {
+            debug!(dir = %dir.display(), "scan started");
+            // the entries are joined to the original path so the
+            // long path prefix never ends up in the manifest, they
+            // are sent one at a time so a huge directory is never
+            // held in memory all at once
+            let dir_iter = match long_path(&dir).read_dir() {
+                Ok(dir_iter) => dir_iter,
+                Err(e) => {
+                    let skipped = Skipped { path: dir, reason: format!("{}", e) };
+                    tx.send(JobType::Skip(job_no, skipped)).unwrap();
+                    tx.send(JobType::Done(job_no)).unwrap();
+                    return;
+                }
+            };
+            let mut entries = 0;
+            let mut unreadable = Vec::new();
+            for res in dir_iter {
+                entries += 1;
+                let entry = match res {
+                    Ok(entry) => entry,
+                    Err(e) => {
+                        unreadable.push(format!("{}", e));
+                        continue;
+                    }
+                };
+                if let Some(j) = classify_path(hasher, dir.join(entry.file_name()), depth + 1) {
+                    tx.send(j).unwrap();
+                }
+            }
+            if entries == 0 && hasher.empty_dirs {
+                tx.send(JobType::Empty(0, dir.to_path_buf())).unwrap();
+            }
+            // the skip closes the scan's job so it comes after all of the
+            // entries that were read
+            if !unreadable.is_empty() {
+                let reason = format!("{} entries could not be read: {}", unreadable.len(), unreadable[0]);
+                tx.send(JobType::Skip(job_no, Skipped { path: dir, reason: reason })).unwrap();
+            }
+            tx.send(JobType::Done(job_no)).unwrap();
+        },
+        JobType::Hash(_, _) |
+        JobType::Skip(_, _) |
+        JobType::Empty(_, _) |
+        JobType::Done(_) |
+        JobType::Died(_, _) => {}
+    }
+}
+
+// a panic while working on a job is sent to the coordinator as the job's
+// result, otherwise the job would be waited on forever
+fn worker(hasher: Hasher, journal: Option<Arc<Journal>>, rx: Receiver<JobType>, tx: SyncSender<JobType>) {
+    if hasher.nice {
+        lower_priority();
+    }
+    'worker: loop {
+        if let Ok(job) = rx.recv() {
+            let job_no = job.job_no();
+            if let Err(cause) = panic::catch_unwind(AssertUnwindSafe(|| run_job(&hasher, &journal, job, &tx))) {
+                let _ = tx.send(JobType::Died(job_no, panic_reason(&cause)));
                 break 'worker;
             }
+        } else {
+            break 'worker;
+        }
+    }
+}
+
+/// Finds the file on disk for a path that was recorded in Unicode NFC. A
+/// component that doesn't exist as written is looked up among the entries
+/// of its directory by its NFC form, so a file whose name is stored
+/// decomposed is still found. The path is returned as it was if nothing
+/// matches.
+pub fn find_normalized(path: &Path) -> PathBuf {
+    if path.symlink_metadata().is_ok() {
+        return path.to_path_buf();
+    }
+    let mut found = PathBuf::new();
+    for component in path.components() {
+        let candidate = found.join(component.as_os_str());
+        if candidate.symlink_metadata().is_ok() {
+            found = candidate;
+            continue;
+        }
+        let wanted: Option<String> = component.as_os_str().to_str().map(|name| name.nfc().collect());
+        let dir = if found.as_os_str().is_empty() { Path::new(".") } else { found.as_path() };
+        let entry = match (wanted, dir.read_dir()) {
+            (Some(wanted), Ok(entries)) => entries.filter_map(|e| e.ok()).find(|e| {
+                e.file_name().to_str().map_or(false, |name| name.nfc().eq(wanted.chars()))
+            }),
+            _ => None
+        };
+        match entry {
+            Some(entry) => found.push(entry.file_name()),
+            None => return path.to_path_buf()
+        }
+    }
+    found
+}
+
+// the device and inode of a file with more than one hardlink
+#[cfg(unix)]
+fn shared_inode(path: &Path) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    let meta = long_path(path).metadata().ok()?;
+    if meta.nlink() > 1 {
+        Some((meta.dev(), meta.ino()))
+    } else {
+        None
+    }
+}
+
+#[cfg(not(unix))]
+fn shared_inode(_path: &Path) -> Option<(u64, u64)> {
+    None
+}
+
+// the paths that are hardlinks to a file that is already being hashed, they
+// get its result instead of being hashed again
+#[derive(Default)]
+struct Hardlinks {
+    first: HashMap<(u64, u64), PathBuf>,
+    aliases: HashMap<PathBuf, Vec<PathBuf>>,
+}
+
+impl Hardlinks {
+    // returns true if the path is another link to a file already seen
+    fn alias(&mut self, hasher: &Hasher, path: &PathBuf) -> bool {
+        if hasher.hardlink_mode != HardlinkMode::Share || is_stdin(hasher, path) || is_recorded_symlink(hasher, path) {
+            return false;
+        }
+        let inode = match shared_inode(path) {
+            Some(inode) => inode,
+            None => return false
+        };
+        match self.first.get(&inode) {
+            Some(first) => {
+                debug!(path = %path.display(), first = %first.display(), "hardlink shares a digest");
+                self.aliases.entry(first.to_path_buf()).or_insert_with(Vec::new).push(path.to_path_buf());
+                true
+            },
+            None => {
+                self.first.insert(inode, path.to_path_buf());
+                false
+            }
+        }
+    }
+
+    fn take(&mut self, path: &PathBuf) -> Vec<PathBuf> {
+        self.aliases.remove(path).unwrap_or_default()
+    }
+}
+
+// returns false if the path to digest has already been seen, recognizing the
+// same file through different paths by canonicalizing them
+fn first_sighting(hasher: &Hasher, seen: &mut BTreeSet<PathBuf>, path: &PathBuf) -> bool {
+    let canonical = if is_recorded_symlink(hasher, path) {
+        // a recorded symlink is an entry of its own and not the file it
+        // points to, so only its directory is canonicalized
+        let parent = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new(".")
+        };
+        match (std::fs::canonicalize(parent), path.file_name()) {
+            (Ok(dir), Some(name)) => dir.join(name),
+            _ => path.to_path_buf()
+        }
+    } else if is_stdin(hasher, path) {
+        path.to_path_buf()
+    } else {
+        std::fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+    };
+    seen.insert(canonical)
+}
+
+// the file total keeps growing while directories are still being scanned,
+// once they all are it is final and only hashing is left
+fn progress_prefix(scanning: u64, done: u64, total: u64) -> String {
+    let phase = if scanning > 0 { "Scanning" } else { "Hashing" };
+    format!("{} {}/{}", phase, done, total)
+}
+
+// how many results the workers may send ahead of the coordinator. a worker
+// scanning a huge directory blocks once it is this far ahead instead of
+// queueing every entry at once, the coordinator never blocks on the workers
+// so this can't deadlock
+const FEEDBACK_CAPACITY: usize = 1024;
+
+fn coordinator<S: Sink>(hasher: &Hasher, paths: Vec<PathBuf>, sink: &mut S) -> Result<Count> {
+    let span = info_span!("hash", paths = paths.len());
+    let _enter = span.enter();
+
+    // initialize the progress bar, its position and length are in bytes so
+    // the ETA follows the throughput and the file counts are the prefix
+    let mut total: u64 = 0;
+    let mut total_bytes: u64 = 0;
+    let mut job_no: u64 = 1;
+    let mut hashed_count: u64 = 0;
+    let mut skipped_count: u64 = 0;
+    let pb = match hasher.progress_template.template() {
+        Some(template) if hasher.progress => {
+            let pb = ProgressBar::new(total_bytes);
+            pb.set_style(ProgressStyle::default_bar()
+                .template(template)
+                .progress_chars("=>-"));
+            pb
+        },
+        _ => ProgressBar::hidden()
+    };
+
+    // convert the initial set of paths into jobs, dropping duplicates
+    let mut seen = BTreeSet::new();
+    let mut duplicate = None;
+    let mut special = None;
+    let mut collision = None;
+    let mut digests: HashMap<(&'static str, [u8; 32]), PathBuf> = HashMap::new();
+    let mut hardlinks = Hardlinks::default();
+    let mut jobs = BinaryHeap::new();
+
+    // reading sequentially, the files are held back until the scanning is
+    // done and then handed out in path order one at a time
+    let sequential = hasher.read_strategy == ReadStrategy::Sequential;
+    let mut deferred: BTreeMap<PathBuf, u64> = BTreeMap::new();
+    for job in classify_paths(hasher, paths, 0) {
+        if let JobType::Skip(_, ref skipped) = job {
+            special = special.or_else(|| Some(skipped.clone()));
+            continue;
+        }
+        if let JobType::Digest(_, ref path, size) = job {
+            if !first_sighting(hasher, &mut seen, path) {
+                duplicate = duplicate.or_else(|| Some(path.to_path_buf()));
+                continue;
+            }
+            if let Some(reason) = unmodified(hasher, path) {
+                debug!(path = %path.display(), reason = %reason, "file skipped");
+                sink.skipped(Skipped { path: path.to_path_buf(), reason: reason });
+                continue;
+            }
+            total += 1;
+            if hardlinks.alias(hasher, path) {
+                continue;
+            }
+            total_bytes += size;
+            if hasher.count_only {
+                continue;
+            }
+            if sequential {
+                deferred.insert(path.to_path_buf(), size);
+                continue;
+            }
         }
+        jobs.push(job);
     }
+    pb.set_length(total_bytes);
+    let mut scanning = jobs.iter().filter(|job| if let JobType::Scan(..) = job { true } else { false }).count() as u64;
+    pb.set_prefix(&progress_prefix(scanning, 0, total));
 
-    fn coordinator(paths: Vec<PathBuf>, hashes: &mut Vec<Hash>) {
+    // the waiting jobs and the number of bytes they will hash
+    let mut waiting = BTreeMap::new();
 
-        // initialize the progress bar
-        let mut total: u64 = 0;
-        let mut job_no: u64 = 1;
-        let pb = ProgressBar::new(total);
-        pb.set_style(ProgressStyle::default_bar()
-            .template("[ETA: {eta_precise}] [{bar}] {pos:>}/{len:} {wide_msg}")
-            .progress_chars("=>-"));
+    // the workers share the journal, counting never hashes anything
+    let journal = match hasher.journal {
+        Some(ref path) if !hasher.count_only => {
+            let journal = Journal::open(path, &hasher.journal_options())?;
+            info!(journal = %path.display(), hashes = journal.len(), "resuming from journal");
+            Some(Arc::new(journal))
+        },
+        _ => None
+    };
 
-        // convert the initial set of paths into jobs
-        let mut jobs = BinaryHeap::from(classify_paths(paths));
-        let mut waiting = BTreeSet::new();
+    // set up the feedback channel
+    let (tx, rx): (SyncSender<JobType>, Receiver<JobType>) = mpsc::sync_channel(FEEDBACK_CAPACITY);
 
-        // set up the feedback channel
-        let (tx, rx): (Sender<JobType>, Receiver<JobType>) = mpsc::channel();
+    // spin up the workers
+    let mut workers = Vec::new();
+    for _ in 0..hasher.workers() {
+        let (thread_tx, thread_rx): (SyncSender<JobType>, Receiver<JobType>) = mpsc::sync_channel(2);
+        workers.push(thread_tx);
+        let coord_tx = tx.clone();
+        let worker_hasher = hasher.clone();
+        let worker_journal = journal.clone();
+        rayon::spawn(move || worker(worker_hasher, worker_journal, thread_rx, coord_tx));
+    }
+    // only the workers hold senders so the channel disconnects if they all
+    // stop without reporting it
+    drop(tx);
+    let mut died = None;
 
-        // spin up the workers
-        let mut workers = Vec::new();
-        for _ in 0..num_cpus::get() {
-            let (thread_tx, thread_rx): (SyncSender<JobType>, Receiver<JobType>) = mpsc::sync_channel(2);
-            workers.push(thread_tx);
-            let coord_tx = tx.clone();
-            rayon::spawn(|| worker(thread_rx, coord_tx));
-        }
+    // loop until all jobs are processed
+    'processing: loop {
 
-        // loop until all jobs are processed
-        'processing: loop {
+        // once cancelled nothing new is handed out, the waiting jobs are
+        // still collected so the workers aren't left sending to no one
+        let cancelled = hasher.is_cancelled();
+        if cancelled {
+            jobs.clear();
+            deferred.clear();
+        }
 
-            // try to farm out jobs to workers
-            'sending: for worker in &workers {
-                if let Some(job) = jobs.peek() {
-                    let job = JobType::new_from(job_no, job);
-                    if let Ok(_) = worker.try_send(job) {
-                        // add the job number to the list of waiting jobs
-                        waiting.insert(job_no);
+        // try to farm out jobs to workers
+        'sending: for worker in &workers {
+            if let Some(job) = jobs.peek() {
+                let job = JobType::new_from(job_no, job);
+                let size = match job {
+                    JobType::Digest(_, _, size) => size,
+                    _ => 0
+                };
+                if let Ok(_) = worker.try_send(job) {
+                    // add the job number to the list of waiting jobs
+                    waiting.insert(job_no, size);
 
-                        // increment the job number and total
-                        job_no += 1;
+                    // increment the job number and total
+                    job_no += 1;
 
-                        // remove the job from the queue
-                        jobs.pop();
-                    }
-                } else {
-                    break 'sending;
+                    // remove the job from the queue
+                    jobs.pop();
+                }
+            } else {
+                break 'sending;
+            }
+        }
+        if sequential && jobs.is_empty() && waiting.is_empty() {
+            let next = deferred.iter().next().map(|(path, size)| (path.to_path_buf(), *size));
+            if let Some((path, size)) = next {
+                if let Ok(_) = workers[0].try_send(JobType::Digest(job_no, path.to_path_buf(), size)) {
+                    deferred.remove(&path);
+                    waiting.insert(job_no, size);
+                    job_no += 1;
                 }
             }
+        }
 
-            // check for incoming jobs and sort it
-            if let Ok(job) = rx.try_recv() {
-                waiting.remove(&job.job_no());
-                match job {
-                    JobType::Digest(_, _) => {
-                        total += 1;
-                        pb.set_length(total);
+        // check for incoming jobs and sort it
+        let received = rx.try_recv();
+        if let Err(TryRecvError::Disconnected) = received {
+            died = Some("all of the hashing threads stopped".to_string());
+            break 'processing;
+        }
+        if let Ok(job) = received {
+            let size = waiting.remove(&job.job_no()).unwrap_or(0);
+            let unmodified_reason = match job {
+                JobType::Digest(_, ref path, _) => unmodified(hasher, path),
+                _ => None
+            };
+            match job {
+                JobType::Digest(_, ref path, _) if !first_sighting(hasher, &mut seen, path) => {
+                    duplicate = duplicate.or_else(|| Some(path.to_path_buf()));
+                }
+                JobType::Digest(_, ref path, _) if unmodified_reason.is_some() => {
+                    let reason = unmodified_reason.unwrap_or_default();
+                    debug!(path = %path.display(), reason = %reason, "file skipped");
+                    sink.skipped(Skipped { path: path.to_path_buf(), reason: reason });
+                }
+                JobType::Digest(_, ref path, _) if hardlinks.alias(hasher, path) => {
+                    total += 1;
+                    pb.set_prefix(&progress_prefix(scanning, hashed_count + skipped_count, total));
+                }
+                JobType::Digest(_, ref path, discovered) => {
+                    total += 1;
+                    total_bytes += discovered;
+                    pb.set_length(total_bytes);
+                    pb.set_prefix(&progress_prefix(scanning, hashed_count + skipped_count, total));
+                    if !hasher.count_only && sequential {
+                        deferred.insert(path.to_path_buf(), discovered);
+                    } else if !hasher.count_only {
                         jobs.push(job);
                     }
-                    JobType::Scan(_, ref dir) => {
-                        pb.set_message(&format!("Scan: {}", dir.to_str().unwrap()));
-                        jobs.push(job);
-                    },
-                    JobType::Hash(_, hash) => {
-                        pb.inc(1);
-                        pb.set_message(&format!("Hash: {}", hash.path.to_str().unwrap()));
-                        hashes.push(hash);
+                }
+                JobType::Scan(_, ref dir, depth) if hasher.max_depth.map_or(false, |max| depth > max) => {
+                    // too deep directories are skipped without being read,
+                    // they aren't files so they don't count towards the total
+                    let reason = format!("deeper than the maximum depth of {}", hasher.max_depth.unwrap_or(0));
+                    warn!(dir = %dir.display(), reason = %reason, "directory skipped");
+                    sink.skipped(Skipped { path: dir.to_path_buf(), reason: reason });
+                }
+                JobType::Scan(_, ref dir, _) => {
+                    scanning += 1;
+                    pb.set_message(&format!("Scan: {}", dir.display()));
+                    jobs.push(job);
+                },
+                JobType::Hash(_, hash) => {
+                    debug!(path = %hash.path.display(), size = hash.size,
+                           algorithm = hash.algorithm.name(), "file hashed");
+                    if hasher.unique_content && hash.target.is_none() {
+                        // the same digest is only a collision if the
+                        // contents differ, which is checked byte by byte,
+                        // stdin can't be read again so its digest has to do
+                        match digests.get(&(hash.algorithm.name(), hash.hash)) {
+                            Some(other) if collision.is_none() => {
+                                let stdin = is_stdin(hasher, other) || is_stdin(hasher, &hash.path);
+                                if !stdin && !same_contents(hasher, other, &hash.path).unwrap_or(false) {
+                                    collision = Some((other.to_path_buf(), hash.path.to_path_buf()));
+                                }
+                            },
+                            Some(_) => {},
+                            None => {
+                                digests.insert((hash.algorithm.name(), hash.hash), hash.path.to_path_buf());
+                            }
+                        }
                     }
-                    JobType::Done(_) => {
+                    hashed_count += 1;
+                    pb.inc(size);
+                    for alias in hardlinks.take(&hash.path) {
+                        hashed_count += 1;
+                        sink.hashed(Hash { path: alias, ..hash.clone() });
+                    }
+                    pb.set_prefix(&progress_prefix(scanning, hashed_count + skipped_count, total));
+                    pb.set_message(&format!("Hash: {}", hash.path.display()));
+                    sink.hashed(hash);
+                }
+                JobType::Skip(0, skipped) => {
+                    special = special.or(Some(skipped));
+                }
+                JobType::Skip(_, _) if cancelled => {
+                    pb.inc(size);
+                }
+                JobType::Skip(_, skipped) => {
+                    warn!(path = %skipped.path.display(), reason = %skipped.reason, "file skipped");
+                    skipped_count += 1;
+                    pb.inc(size);
+                    for alias in hardlinks.take(&skipped.path) {
+                        skipped_count += 1;
+                        sink.skipped(Skipped { path: alias, reason: skipped.reason.to_owned() });
+                    }
+                    pb.set_prefix(&progress_prefix(scanning, hashed_count + skipped_count, total));
+                    pb.set_message(&format!("Skip: {}", skipped.path.display()));
+                    sink.skipped(skipped);
+                }
+                JobType::Empty(_, dir) => {
+                    debug!(dir = %dir.display(), "empty directory found");
+                    sink.empty_dir(dir);
+                }
+                JobType::Done(_) => {
+                    scanning = scanning.saturating_sub(1);
+                    if scanning == 0 {
+                        pb.set_prefix(&progress_prefix(scanning, hashed_count + skipped_count, total));
                     }
                 }
+                JobType::Died(_, reason) => {
+                    died = Some(reason);
+                    break 'processing;
+                }
             }
+        }
 
-            // check to see if all of our jobs are done
-            if waiting.is_empty() {
-                break 'processing;
-            }
+        // check to see if all of our jobs are done
+        if waiting.is_empty() && deferred.is_empty() {
+            break 'processing;
         }
+    }
 
+    if let Some(reason) = died {
+        pb.abandon();
+        return Err(Error::IoError(format!("a hashing thread died: {}", reason)));
+    }
+    if hasher.is_cancelled() {
+        pb.abandon();
+        info!(hashed = hashed_count, skipped = skipped_count, "hashing cancelled");
+    } else {
         pb.set_message("Done...");
         pb.finish();
+        info!(hashed = hashed_count, skipped = skipped_count, "hashing finished");
+    }
+
+    if let Some((a, b)) = collision {
+        return Err(Error::DigestMismatch(format!("{} and {} have the same digest but different contents",
+                                                 a.display(), b.display())));
+    }
+    if let Some(skipped) = special {
+        return Err(Error::IoError(format!("{} {}", skipped.path.display(), skipped.reason)));
+    }
+    match duplicate {
+        Some(ref path) if hasher.strict => {
+            Err(Error::DuplicatePath(format!("{} was given more than once", path.display())))
+        },
+        _ => Ok(Count { files: total, bytes: total_bytes })
     }
-    
-    let mut hashes = Vec::new();
-    let pool = rayon::ThreadPoolBuilder::new().num_threads(num_cpus::get() + 1).build().unwrap();
-    pool.install(|| coordinator(paths, &mut hashes));
-    hashes
 }
 
 impl Ord for JobType {
     fn cmp(&self, other: &JobType) -> Ordering {
         match self {
-            JobType::Digest(_, _) => {
+            JobType::Digest(_, _, _) => {
                 match other {
-                    JobType::Digest(_, _) => {
+                    JobType::Digest(_, _, _) => {
                         Ordering::Equal
                     },
-                    JobType::Scan(_, _) => {
+                    JobType::Scan(_, _, _) => {
                         Ordering::Less
                     },
                     JobType::Hash(_, _) => {
                         Ordering::Greater
                     },
+                    JobType::Skip(_, _) => {
+                        Ordering::Greater
+                    },
+                    JobType::Empty(_, _) => {
+                        Ordering::Greater
+                    },
                     JobType::Done(_) => {
                         Ordering::Greater
+                    },
+                    JobType::Died(_, _) => {
+                        Ordering::Greater
                     }
                 }
             },
-            JobType::Scan(_, _) => {
+            JobType::Scan(_, _, _) => {
                 match other {
-                    JobType::Digest(_, _) => {
+                    JobType::Digest(_, _, _) => {
                         Ordering::Greater
                     },
-                    JobType::Scan(_, _) => {
+                    JobType::Scan(_, _, _) => {
                         Ordering::Equal
                     },
                     JobType::Hash(_, _) => {
                         Ordering::Greater
                     },
+                    JobType::Skip(_, _) => {
+                        Ordering::Greater
+                    },
+                    JobType::Empty(_, _) => {
+                        Ordering::Greater
+                    },
                     JobType::Done(_) => {
                         Ordering::Greater
+                    },
+                    JobType::Died(_, _) => {
+                        Ordering::Greater
                     }
                 }
             },
             JobType::Hash(_, _) => {
                 match other {
-                    JobType::Digest(_, _) => {
+                    JobType::Digest(_, _, _) => {
+                        Ordering::Less
+                    },
+                    JobType::Scan(_, _, _) => {
+                        Ordering::Less
+                    },
+                    JobType::Hash(_, _) => {
+                        Ordering::Equal
+                    },
+                    JobType::Skip(_, _) => {
+                        Ordering::Greater
+                    },
+                    JobType::Empty(_, _) => {
+                        Ordering::Greater
+                    },
+                    JobType::Done(_) => {
+                        Ordering::Greater
+                    },
+                    JobType::Died(_, _) => {
+                        Ordering::Greater
+                    }
+                }
+            },
+            JobType::Skip(_, _) => {
+                match other {
+                    JobType::Digest(_, _, _) => {
+                        Ordering::Less
+                    },
+                    JobType::Scan(_, _, _) => {
+                        Ordering::Less
+                    },
+                    JobType::Hash(_, _) => {
+                        Ordering::Less
+                    },
+                    JobType::Skip(_, _) => {
+                        Ordering::Equal
+                    },
+                    JobType::Empty(_, _) => {
+                        Ordering::Greater
+                    },
+                    JobType::Done(_) => {
+                        Ordering::Greater
+                    },
+                    JobType::Died(_, _) => {
+                        Ordering::Greater
+                    }
+                }
+            },
+            JobType::Empty(_, _) => {
+                match other {
+                    JobType::Digest(_, _, _) => {
                         Ordering::Less
                     },
-                    JobType::Scan(_, _) => {
+                    JobType::Scan(_, _, _) => {
                         Ordering::Less
                     },
                     JobType::Hash(_, _) => {
+                        Ordering::Less
+                    },
+                    JobType::Skip(_, _) => {
+                        Ordering::Less
+                    },
+                    JobType::Empty(_, _) => {
                         Ordering::Equal
                     },
                     JobType::Done(_) => {
                         Ordering::Greater
+                    },
+                    JobType::Died(_, _) => {
+                        Ordering::Greater
                     }
                 }
             },
             JobType::Done(_) => {
                 match other {
-                    JobType::Digest(_, _) => {
+                    JobType::Digest(_, _, _) => {
+                        Ordering::Less
+                    },
+                    JobType::Scan(_, _, _) => {
+                        Ordering::Less
+                    },
+                    JobType::Hash(_, _) => {
+                        Ordering::Less
+                    },
+                    JobType::Skip(_, _) => {
+                        Ordering::Less
+                    },
+                    JobType::Empty(_, _) => {
+                        Ordering::Less
+                    },
+                    JobType::Done(_) => {
+                        Ordering::Equal
+                    },
+                    JobType::Died(_, _) => {
+                        Ordering::Greater
+                    }
+                }
+            },
+            JobType::Died(_, _) => {
+                match other {
+                    JobType::Digest(_, _, _) => {
                         Ordering::Less
                     },
-                    JobType::Scan(_, _) => {
+                    JobType::Scan(_, _, _) => {
                         Ordering::Less
                     },
                     JobType::Hash(_, _) => {
                         Ordering::Less
                     },
+                    JobType::Skip(_, _) => {
+                        Ordering::Less
+                    },
+                    JobType::Empty(_, _) => {
+                        Ordering::Less
+                    },
                     JobType::Done(_) => {
+                        Ordering::Less
+                    },
+                    JobType::Died(_, _) => {
                         Ordering::Equal
                     }
                 }
@@ -259,36 +2009,81 @@ impl Eq for JobType {}
 impl PartialEq for JobType {
     fn eq(&self, other: &JobType) -> bool {
         match self {
-            JobType::Digest(_, _) => {
+            JobType::Digest(_, _, _) => {
                 match other {
-                    JobType::Digest(_, _) => true,
-                    JobType::Scan(_, _) |
+                    JobType::Digest(_, _, _) => true,
+                    JobType::Scan(_, _, _) |
                     JobType::Hash(_, _) |
-                    JobType::Done(_) => false
+                    JobType::Skip(_, _) |
+                    JobType::Empty(_, _) |
+                    JobType::Done(_) |
+                    JobType::Died(_, _) => false
                 }
             },
-            JobType::Scan(_, _) => {
+            JobType::Scan(_, _, _) => {
                 match other {
-                    JobType::Scan(_, _) => true,
-                    JobType::Digest(_, _) |
+                    JobType::Scan(_, _, _) => true,
+                    JobType::Digest(_, _, _) |
                     JobType::Hash(_, _) |
-                    JobType::Done(_) => false
+                    JobType::Skip(_, _) |
+                    JobType::Empty(_, _) |
+                    JobType::Done(_) |
+                    JobType::Died(_, _) => false
                 }
             },
             JobType::Hash(_, _) => {
                 match other {
                     JobType::Hash(_, _) => true,
-                    JobType::Digest(_, _) |
-                    JobType::Scan(_, _) |
-                    JobType::Done(_) => false
+                    JobType::Digest(_, _, _) |
+                    JobType::Scan(_, _, _) |
+                    JobType::Skip(_, _) |
+                    JobType::Empty(_, _) |
+                    JobType::Done(_) |
+                    JobType::Died(_, _) => false
+                }
+            },
+            JobType::Skip(_, _) => {
+                match other {
+                    JobType::Skip(_, _) => true,
+                    JobType::Digest(_, _, _) |
+                    JobType::Scan(_, _, _) |
+                    JobType::Hash(_, _) |
+                    JobType::Empty(_, _) |
+                    JobType::Done(_) |
+                    JobType::Died(_, _) => false
+                }
+            },
+            JobType::Empty(_, _) => {
+                match other {
+                    JobType::Empty(_, _) => true,
+                    JobType::Digest(_, _, _) |
+                    JobType::Scan(_, _, _) |
+                    JobType::Hash(_, _) |
+                    JobType::Skip(_, _) |
+                    JobType::Done(_) |
+                    JobType::Died(_, _) => false
                 }
             },
             JobType::Done(_) => {
                 match other {
                     JobType::Done(_) => true,
-                    JobType::Digest(_, _) |
-                    JobType::Scan(_, _) |
-                    JobType::Hash(_, _) => false
+                    JobType::Digest(_, _, _) |
+                    JobType::Scan(_, _, _) |
+                    JobType::Hash(_, _) |
+                    JobType::Skip(_, _) |
+                    JobType::Empty(_, _) |
+                    JobType::Died(_, _) => false
+                }
+            },
+            JobType::Died(_, _) => {
+                match other {
+                    JobType::Died(_, _) => true,
+                    JobType::Digest(_, _, _) |
+                    JobType::Scan(_, _, _) |
+                    JobType::Hash(_, _) |
+                    JobType::Skip(_, _) |
+                    JobType::Empty(_, _) |
+                    JobType::Done(_) => false
                 }
             }
         }
@@ -298,27 +2093,507 @@ impl PartialEq for JobType {
 impl JobType {
     fn new_from(job_no: u64, job: &JobType) -> Self {
         match job {
-            JobType::Digest(_, file) => {
-                JobType::Digest(job_no, file.to_path_buf())
+            JobType::Digest(_, file, size) => {
+                JobType::Digest(job_no, file.to_path_buf(), *size)
             },
-            JobType::Scan(_, dir) => {
-                JobType::Scan(job_no, dir.to_path_buf())
+            JobType::Scan(_, dir, depth) => {
+                JobType::Scan(job_no, dir.to_path_buf(), *depth)
             },
             JobType::Hash(_, hash) => {
                 JobType::Hash(job_no, hash.clone())
             },
+            JobType::Skip(_, skipped) => {
+                JobType::Skip(job_no, skipped.clone())
+            },
+            JobType::Empty(_, dir) => {
+                JobType::Empty(job_no, dir.to_path_buf())
+            },
             JobType::Done(_) => {
                 JobType::Done(job_no)
+            },
+            JobType::Died(_, reason) => {
+                JobType::Died(job_no, reason.to_owned())
             }
         }
     }
 
     fn job_no(&self) -> u64 {
         match *self {
-            JobType::Digest(job_no, _) |
-            JobType::Scan(job_no, _) |
+            JobType::Digest(job_no, _, _) |
+            JobType::Scan(job_no, _, _) |
             JobType::Hash(job_no, _) |
-            JobType::Done(job_no) => job_no
+            JobType::Skip(job_no, _) |
+            JobType::Empty(job_no, _) |
+            JobType::Done(job_no) |
+            JobType::Died(job_no, _) => job_no
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use std::cell::Cell;
+    use std::sync::atomic::AtomicUsize;
+
+    fn quick_retries() -> Hasher {
+        Hasher::new().progress(false).retries(2).backoff(Duration::from_millis(1))
+    }
+
+    #[test]
+    fn retries_interrupted_reads() {
+        let calls = Cell::new(0);
+        let read = retrying(&quick_retries(), Path::new("flaky"), || {
+            calls.set(calls.get() + 1);
+            if calls.get() < 3 {
+                Err(io::Error::new(io::ErrorKind::Interrupted, "injected"))
+            } else {
+                Ok(42)
+            }
+        });
+        assert_eq!(read.unwrap(), 42);
+        assert_eq!(calls.get(), 3);
+    }
+
+    #[test]
+    fn gives_up_after_the_retries() {
+        let calls = Cell::new(0);
+        let read: io::Result<()> = retrying(&quick_retries(), Path::new("flaky"), || {
+            calls.set(calls.get() + 1);
+            Err(io::Error::new(io::ErrorKind::Interrupted, "injected"))
+        });
+        assert_eq!(read.unwrap_err().kind(), io::ErrorKind::Interrupted);
+        assert_eq!(calls.get(), 3);
+    }
+
+    #[test]
+    fn fails_fast_on_permanent_errors() {
+        for kind in &[io::ErrorKind::NotFound, io::ErrorKind::PermissionDenied] {
+            let calls = Cell::new(0);
+            let read: io::Result<()> = retrying(&quick_retries(), Path::new("gone"), || {
+                calls.set(calls.get() + 1);
+                Err(io::Error::new(*kind, "injected"))
+            });
+            assert_eq!(read.unwrap_err().kind(), *kind);
+            assert_eq!(calls.get(), 1);
+        }
+    }
+
+    fn hashed_paths(hashed: &Hashed) -> BTreeSet<PathBuf> {
+        hashed.hashes.iter().map(|hash| hash.path.to_path_buf()).collect()
+    }
+
+    // a file at every level of a chain of nested directories
+    fn nested_tree(name: &str, levels: usize) -> (PathBuf, Vec<PathBuf>) {
+        let root = testing::scratch_dir(name);
+        let mut dir = root.clone();
+        let mut files = Vec::new();
+        for level in 0..levels {
+            files.push(dir.join(format!("f{}", level)));
+            testing::write(files.last().unwrap(), format!("level {}", level).as_bytes());
+            dir.push(format!("d{}", level + 1));
+        }
+        (root, files)
+    }
+
+    #[test]
+    fn deep_trees_are_hashed_without_a_limit() {
+        let (root, files) = nested_tree("deep", 64);
+        let hashed = Hasher::new().progress(false).hash(vec![root]).unwrap();
+        assert_eq!(hashed_paths(&hashed), files.into_iter().collect());
+        assert!(hashed.skipped.is_empty());
+    }
+
+    #[test]
+    fn max_depth_skips_deeper_directories() {
+        let (root, files) = nested_tree("max-depth", 8);
+        let hashed = Hasher::new().progress(false).max_depth(Some(2)).hash(vec![root.clone()]).unwrap();
+        // the given directory is depth 0, its files and those of the next
+        // two levels are hashed
+        assert_eq!(hashed_paths(&hashed), files[..3].iter().cloned().collect());
+        assert_eq!(hashed.skipped.len(), 1);
+        assert_eq!(hashed.skipped[0].path, root.join("d1").join("d2").join("d3"));
+        assert!(hashed.skipped[0].reason.contains("maximum depth of 2"));
+    }
+
+    #[test]
+    fn huge_flat_directories_are_hashed() {
+        // several times more entries than the workers may send ahead
+        let root = testing::scratch_dir("flat");
+        let files: BTreeSet<PathBuf> = (0..FEEDBACK_CAPACITY * 8).map(|i| root.join(format!("f{:05}", i))).collect();
+        for (i, file) in files.iter().enumerate() {
+            testing::write(file, i.to_string().as_bytes());
+        }
+        for threads in &[1, 4] {
+            let hashed = Hasher::new().progress(false).threads(*threads).hash(vec![root.clone()]).unwrap();
+            assert_eq!(hashed_paths(&hashed), files);
+            assert!(hashed.skipped.is_empty());
+        }
+    }
+
+    #[cfg(unix)]
+    fn mkfifo(path: &Path) {
+        use std::ffi::CString;
+        use std::os::unix::ffi::OsStrExt;
+
+        let c_path = CString::new(path.as_os_str().as_bytes()).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(c_path.as_ptr(), 0o600) }, 0, "mkfifo {}", path.display());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn fifos_are_left_out() {
+        let root = testing::scratch_dir("fifo");
+        testing::write(&root.join("file"), b"regular");
+        mkfifo(&root.join("fifo"));
+        let hashed = Hasher::new().progress(false).hash(vec![root.clone()]).unwrap();
+        assert_eq!(hashed_paths(&hashed), vec![root.join("file")].into_iter().collect());
+        assert!(hashed.skipped.is_empty());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn strict_file_types_reject_fifos() {
+        let root = testing::scratch_dir("strict-fifo");
+        testing::write(&root.join("file"), b"regular");
+        mkfifo(&root.join("fifo"));
+        // found while scanning and given directly
+        for paths in vec![vec![root.clone()], vec![root.join("fifo")]] {
+            match Hasher::new().progress(false).strict_file_types(true).hash(paths) {
+                Err(Error::IoError(msg)) => {
+                    assert!(msg.contains(&format!("{} is a fifo", root.join("fifo").display())), "{}", msg)
+                },
+                Err(e) => panic!("unexpected error: {}", e),
+                Ok(_) => panic!("the fifo wasn't rejected")
+            }
+        }
+    }
+
+    #[cfg(unix)]
+    fn set_mtime(path: &Path, secs: u64) {
+        use std::ffi::CString;
+        use std::os::unix::ffi::OsStrExt;
+
+        let c_path = CString::new(path.as_os_str().as_bytes()).unwrap();
+        let time = libc::timeval { tv_sec: secs as libc::time_t, tv_usec: 0 };
+        assert_eq!(unsafe { libc::utimes(c_path.as_ptr(), [time, time].as_ptr()) }, 0, "utimes {}", path.display());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn only_files_modified_since_are_hashed() {
+        // 2000-01-01, 2020-01-01 and 2021-01-01
+        let (old, since, new) = (946_684_800, 1_577_836_800, 1_609_459_200);
+        let root = testing::scratch_dir("modified-since");
+        for (name, mtime) in &[("old", old), ("exactly", since), ("new", new)] {
+            testing::write(&root.join("tree").join(name), name.as_bytes());
+            set_mtime(&root.join("tree").join(name), *mtime);
+        }
+        testing::write(&root.join("given"), b"given directly");
+        set_mtime(&root.join("given"), old);
+
+        let hashed = Hasher::new()
+            .progress(false)
+            .modified_since(Some(SystemTime::UNIX_EPOCH + Duration::from_secs(since)))
+            .hash(vec![root.join("tree"), root.join("given")])
+            .unwrap();
+        assert_eq!(hashed_paths(&hashed), vec![root.join("tree").join("exactly"), root.join("tree").join("new")]
+            .into_iter().collect());
+        let mut skipped: Vec<(PathBuf, String)> = hashed.skipped.into_iter().map(|s| (s.path, s.reason)).collect();
+        skipped.sort();
+        let reason = "not modified since 2020-01-01T00:00:00Z".to_string();
+        assert_eq!(skipped, vec![(root.join("given"), reason.to_owned()), (root.join("tree").join("old"), reason)]);
+    }
+
+    // appends to the file it is hashing the first few times, like a log
+    // that is still being written
+    struct GrowingFile {
+        path: PathBuf,
+        writes: AtomicUsize,
+    }
+
+    impl FileHasher for GrowingFile {
+        fn hash_reader(&self, r: &mut dyn Read) -> Result<Vec<u8>> {
+            let mut data = Vec::new();
+            r.read_to_end(&mut data)?;
+            if self.writes.load(AtomicOrdering::SeqCst) > 0 {
+                self.writes.fetch_sub(1, AtomicOrdering::SeqCst);
+                use std::io::Write;
+                std::fs::OpenOptions::new().append(true).open(&self.path)?.write_all(b" more")?;
+            }
+            Algorithm::Sha512_256.hash_reader(&mut &data[..])
         }
     }
+
+    fn hash_growing(name: &str, writes: usize) -> Hashed {
+        let path = testing::scratch_dir(name).join("log");
+        testing::write(&path, b"started");
+        let growing = GrowingFile { path: path.to_path_buf(), writes: AtomicUsize::new(writes) };
+        Hasher::new()
+            .progress(false)
+            .verify_stable(true)
+            .file_hasher(Algorithm::Sha512_256, Arc::new(growing))
+            .hash(vec![path])
+            .unwrap()
+    }
+
+    #[test]
+    fn a_file_changed_once_is_hashed_again() {
+        let hashed = hash_growing("stable-once", 1);
+        assert!(hashed.skipped.is_empty());
+        assert_eq!(hashed.hashes.len(), 1);
+        // the second read saw the whole file
+        assert_eq!(hashed.hashes[0].size, b"started more".len() as u64);
+        let (digest, _) = digest_reader(Algorithm::Sha512_256, &b"started more"[..]).unwrap();
+        assert_eq!(hashed.hashes[0].hash[..], digest[..]);
+    }
+
+    #[test]
+    fn a_file_that_keeps_changing_is_skipped() {
+        let hashed = hash_growing("stable-never", 5);
+        assert!(hashed.hashes.is_empty());
+        assert_eq!(hashed.skipped.len(), 1);
+        assert_eq!(hashed.skipped[0].reason, "changed during hashing");
+    }
+
+    struct Panicking;
+
+    impl FileHasher for Panicking {
+        fn hash_reader(&self, _r: &mut dyn Read) -> Result<Vec<u8>> {
+            panic!("injected panic")
+        }
+    }
+
+    #[test]
+    fn a_worker_panic_is_an_error() {
+        let root = testing::scratch_dir("panic");
+        for i in 0..8 {
+            testing::write(&root.join(format!("f{}", i)), b"contents");
+        }
+        // hashed on another thread so a hang fails the test instead of
+        // stalling it
+        let (tx, rx) = mpsc::channel();
+        std::thread::spawn(move || {
+            let hashed = Hasher::new()
+                .progress(false)
+                .threads(2)
+                .file_hasher(Algorithm::Sha512_256, Arc::new(Panicking))
+                .hash(vec![root]);
+            let _ = tx.send(hashed.map(|_| ()));
+        });
+        match rx.recv_timeout(Duration::from_secs(30)) {
+            Ok(Err(Error::IoError(msg))) => assert_eq!(msg, "a hashing thread died: injected panic"),
+            Ok(Err(e)) => panic!("unexpected error: {}", e),
+            Ok(Ok(())) => panic!("the panic was lost"),
+            Err(_) => panic!("hashing didn't return after a worker panicked")
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unreadable_directories_are_skipped() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let root = testing::scratch_dir("unreadable-dir");
+        testing::write(&root.join("file"), b"readable");
+        testing::write(&root.join("locked").join("hidden"), b"unreadable");
+        std::fs::set_permissions(root.join("locked"), std::fs::Permissions::from_mode(0o000)).unwrap();
+        if std::fs::read_dir(root.join("locked")).is_ok() {
+            // permissions don't keep root out
+            return;
+        }
+        let hashed = Hasher::new().progress(false).hash(vec![root.clone()]);
+        std::fs::set_permissions(root.join("locked"), std::fs::Permissions::from_mode(0o700)).unwrap();
+        let hashed = hashed.unwrap();
+        assert_eq!(hashed_paths(&hashed), vec![root.join("file")].into_iter().collect());
+        assert_eq!(hashed.skipped.len(), 1);
+        assert_eq!(hashed.skipped[0].path, root.join("locked"));
+        assert!(hashed.skipped[0].reason.contains("ermission denied"), "{}", hashed.skipped[0].reason);
+    }
+
+    // counts the files it reads and cancels the run once it has read enough
+    struct Interrupting {
+        hashed: AtomicUsize,
+        cancel_after: usize,
+        cancel: CancelToken,
+    }
+
+    impl FileHasher for Interrupting {
+        fn hash_reader(&self, r: &mut dyn Read) -> Result<Vec<u8>> {
+            if self.hashed.fetch_add(1, AtomicOrdering::SeqCst) + 1 == self.cancel_after {
+                self.cancel.cancel();
+            }
+            Algorithm::Sha512_256.hash_reader(r)
+        }
+    }
+
+    fn journaled(journal: &Path, interrupting: Arc<Interrupting>) -> Hasher {
+        Hasher::new()
+            .progress(false)
+            .threads(1)
+            .journal(Some(journal.to_path_buf()))
+            .cancel(Some(interrupting.cancel.clone()))
+            .file_hasher(Algorithm::Sha512_256, interrupting)
+    }
+
+    #[test]
+    fn an_interrupted_run_is_resumed() {
+        let root = testing::scratch_dir("resume");
+        let tree = root.join("tree");
+        let files: Vec<PathBuf> = (0..20).map(|i| tree.join(format!("f{:02}", i))).collect();
+        for (i, file) in files.iter().enumerate() {
+            testing::write(file, format!("file {}", i).as_bytes());
+        }
+        let journal = root.join("journal");
+
+        let interrupting = |cancel_after| {
+            let hashed = AtomicUsize::new(0);
+            Arc::new(Interrupting { hashed: hashed, cancel_after: cancel_after, cancel: CancelToken::new() })
+        };
+        let first = interrupting(5);
+        let interrupted = journaled(&journal, first.clone()).hash(vec![tree.clone()]).unwrap();
+        assert_eq!(first.hashed.load(AtomicOrdering::SeqCst), 5);
+        assert_eq!(interrupted.hashes.len(), 5);
+
+        // the resumed run only reads the files the first one didn't get to
+        let second = interrupting(0);
+        let resumed = journaled(&journal, second.clone()).hash_sorted(vec![tree.clone()]).unwrap();
+        assert_eq!(second.hashed.load(AtomicOrdering::SeqCst), files.len() - 5);
+        assert!(resumed.skipped.is_empty());
+
+        let fresh = Hasher::new().progress(false).hash_sorted(vec![tree.clone()]).unwrap();
+        assert_eq!(resumed.hashes.len(), files.len());
+        for (resumed, fresh) in resumed.hashes.iter().zip(&fresh.hashes) {
+            assert_eq!((&resumed.path, resumed.hash, resumed.size), (&fresh.path, fresh.hash, fresh.size));
+        }
+    }
+
+    struct Counting(AtomicUsize);
+
+    impl FileHasher for Counting {
+        fn hash_reader(&self, r: &mut dyn Read) -> Result<Vec<u8>> {
+            self.0.fetch_add(1, AtomicOrdering::SeqCst);
+            Algorithm::Sha512_256.hash_reader(r)
+        }
+    }
+
+    // the number of files that were read and the hashes in path order
+    fn hash_counting(hasher: Hasher, paths: Vec<PathBuf>) -> (usize, Vec<Hash>) {
+        let counting = Arc::new(Counting(AtomicUsize::new(0)));
+        let hashed = hasher.progress(false).file_hasher(Algorithm::Sha512_256, counting.clone()).hash_sorted(paths);
+        (counting.0.load(AtomicOrdering::SeqCst), hashed.unwrap().hashes)
+    }
+
+    #[test]
+    fn hardlinks_are_shared() {
+        let root = testing::scratch_dir("hardlinks");
+        testing::write(&root.join("a"), b"linked");
+        std::fs::hard_link(root.join("a"), root.join("b")).unwrap();
+        testing::write(&root.join("c"), b"linked");
+
+        let (reads, each) = hash_counting(Hasher::new(), vec![root.clone()]);
+        assert_eq!(reads, 3);
+        let (reads, shared) = hash_counting(Hasher::new().hardlink_mode(HardlinkMode::Share), vec![root.clone()]);
+        // off unix links can't be told apart so every path is read
+        assert_eq!(reads, if cfg!(unix) { 2 } else { 3 });
+
+        // both names of the link are recorded with the one digest
+        let paths = vec![root.join("a"), root.join("b"), root.join("c")];
+        for hashes in &[&each, &shared] {
+            assert_eq!(hashes.iter().map(|hash| hash.path.to_path_buf()).collect::<Vec<_>>(), paths);
+            assert!(hashes.iter().all(|hash| hash.hash == each[0].hash && hash.size == 6));
+        }
+    }
+
+    #[test]
+    fn stdin_is_hashed_with_the_files() {
+        let root = testing::scratch_dir("stdin");
+        testing::write(&root.join("a"), b"streamed");
+        std::fs::hard_link(root.join("a"), root.join("b")).unwrap();
+        let stdin = PathBuf::from(STDIN_PATH);
+        let streamed = || io::Cursor::new(b"streamed".to_vec());
+
+        // the links on either side of the stream are still shared
+        let hasher = Hasher::new().hardlink_mode(HardlinkMode::Share).stdin(streamed());
+        let (reads, hashes) = hash_counting(hasher, vec![root.join("a"), stdin.clone(), root.join("b")]);
+        assert_eq!(reads, if cfg!(unix) { 2 } else { 3 });
+        assert_eq!(hashes.iter().map(|hash| hash.path.to_path_buf()).collect::<Vec<_>>(),
+                   vec![root.join("a"), root.join("b"), stdin.clone()]);
+        assert!(hashes.iter().all(|hash| hash.hash == hashes[0].hash && hash.size == 8));
+
+        // the same contents as a file isn't a collision
+        let hasher = Hasher::new().progress(false).unique_content(true).stdin(streamed());
+        assert_eq!(hasher.hash(vec![root.join("a"), stdin.clone()]).unwrap().hashes.len(), 2);
+
+        // the files given before and after it are one list
+        let hasher = Hasher::new().progress(false).strict(true).stdin(streamed());
+        match hasher.hash(vec![root.join("a"), stdin.clone(), root.join("a")]) {
+            Err(err) => assert_eq!(err, Error::DuplicatePath(format!("{} was given more than once",
+                                                                     root.join("a").display()))),
+            Ok(_) => panic!("a duplicate across stdin was accepted")
+        }
+        let hasher = Hasher::new().progress(false).strict(true).stdin(streamed());
+        match hasher.hash(vec![stdin.clone(), stdin.clone()]) {
+            Err(err) => assert_eq!(err, Error::DuplicatePath("- was given more than once".to_string())),
+            Ok(_) => panic!("stdin was read twice")
+        }
+    }
+
+    #[cfg(feature = "decompress")]
+    #[test]
+    fn archives_of_the_same_contents_are_not_collisions() {
+        use std::io::Write;
+
+        let root = testing::scratch_dir("unique-gzip");
+        let contents = b"the same data compressed twice".repeat(100);
+        for (name, level) in &[("fast.gz", flate2::Compression::fast()), ("best.gz", flate2::Compression::best())] {
+            let mut gz = flate2::write::GzEncoder::new(Vec::new(), *level);
+            gz.write_all(&contents).unwrap();
+            testing::write(&root.join(name), &gz.finish().unwrap());
+        }
+        assert_ne!(std::fs::read(root.join("fast.gz")).unwrap(), std::fs::read(root.join("best.gz")).unwrap());
+
+        let hasher = Hasher::new().progress(false).unique_content(true).decompress(Compression::Gzip);
+        let hashed = hasher.hash_sorted(vec![root.clone()]).unwrap();
+        assert_eq!(hashed.hashes.len(), 2);
+        assert_eq!(hashed.hashes[0].hash, hashed.hashes[1].hash);
+    }
+
+    #[test]
+    fn algorithm_sets() {
+        let set = AlgorithmSet::from_str("sha512_256, blake3").unwrap();
+        assert!(set.check(Algorithm::Sha512_256).is_ok() && set.check(Algorithm::Blake3).is_ok());
+        assert_eq!(AlgorithmSet::from_str("blake3").unwrap().check(Algorithm::Sha512_256),
+                   Err(Error::InvalidMeta("the sha512_256 digest algorithm is not allowed".to_string())));
+        assert_eq!(AlgorithmSet::from_str("blake3,md5"),
+                   Err(Error::InvalidMeta("unknown digest algorithm: md5".to_string())));
+        assert!(AlgorithmSet::from_str(" , ").is_err());
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn long_paths_are_verbatim() {
+        assert_eq!(long_path(Path::new(r"C:\a\.\b\..\c")), PathBuf::from(r"\\?\C:\a\c"));
+        assert_eq!(long_path(Path::new(r"\\server\share\a\b")), PathBuf::from(r"\\?\UNC\server\share\a\b"));
+        assert_eq!(long_path(Path::new(r"\\?\C:\a\..\b")), PathBuf::from(r"\\?\C:\a\..\b"));
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn hashes_past_max_path() {
+        let root = testing::scratch_dir("long-path");
+        let mut deep = root.clone();
+        while deep.as_os_str().len() < 300 {
+            deep.push("a-directory-name-to-get-past-max-path");
+        }
+        std::fs::create_dir_all(long_path(&deep)).unwrap();
+        std::fs::write(long_path(&deep.join("file")), b"deep").unwrap();
+
+        let hashed = Hasher::new().progress(false).hash(vec![root]).unwrap();
+        assert!(hashed.skipped.is_empty());
+        assert_eq!(hashed.hashes.len(), 1);
+        // the manifest gets the path as it was given, without the prefix
+        assert_eq!(hashed.hashes[0].path, deep.join("file"));
+    }
 }
@@ -1,8 +1,9 @@
-use base64::{decode_config, URL_SAFE};
+use base64::{decode_config, encode_config, URL_SAFE, URL_SAFE_NO_PAD};
 use crate::{Error, Result};
-use diddir::DIDDir;
+use diddir::{Config, DIDDir};
+use once_cell::sync::Lazy;
 use regex::Regex;
-use serde_json::{self, Value as JsonValue};
+use serde_json::{self, json, Value as JsonValue};
 use sodiumoxide::crypto::secretbox::{
     self, 
     Key as BoxKey,
@@ -11,10 +12,13 @@ use sodiumoxide::crypto::secretbox::{
     NONCEBYTES
 };
 use sodiumoxide::crypto::sign::{
+    self,
     PublicKey,
     SecretKey,
+    Seed,
     PUBLICKEYBYTES,
-    SECRETKEYBYTES
+    SECRETKEYBYTES,
+    SEEDBYTES
 };
 use sodiumoxide::crypto::pwhash::argon2id13::{
     self,
@@ -23,7 +27,21 @@ use sodiumoxide::crypto::pwhash::argon2id13::{
     OPSLIMIT_SENSITIVE,
     MEMLIMIT_SENSITIVE
 };
+use sha2::{Digest, Sha512Trunc256};
 use std::convert::{self, TryFrom};
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tracing::{debug, warn};
+
+static PUBLICKEY_REGEX: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"^@(?P<data>[A-Za-z0-9-_=]+)\.ed25519\n*$").unwrap()
+});
+
+static SECRETKEY_REGEX: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"(?P<data>[A-Za-z0-9-_=]+).box.xsalsa20poly1305\n*").unwrap()
+});
 
 #[derive(Clone)]
 pub struct VerifyKey(pub [u8; PUBLICKEYBYTES]);
@@ -32,11 +50,8 @@ impl convert::TryFrom<&String> for VerifyKey {
     type Error = Error;
 
     fn try_from(sb: &String) -> Result<Self> {
-        static PUBLICKEY_REGEX: &'static str = 
-            r"@(?P<data>[A-Za-z0-9-_=]+).ed25519\n*";
-
         // 1. use regex to extract base64 encoded verify key
-        let re = Regex::new(PUBLICKEY_REGEX)?;
+        let re = &*PUBLICKEY_REGEX;
         if !re.is_match(sb) {
             return Err(Error::InvalidEncoding("not valid sb public key".to_string()));
         }
@@ -45,8 +60,6 @@ impl convert::TryFrom<&String> for VerifyKey {
 
         // 2. decode the base64 into a Vec<u8>
         let data = decode_config(cap.as_str(), URL_SAFE)?;
-        
-        //println!("\nVerifyKey: {:x}", ByteBuff(&data));
 
         // 3. create a verify key from the binary
         if data.as_slice().len() != PUBLICKEYBYTES {
@@ -73,6 +86,134 @@ impl convert::Into<PublicKey> for VerifyKey {
     }
 }
 
+// a JWK must be an Ed25519 OKP key, the member is decoded from unpadded
+// base64url
+fn jwk_member(json: &JsonValue, name: &str) -> Result<Vec<u8>> {
+    if json["kty"].as_str() != Some("OKP") {
+        return Err(Error::InvalidEncoding("JWK \"kty\" must be \"OKP\"".to_string()));
+    }
+    if json["crv"].as_str() != Some("Ed25519") {
+        return Err(Error::InvalidEncoding("JWK \"crv\" must be \"Ed25519\"".to_string()));
+    }
+    match json[name].as_str() {
+        Some(data) => Ok(decode_config(data, URL_SAFE_NO_PAD)?),
+        None => Err(Error::InvalidEncoding(format!("JWK has no \"{}\"", name)))
+    }
+}
+
+impl VerifyKey {
+    /// Makes a verify key from the raw 32 byte Ed25519 public key.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() != PUBLICKEYBYTES {
+            return Err(Error::Syntax("not the right number of bytes for a verify key".to_string()));
+        }
+        let mut vk = VerifyKey([0; PUBLICKEYBYTES]);
+        vk.0.copy_from_slice(bytes);
+        Ok(vk)
+    }
+
+    /// The raw public key.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0[..]
+    }
+
+    /// The canonical `@<b64>.ed25519` pkid of the key.
+    pub fn pkid(&self) -> String {
+        format!("@{}.ed25519", encode_config(&self.0[..], URL_SAFE))
+    }
+
+    /// A short fingerprint for comparing keys by eye, the first 8 bytes of
+    /// the SHA-512/256 of the key in colon separated hex.
+    pub fn fingerprint(&self) -> String {
+        let digest = Sha512Trunc256::digest(&self.0[..]);
+        digest[..8].iter().map(|b| format!("{:02x}", b)).collect::<Vec<String>>().join(":")
+    }
+
+    /// The key as a public JWK.
+    pub fn to_jwk(&self) -> String {
+        format!("{:#}\n", json!({
+            "kty": "OKP",
+            "crv": "Ed25519",
+            "x": encode_config(&self.0[..], URL_SAFE_NO_PAD)
+        }))
+    }
+
+    /// Reads the public part of an Ed25519 JWK.
+    pub fn from_jwk(jwk: &str) -> Result<Self> {
+        VerifyKey::from_bytes(&jwk_member(&serde_json::from_str(jwk)?, "x")?)
+    }
+}
+
+// the file the passphrase is read from, the built-in one is used without it.
+// nothing else is ever read, there is no passphrase fd or environment variable
+static PASSPHRASE_FILE: Lazy<Mutex<Option<PathBuf>>> = Lazy::new(|| Mutex::new(None));
+// the same in every copy of bs, so it protects nothing
+static DEFAULT_PASSPHRASE: &'static [u8] = b"test";
+const MAX_PASSPHRASE_BYTES: u64 = 4096;
+
+/// Reads the passphrase that seals the identities' secret keys from the file
+/// instead of using the built-in one. The file is read again each time a key
+/// is sealed or unlocked, one trailing newline is trimmed off.
+pub fn set_passphrase_file(path: Option<PathBuf>) {
+    if let Ok(mut file) = PASSPHRASE_FILE.lock() {
+        *file = path;
+    }
+}
+
+fn read_passphrase(path: &Path) -> Result<Vec<u8>> {
+    let file = File::open(path)?;
+    let len = file.metadata()?.len();
+    if len == 0 || len > MAX_PASSPHRASE_BYTES {
+        return Err(Error::Syntax(format!("{} must hold a passphrase of 1 to {} bytes",
+                                         path.display(), MAX_PASSPHRASE_BYTES)));
+    }
+    // one read into a buffer that is big enough up front, a growing buffer
+    // would leave copies of the passphrase behind wherever it was moved from
+    let mut passwd = Vec::with_capacity(len as usize + 1);
+    file.take(len).read_to_end(&mut passwd)?;
+    if passwd.ends_with(b"\n") {
+        passwd.pop();
+        if passwd.ends_with(b"\r") {
+            passwd.pop();
+        }
+    }
+    Ok(passwd)
+}
+
+// the passphrase file is the only place the passphrase comes from, without
+// it the built-in one is used. BS_PASSPHRASE is never read, it is only warned
+// about so nobody takes it for a way to give the passphrase
+fn passphrase() -> Result<Vec<u8>> {
+    let path = PASSPHRASE_FILE.lock().ok().and_then(|file| file.clone());
+    match path {
+        Some(ref path) => read_passphrase(path),
+        None => {
+            if std::env::var_os("BS_PASSPHRASE").is_some() {
+                warn!("BS_PASSPHRASE is ignored, give the passphrase with --passphrase-file");
+            }
+            warn!("using the built-in passphrase, anyone who can read the DIDDir can unlock its keys");
+            Ok(DEFAULT_PASSPHRASE.to_vec())
+        }
+    }
+}
+
+// the passphrase is wiped as soon as the key is derived from it, a given one
+// is used instead of the configured one
+fn derive_box_key(salt: &PwSalt, given: Option<&[u8]>) -> Result<BoxKey> {
+    let mut passwd = match given {
+        Some(passwd) => passwd.to_vec(),
+        None => passphrase()?
+    };
+    let mut box_key = BoxKey([0; KEYBYTES]);
+    let derived = {
+        let BoxKey(ref mut kb) = box_key;
+        argon2id13::derive_key(kb, &passwd, salt, OPSLIMIT_SENSITIVE, MEMLIMIT_SENSITIVE).map(|_| ())
+    };
+    sodiumoxide::utils::memzero(&mut passwd);
+    derived?;
+    Ok(box_key)
+}
+
 #[derive(Clone)]
 pub struct SignKey(pub [u8; SECRETKEYBYTES]);
 
@@ -85,9 +226,7 @@ impl convert::TryFrom<&JsonValue> for SignKey
         let sb_box = String::from(json["secrets"]["signing_key"].as_str().unwrap());
 
         // 2. use regex to extract the base64 encoded secret box
-        static SECRETKEY_REGEX: &'static str = 
-            r"(?P<data>[A-Za-z0-9-_=]+).box.xsalsa20poly1305\n*";
-        let re = Regex::new(SECRETKEY_REGEX)?;
+        let re = &*SECRETKEY_REGEX;
         if !re.is_match(&sb_box) {
             return Err(Error::InvalidEncoding("not valid sb secret box".to_string()));
         }
@@ -111,17 +250,8 @@ impl convert::TryFrom<&JsonValue> for SignKey
             sb.copy_from_slice(&nonce[(NONCEBYTES - SALTBYTES)..]);
         }
 
-        // 6. get the password from the user
-        let passwd = b"test";
-
-        // 7. derive the secret box key from the password and salt
-        let mut box_key = BoxKey([0; KEYBYTES]);
-        {
-            let BoxKey(ref mut kb) = box_key;
-            argon2id13::derive_key(kb, passwd, &salt,
-                               OPSLIMIT_SENSITIVE,
-                               MEMLIMIT_SENSITIVE)?;
-        }
+        // 6. derive the secret box key from the passphrase and salt
+        let box_key = derive_box_key(&salt, None)?;
 
         // 4. decrypt the secret box and create a SignKey from the plaintext
         let mut bb = Vec::new();
@@ -132,25 +262,6 @@ impl convert::TryFrom<&JsonValue> for SignKey
                 return Err(Error::Syntax("decryption failed".to_string()));
             }
         };
-        /*
-        {
-            println!("\nPassword: test");
-
-            let PwSalt(ref sb) = salt;
-            println!("\nSalt: {:x}", ByteBuff(sb));
-
-            let BoxKey(ref kb) = box_key;
-            println!("\nBoxKey: {:x}", ByteBuff(kb));
-
-            println!("\nSignKey: {:x}", ByteBuff(&sign_key_data));
-
-            let BoxNonce(ref nb) = nonce;
-            println!("\nNonce: {:x}", ByteBuff(nb));
-
-            println!("\nSecretBox: {:x}", ByteBuff(&box_data.as_slice()));
-        }
-        */
-
         if sign_key_data.as_slice().len() != SECRETKEYBYTES {
             return Err(Error::Syntax("not the right number of bytes for a SignKey".to_string()));
         }
@@ -164,6 +275,74 @@ impl convert::TryFrom<&JsonValue> for SignKey
     }
 }
 
+impl SignKey {
+    /// Seals the key in a secret box the way identity files store it so it
+    /// can be unlocked with `SignKey::try_from`.
+    pub fn to_secret_box(&self) -> Result<String> {
+        self.seal(None)
+    }
+
+    fn seal(&self, passwd: Option<&[u8]>) -> Result<String> {
+        // 1. create a random nonce, its tail doubles as the salt
+        let nonce = secretbox::gen_nonce();
+        let mut salt = PwSalt([0; SALTBYTES]);
+        {
+            let PwSalt(ref mut sb) = salt;
+            sb.copy_from_slice(&nonce[(NONCEBYTES - SALTBYTES)..]);
+        }
+
+        // 2. derive the secret box key from the passphrase and salt
+        let box_key = derive_box_key(&salt, passwd)?;
+
+        // 4. seal the key and put the nonce in front of the secret box
+        let mut box_data = nonce[..].to_vec();
+        box_data.extend_from_slice(&secretbox::seal(&self.0, &nonce, &box_key));
+        Ok(format!("{}.box.xsalsa20poly1305", encode_config(&box_data, URL_SAFE)))
+    }
+}
+
+impl SignKey {
+    /// Makes a sign key from the raw 64 byte Ed25519 secret key, the seed
+    /// followed by the public key.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() != SECRETKEYBYTES {
+            return Err(Error::Syntax("not the right number of bytes for a SignKey".to_string()));
+        }
+        let mut sk = SignKey([0; SECRETKEYBYTES]);
+        sk.0.copy_from_slice(bytes);
+        Ok(sk)
+    }
+
+    /// The raw secret key.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0[..]
+    }
+
+    /// The key as a private JWK, "d" is the seed and "x" the public key.
+    pub fn to_jwk(&self) -> String {
+        format!("{:#}\n", json!({
+            "kty": "OKP",
+            "crv": "Ed25519",
+            "x": encode_config(&self.0[SEEDBYTES..], URL_SAFE_NO_PAD),
+            "d": encode_config(&self.0[..SEEDBYTES], URL_SAFE_NO_PAD)
+        }))
+    }
+
+    /// Reads a private Ed25519 JWK, the public key it lists must be the one
+    /// derived from the seed.
+    pub fn from_jwk(jwk: &str) -> Result<Self> {
+        let json: JsonValue = serde_json::from_str(jwk)?;
+        let d = jwk_member(&json, "d")?;
+        let seed = Seed::from_slice(&d)
+            .ok_or_else(|| Error::Syntax("not the right number of bytes for a seed".to_string()))?;
+        let (PublicKey(pkb), SecretKey(skb)) = sign::keypair_from_seed(&seed);
+        if jwk_member(&json, "x")?[..] != pkb[..] {
+            return Err(Error::InvalidEncoding("JWK \"x\" is not the public key of \"d\"".to_string()));
+        }
+        Ok(SignKey(skb))
+    }
+}
+
 impl convert::Into<SecretKey> for SignKey {
     fn into(self) -> SecretKey {
         let mut sk = SecretKey([0; SECRETKEYBYTES]);
@@ -175,6 +354,207 @@ impl convert::Into<SecretKey> for SignKey {
     }
 }
 
+/// Parses a keyring, one `@<b64>.ed25519` pkid per line. Blank lines and
+/// lines starting with "#" are skipped, each key is returned with its line
+/// number. A keyring without keys is an error unless `allow_empty`, for
+/// lists laid out like keyrings that may well be empty.
+pub fn parse_keyring(keyring: &str, allow_empty: bool) -> Result<Vec<(usize, VerifyKey)>> {
+    let mut keys = Vec::new();
+    for (i, line) in keyring.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match verify_key_from_pkid(line) {
+            Ok(vk) => keys.push((i + 1, vk)),
+            Err(e) => return Err(Error::InvalidEncoding(format!("keyring line {}: {}", i + 1, e)))
+        }
+    }
+    if keys.is_empty() && !allow_empty {
+        return Err(Error::InvalidEncoding("the keyring has no keys".to_string()));
+    }
+    Ok(keys)
+}
+
+/// Formats a public key as a `@<b64>.ed25519` pkid.
+pub fn pkid_from_public_key(pk: &PublicKey) -> String {
+    let PublicKey(ref pkb) = *pk;
+    format!("@{}.ed25519", encode_config(pkb, URL_SAFE))
+}
+
+/// Parses a `@<b64>.ed25519` pkid into its verify key, for when the key is
+/// known and there is no DIDDir to look it up in.
+pub fn verify_key_from_pkid(pkid: &str) -> Result<VerifyKey> {
+    VerifyKey::try_from(&pkid.to_string())
+}
+
+/// Generates a new keypair and returns its pkid and the contents of an
+/// identity file holding it, sealed with the passphrase from
+/// `set_passphrase_file` or the built-in one. A throwaway signer for tests
+/// is stored in a DIDDir with `seed_identity` instead.
+pub fn generate() -> Result<(String, String)> {
+    crate::init()?;
+    let (pk, SecretKey(skb)) = sign::gen_keypair();
+    identity_file(&pk, SignKey(skb), None)
+}
+
+fn identity_file(pk: &PublicKey, sk: SignKey, passwd: Option<&[u8]>) -> Result<(String, String)> {
+    let json = json!({
+        "secrets": {
+            "signing_key": sk.seal(passwd)?
+        }
+    });
+    Ok((pkid_from_public_key(pk), serde_json::to_string_pretty(&json)?))
+}
+
+// where a DIDDir keeps its identity files, named after their pkids, and its
+// aliases, files holding a pkid. only storing identities needs to know, they
+// are read through the diddir crate everywhere else
+static IDENTITIES_DIR: &'static str = "identities";
+static ALIASES_DIR: &'static str = "aliases";
+
+// the files are only readable by the owner from the moment they are created
+// and an existing one is replaced in one step, so an alias never points at
+// nothing
+fn write_diddir_file(path: &Path, contents: &str) -> Result<()> {
+    use std::io::Write;
+
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let name = path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+    let tmp = path.with_file_name(format!(".{}.tmp", name));
+    let _ = std::fs::remove_file(&tmp);
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(&tmp)?;
+    file.write_all(contents.as_bytes())?;
+    file.sync_all()?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// Stores an identity file in the DIDDir at `kdroot`, or the default one,
+/// under its pkid and points `alias` at it if one is given, moving the alias
+/// off any identity it pointed at before. The identity is read back through
+/// the diddir crate, so a DIDDir that keeps its identities elsewhere is an
+/// error instead of an identity that can't be found later.
+pub fn store_identity(kdroot: &Option<String>, pkid: &str, identity: &str, alias: Option<&str>) -> Result<()> {
+    let root = diddir_root(kdroot)?;
+    verify_key_from_pkid(pkid)?;
+    DIDDir::open_or_init(&get_config(kdroot)?)?;
+    write_diddir_file(&root.join(IDENTITIES_DIR).join(pkid), identity)?;
+    if let Some(alias) = alias {
+        write_diddir_file(&root.join(ALIASES_DIR).join(alias), pkid)?;
+    }
+
+    // opened again so it sees what was written
+    let stored = DIDDir::open_or_init(&get_config(kdroot)?)?;
+    match stored.get_identity(&pkid.to_string()) {
+        Ok(ref read) if read == identity => {},
+        _ => {
+            return Err(Error::InvalidMeta(format!("the DIDDir {} doesn't find identities in {}/, it can't be written \
+                                                   to", root.display(), IDENTITIES_DIR)));
+        }
+    }
+    if let Some(alias) = alias {
+        if stored.get_pkid_from_alias(alias).ok().as_ref().map(String::as_str) != Some(pkid) {
+            return Err(Error::InvalidMeta(format!("the DIDDir {} doesn't find aliases in {}/, it can't be written \
+                                                   to", root.display(), ALIASES_DIR)));
+        }
+    }
+    debug!(diddir = %root.display(), pkid = %pkid, alias = ?alias, "identity stored");
+    Ok(())
+}
+
+/// The pkid an alias points at in the DIDDir at `kdroot`, or the default
+/// one, if there is such an alias.
+pub fn alias_target(kdroot: &Option<String>, alias: &str) -> Result<Option<String>> {
+    let diddir = DIDDir::open_or_init(&get_config(kdroot)?)?;
+    Ok(diddir.get_pkid_from_alias(alias).ok())
+}
+
+/// Generates an identity sealed with `password`, stores it in the DIDDir at
+/// `diddir` and returns its pkid. The DIDDir is created if it doesn't exist
+/// and restricted to the owner. The identity becomes the "default" alias
+/// unless there already is one. Unlocking it takes the same passphrase,
+/// given with `set_passphrase_file`, or the built-in one by default.
+///
+/// This is for throwaway signers in tests, needing the "test-support"
+/// feature outside of bs. It is stored with `store_identity`.
+#[cfg(any(test, feature = "test-support"))]
+pub fn seed_identity(diddir: &Path, password: &[u8]) -> Result<String> {
+    crate::init()?;
+    let kdroot = Some(diddir.to_string_lossy().into_owned());
+    let has_default = alias_target(&kdroot, "default")?.is_some();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(diddir, std::fs::Permissions::from_mode(0o700))?;
+    }
+
+    let (pk, SecretKey(skb)) = sign::gen_keypair();
+    let (pkid, identity) = identity_file(&pk, SignKey(skb), Some(password))?;
+    store_identity(&kdroot, &pkid, &identity, if has_default { None } else { Some("default") })?;
+    debug!(diddir = %diddir.display(), pkid = %pkid, "identity seeded");
+    Ok(pkid)
+}
+
+/// Generates a new keypair from a fresh 24 word BIP-39 mnemonic and returns
+/// its pkid, the contents of an identity file holding it and the mnemonic.
+/// The same identity can be made again from the mnemonic with
+/// `from_mnemonic`, so it is a paper backup of the key.
+#[cfg(feature = "mnemonic")]
+pub fn generate_mnemonic() -> Result<(String, String, String)> {
+    use bip39::{Language, Mnemonic, MnemonicType};
+
+    let mnemonic = Mnemonic::new(MnemonicType::Words24, Language::English);
+    let (pkid, identity) = from_mnemonic(mnemonic.phrase())?;
+    Ok((pkid, identity, mnemonic.phrase().to_string()))
+}
+
+/// Makes the identity of a BIP-39 mnemonic and returns its pkid and the
+/// contents of an identity file holding it. The Ed25519 seed is the first
+/// 32 bytes of the mnemonic's BIP-39 seed without a passphrase, the keys are
+/// derived from it the usual way.
+///
+/// This derivation is bs's own. It isn't SLIP-0010 or any wallet's
+/// derivation path, so other tools make a different key from the same words
+/// and only bs can recreate the identity from them.
+#[cfg(feature = "mnemonic")]
+pub fn from_mnemonic(phrase: &str) -> Result<(String, String)> {
+    crate::init()?;
+    let (pk, SecretKey(skb)) = mnemonic_keypair(phrase)?;
+    identity_file(&pk, SignKey(skb), None)
+}
+
+#[cfg(feature = "mnemonic")]
+fn mnemonic_keypair(phrase: &str) -> Result<(PublicKey, SecretKey)> {
+    use bip39::{Language, Mnemonic, Seed as Bip39Seed};
+
+    let words: Vec<&str> = phrase.split_whitespace().collect();
+    let mnemonic = Mnemonic::from_phrase(&words.join(" "), Language::English)
+        .map_err(|e| Error::InvalidEncoding(format!("not a valid BIP-39 mnemonic: {}", e)))?;
+    let bip39_seed = Bip39Seed::new(&mnemonic, "");
+    let seed = Seed::from_slice(&bip39_seed.as_bytes()[..SEEDBYTES])?;
+    Ok(sign::keypair_from_seed(&seed))
+}
+
+#[cfg(not(feature = "mnemonic"))]
+pub fn generate_mnemonic() -> Result<(String, String, String)> {
+    Err(Error::Syntax("mnemonic keys require the \"mnemonic\" feature".to_string()))
+}
+
+#[cfg(not(feature = "mnemonic"))]
+pub fn from_mnemonic(_phrase: &str) -> Result<(String, String)> {
+    Err(Error::Syntax("mnemonic keys require the \"mnemonic\" feature".to_string()))
+}
+
 pub trait Identity {
     fn pkid(&self) -> String;
     fn verify_key(&self) -> Option<VerifyKey>;
@@ -192,19 +572,6 @@ struct PrivateIdentity {
     sign_key: SignKey,
 }
 
-/*
-struct ByteBuff<'a>(&'a [u8]);
-
-impl<'a> std::fmt::LowerHex for ByteBuff<'a> {
-    fn fmt(&self, fmtr: &mut std::fmt::Formatter) -> std::result::Result<(), std::fmt::Error> {
-        for byte in self.0 {
-            fmtr.write_fmt(format_args!("{:02x}", byte)).unwrap();
-        }
-        Ok(())
-    }
-}
-*/
-
 impl Identity for PublicIdentity {
     fn pkid(&self) -> String {
         self.pkid.clone()
@@ -256,24 +623,152 @@ impl convert::TryFrom<(&String, &JsonValue)> for PrivateIdentity {
     }
 }
 
-pub fn from_pkid_or_alias(diddir: &DIDDir, pkid_or_alias: &Option<String>) -> Result<Box<Identity>> {
+// identity files are JSON but the public parts can also be written in TOML
+// or YAML when bs is built with those features, JSON is tried first
+fn parse_identity(s: &str) -> Result<JsonValue> {
+    if s.trim_start().starts_with('{') {
+        return Ok(serde_json::from_str(s)?);
+    }
+
+    #[cfg(feature = "toml")]
+    {
+        if let Ok(json) = toml::from_str::<JsonValue>(s) {
+            return Ok(json);
+        }
+    }
+
+    #[cfg(feature = "serde_yaml")]
+    {
+        if let Ok(json) = serde_yaml::from_str::<JsonValue>(s) {
+            return Ok(json);
+        }
+    }
+
+    Ok(serde_json::from_str(s)?)
+}
+
+/// The directory in the home directory that is the DIDDir unless another one
+/// is given.
+pub static DEFAULT_DIDDIR: &'static str = ".diddir";
+
+/// The root of the DIDDir given by path, or of the default one in the home
+/// directory.
+pub fn diddir_root(diddir: &Option<String>) -> Result<PathBuf> {
+    if let Some(root) = diddir {
+        return Ok(PathBuf::from(root));
+    }
+    let home = std::env::var_os("HOME").or_else(|| std::env::var_os("USERPROFILE"));
+    match home {
+        Some(home) if !home.is_empty() => Ok(PathBuf::from(home).join(DEFAULT_DIDDIR)),
+        _ => Err(Error::InvalidMeta("there is no home directory for the default DIDDir, give one with --diddir"
+                                    .to_string()))
+    }
+}
+
+// the root is always resolved here so the DIDDir that is opened is the one
+// whose permissions were checked
+pub fn get_config(diddir: &Option<String>) -> Result<Config> {
+    Ok(Config::with_path(diddir_root(diddir)?.as_path()))
+}
+
+// errors if the DIDDir is open to other users, like ssh does for ~/.ssh.
+// the identities are sealed with a passphrase but anyone who can read them
+// can still try to guess it offline. `insecure` makes it a warning
+#[cfg(unix)]
+fn check_permissions(root: &Path, insecure: bool) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    // a missing DIDDir is created by DIDDir::open_or_init
+    let mode = match std::fs::metadata(root) {
+        Ok(meta) => meta.permissions().mode() & 0o777,
+        Err(_) => return Ok(())
+    };
+    if mode & 0o077 == 0 {
+        return Ok(());
+    }
+    if insecure {
+        warn!(diddir = %root.display(), mode = %format!("{:04o}", mode), "DIDDir is open to other users");
+        return Ok(());
+    }
+    Err(Error::InvalidMeta(format!("the DIDDir {} is open to other users (mode {:04o}), restrict it with \
+                                    \"chmod 700\" or pass --insecure-permissions", root.display(), mode)))
+}
+
+#[cfg(not(unix))]
+fn check_permissions(_root: &Path, _insecure: bool) -> Result<()> {
+    Ok(())
+}
+
+/// Both halves of an identity's key, for signing. The verify key is needed
+/// up front too since the pkid a signature is recorded under comes from it.
+pub fn key_pair(identity: &Identity) -> Result<(SignKey, VerifyKey)> {
+    match (identity.sign_key(), identity.verify_key()) {
+        (Some(signk), Some(verifyk)) => Ok((signk, verifyk)),
+        (None, _) => Err(missing_key(identity, "signing")),
+        (_, None) => Err(missing_key(identity, "verify"))
+    }
+}
+
+// the same error everywhere a half of the key is needed
+fn missing_key(identity: &Identity, half: &str) -> Error {
+    Error::InvalidMeta(format!("the identity {} has no {} key", identity.pkid(), half))
+}
+
+// the pkid the argument stands for, in the order from_pkid_or_alias
+// documents. the alias lookup is passed in so the order is the same no
+// matter where the aliases are kept
+fn resolve_pkid<F: Fn(&str) -> Option<String>>(pkid_or_alias: &Option<String>, alias: F) -> Result<String> {
     // if no pkid or alias given, try using "default"
     let poa = match pkid_or_alias {
         Some(value) => value.to_owned(),
         None => "default".to_owned()
     };
 
-    // dereference the alias if one was passed in
-    let pkid = match diddir.get_pkid_from_alias(&poa) {
-        Ok(pkid) => pkid,
-        _ => poa
-    };
+    // a literal pkid always wins over an alias, otherwise dereference the alias
+    if pkid_or_alias.is_some() && verify_key_from_pkid(&poa).is_ok() {
+        return Ok(poa);
+    }
+    match alias(&poa) {
+        Some(pkid) => Ok(pkid),
+        None if pkid_or_alias.is_none() => {
+            Err(Error::InvalidMeta(
+                "no identity specified and no 'default' alias set; pass --id <pkid>, or make an identity the \
+                 'default' alias with the DIDDir tools".to_string()))
+        },
+        None => Ok(poa)
+    }
+}
+
+/// Loads an identity from the DIDDir at `kdroot`, or the default one. The
+/// argument is resolved in this order:
+///
+/// 1. nothing given, the "default" alias is used.
+/// 2. a valid `@<b64>.ed25519` pkid is used as the pkid even if there is an
+///    alias with the same name.
+/// 3. anything else is looked up as an alias and used as a pkid if there is
+///    no such alias.
+///
+/// Loading a private identity unseals its signing key, so before anything is
+/// read the DIDDir is refused if it is open to other users, the way ssh
+/// treats ~/.ssh. `insecure_permissions` makes that a warning.
+pub fn from_pkid_or_alias(kdroot: &Option<String>,
+                          pkid_or_alias: &Option<String>,
+                          insecure_permissions: bool) -> Result<Box<Identity>> {
+    let root = diddir_root(kdroot)?;
+    check_permissions(&root, insecure_permissions)?;
+    let diddir = DIDDir::open_or_init(&get_config(kdroot)?)?;
+    load_identity(&diddir, pkid_or_alias)
+}
+
+fn load_identity(diddir: &DIDDir, pkid_or_alias: &Option<String>) -> Result<Box<Identity>> {
+    let pkid = resolve_pkid(pkid_or_alias, |alias| diddir.get_pkid_from_alias(alias).ok())?;
+    debug!(pkid = %pkid, "identity selected");
 
     // get the contents of the identity JSON file
     let id_str = diddir.get_identity(&pkid)?;
 
-    // deserialize the JSON
-    let json: JsonValue = serde_json::from_str(id_str.as_str())?;
+    // deserialize the JSON, TOML or YAML
+    let json = parse_identity(id_str.as_str())?;
 
     if !json.is_object() {
         return Err(Error::InvalidEncoding("Identity file contents is not a JSON map".to_string()));
@@ -281,8 +776,450 @@ pub fn from_pkid_or_alias(diddir: &DIDDir, pkid_or_alias: &Option<String>) -> Re
 
     // check to see if we can make a public or private identity
     if !json["secrets"].is_null() && !json["secrets"]["signing_key"].is_null() {
-        Ok(Box::new(PrivateIdentity::try_from((&pkid, &json))?))
+        let identity = PrivateIdentity::try_from((&pkid, &json))?;
+        debug!(pkid = %pkid, "private identity loaded");
+        Ok(Box::new(identity))
     } else {
-        Ok(Box::new(PublicIdentity::try_from((&pkid, &json))?))
+        let identity = PublicIdentity::try_from((&pkid, &json))?;
+        debug!(pkid = %pkid, "public identity loaded");
+        Ok(Box::new(identity))
+    }
+}
+
+/// Loads a signing key from an age-encrypted file instead of the DIDDir, for
+/// keys that are already managed with age. The file may be binary or
+/// armored. It is decrypted with the identities in the age identity file if
+/// it was encrypted to recipients, or with a passphrase read from the
+/// terminal if it was encrypted with one.
+///
+/// The decrypted payload is the raw 64 byte Ed25519 secret key, the 32 byte
+/// seed followed by the 32 byte public key, with nothing around it. The
+/// public key must be the one derived from the seed.
+#[cfg(feature = "age")]
+pub fn from_age(path: &Path, age_identity: &Option<PathBuf>) -> Result<Box<Identity>> {
+    use age::armor::ArmoredReader;
+    use age::cli_common::read_secret;
+
+    let undecryptable = |e: &dyn std::fmt::Display| {
+        Error::Syntax(format!("{} could not be decrypted: {}", path.display(), e))
+    };
+    let encrypted = File::open(path)?;
+    let decryptor = age::Decryptor::new(ArmoredReader::new(encrypted)).map_err(|e| undecryptable(&e))?;
+    let mut reader = match decryptor {
+        age::Decryptor::Recipients(d) => {
+            let file = match age_identity {
+                Some(file) => file,
+                None => {
+                    return Err(Error::Syntax(format!("{} is encrypted to age recipients, give an age identity file",
+                                                     path.display())));
+                }
+            };
+            let identities = age::IdentityFile::from_file(file.to_string_lossy().into_owned())?.into_identities();
+            d.decrypt(identities.iter().map(|i| i as &dyn age::Identity)).map_err(|e| undecryptable(&e))?
+        },
+        age::Decryptor::Passphrase(d) => {
+            let passphrase = read_secret(&format!("Unlocking {}", path.display()), "age passphrase", None)
+                .map_err(|e| Error::Syntax(format!("no age passphrase: {}", e)))?;
+            d.decrypt(&passphrase, None).map_err(|e| undecryptable(&e))?
+        }
+    };
+    let mut payload = Vec::new();
+    reader.read_to_end(&mut payload)?;
+    let sign_key = SignKey::from_bytes(&payload);
+    sodiumoxide::utils::memzero(&mut payload);
+    let sign_key = sign_key?;
+
+    let seed = Seed::from_slice(&sign_key.0[..SEEDBYTES])?;
+    let (pk, _) = sign::keypair_from_seed(&seed);
+    if pk.0[..] != sign_key.0[SEEDBYTES..] {
+        return Err(Error::InvalidEncoding("the public key in the age payload is not the one of its seed".to_string()));
+    }
+    let pkid = pkid_from_public_key(&pk);
+    debug!(pkid = %pkid, "age-encrypted key loaded");
+    Ok(Box::new(PrivateIdentity {
+        pkid: pkid,
+        verify_key: VerifyKey::from_bytes(&pk.0)?,
+        sign_key: sign_key
+    }))
+}
+
+#[cfg(not(feature = "age"))]
+pub fn from_age(_path: &Path, _age_identity: &Option<PathBuf>) -> Result<Box<Identity>> {
+    Err(Error::Syntax("loading an age-encrypted key requires the \"age\" feature".to_string()))
+}
+
+/// Exports an identity's public key as an OpenSSH public key line with the
+/// pkid as the comment, for the allowed signers of `ssh-keygen -Y verify`.
+pub fn export_ssh(kdroot: &Option<String>,
+                  pkid_or_alias: &Option<String>,
+                  insecure_permissions: bool) -> Result<String> {
+    crate::init()?;
+    let identity = from_pkid_or_alias(kdroot, pkid_or_alias, insecure_permissions)?;
+    match identity.verify_key() {
+        Some(verifyk) => {
+            let pk: PublicKey = verifyk.into();
+            Ok(crate::sshsig::public_key(&pk, &identity.pkid()))
+        },
+        None => Err(missing_key(&*identity, "verify"))
+    }
+}
+
+/// Exports an identity's key as a JWK, the public key unless the private key
+/// is asked for.
+pub fn export_jwk(kdroot: &Option<String>,
+                  pkid_or_alias: &Option<String>,
+                  private: bool,
+                  insecure_permissions: bool) -> Result<String> {
+    crate::init()?;
+    let identity = from_pkid_or_alias(kdroot, pkid_or_alias, insecure_permissions)?;
+    if private {
+        let (signk, _) = key_pair(&*identity)?;
+        Ok(signk.to_jwk())
+    } else {
+        match identity.verify_key() {
+            Some(verifyk) => Ok(verifyk.to_jwk()),
+            None => Err(missing_key(&*identity, "verify"))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    fn empty_diddir(name: &str) -> DIDDir {
+        let root = testing::scratch_dir(name);
+        let config = get_config(&Some(root.to_string_lossy().into_owned())).unwrap();
+        DIDDir::open_or_init(&config).ok().expect("the DIDDir couldn't be created")
+    }
+
+    fn pkid(byte: u8) -> String {
+        pkid_from_public_key(&PublicKey([byte; PUBLICKEYBYTES]))
+    }
+
+    // aliases that shadow each other: "default" and a pkid are names too
+    fn aliases(name: &str) -> Option<String> {
+        if name == "default" {
+            Some(pkid(1))
+        } else if name == pkid(2) {
+            Some(pkid(3))
+        } else if name == "work" {
+            Some(pkid(4))
+        } else {
+            None
+        }
+    }
+
+    #[test]
+    fn nothing_given_uses_the_default_alias() {
+        assert_eq!(resolve_pkid(&None, aliases).unwrap(), pkid(1));
+    }
+
+    #[test]
+    fn a_literal_pkid_wins_over_an_alias_of_the_same_name() {
+        assert_eq!(resolve_pkid(&Some(pkid(2)), aliases).unwrap(), pkid(2));
+    }
+
+    #[test]
+    fn default_given_explicitly_is_an_alias() {
+        assert_eq!(resolve_pkid(&Some("default".to_string()), aliases).unwrap(), pkid(1));
+        assert_eq!(resolve_pkid(&Some("work".to_string()), aliases).unwrap(), pkid(4));
+    }
+
+    #[test]
+    fn an_unknown_name_is_used_as_the_pkid() {
+        // the DIDDir lookup reports that there is no such identity
+        assert_eq!(resolve_pkid(&Some("nobody".to_string()), aliases).unwrap(), "nobody");
+        // a pkid without its @ isn't a literal pkid so it is an alias first
+        let malformed = pkid(2)[1..].to_string();
+        assert_eq!(resolve_pkid(&Some(malformed.clone()), aliases).unwrap(), malformed);
+    }
+
+    #[test]
+    fn no_default_alias() {
+        match resolve_pkid(&None, |_| None) {
+            Err(Error::InvalidMeta(msg)) => {
+                assert!(msg.contains("pass --id <pkid>, or make an identity the 'default' alias"), "{}", msg)
+            },
+            other => panic!("unexpected result: {:?}", other)
+        }
+    }
+
+    #[test]
+    fn verify_keys_are_32_bytes() {
+        let bytes: Vec<u8> = (0..PUBLICKEYBYTES as u8).collect();
+        match VerifyKey::from_bytes(&bytes) {
+            Ok(vk) => assert_eq!(vk.as_bytes(), &bytes[..]),
+            Err(e) => panic!("unexpected error: {}", e)
+        }
+        for len in &[0, 1, PUBLICKEYBYTES - 1, PUBLICKEYBYTES + 1, SECRETKEYBYTES] {
+            match VerifyKey::from_bytes(&vec![7; *len]) {
+                Err(Error::Syntax(_)) => {},
+                Err(e) => panic!("unexpected error for {} bytes: {}", len, e),
+                Ok(_) => panic!("{} bytes made a verify key", len)
+            }
+        }
+    }
+
+    #[test]
+    fn sign_keys_are_64_bytes() {
+        let bytes: Vec<u8> = (0..SECRETKEYBYTES as u8).collect();
+        match SignKey::from_bytes(&bytes) {
+            Ok(sk) => assert_eq!(sk.as_bytes(), &bytes[..]),
+            Err(e) => panic!("unexpected error: {}", e)
+        }
+        // the seed on its own isn't enough
+        for len in &[0, 1, PUBLICKEYBYTES, SECRETKEYBYTES - 1, SECRETKEYBYTES + 1] {
+            match SignKey::from_bytes(&vec![7; *len]) {
+                Err(Error::Syntax(_)) => {},
+                Err(e) => panic!("unexpected error for {} bytes: {}", len, e),
+                Ok(_) => panic!("{} bytes made a sign key", len)
+            }
+        }
+    }
+
+    #[test]
+    fn a_seeded_identity_signs_and_verifies() {
+        let dir = std::fs::canonicalize(testing::scratch_dir("seeded")).unwrap();
+        testing::write(&dir.join("files").join("a"), b"signed");
+        let diddir = dir.join("diddir");
+        // sealed with the built-in passphrase so nothing global is changed
+        let pkid = seed_identity(&diddir, DEFAULT_PASSPHRASE).unwrap();
+        let kdroot = Some(diddir.to_string_lossy().into_owned());
+
+        let opts = crate::sign::SignOptions::default();
+        let signed = match crate::sign::sign(false, &None, &kdroot, &None, &opts, vec![dir.join("files")]) {
+            Ok(signed) => signed,
+            Err(e) => panic!("signing with the seeded identity failed: {}", e)
+        };
+        let manifest = dir.join("bs.json");
+        std::fs::write(&manifest, &signed.manifest).unwrap();
+        let verify_opts = crate::verify::VerifyOptions::default();
+        let report = crate::verify::verify_manifest(&kdroot, &None, &verify_opts, &manifest).unwrap();
+        assert!(report.is_valid(), "{:?}", report.check());
+        assert_eq!(report.signatures.iter().map(|s| s.signer.as_str()).collect::<Vec<_>>(), vec![pkid.as_str()]);
+    }
+
+    #[test]
+    fn seeding_again_keeps_the_default() {
+        let diddir = testing::scratch_dir("seeded-twice").join("diddir");
+        let first = seed_identity(&diddir, b"first").unwrap();
+        let second = seed_identity(&diddir, b"second").unwrap();
+        assert_ne!(first, second);
+        let config = get_config(&Some(diddir.to_string_lossy().into_owned())).unwrap();
+        let dd = DIDDir::open_or_init(&config).ok().expect("the seeded DIDDir couldn't be opened");
+        assert_eq!(dd.get_pkid_from_alias("default").ok(), Some(first));
+        assert!(dd.get_identity(&second).is_ok());
+    }
+
+    // the official BIP-39 vectors are for the passphrase "TREZOR", bs uses
+    // none so the pkids are bs's own vectors
+    #[cfg(feature = "mnemonic")]
+    static MNEMONIC_VECTORS: [(&'static str, &'static str); 3] = [
+        ("abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about",
+         "@xXheGGW3CJOK_4Fh1XMAZJZmOxqhCDTjltxWaGmixmo=.ed25519"),
+        ("legal winner thank year wave sausage worth useful legal winner thank yellow",
+         "@xvKsVZiXDHljNxTT61w017_D6S2ljHNUs3mW2aSvOrI=.ed25519"),
+        ("zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo vote",
+         "@6hx9QabXApMZT0UgarTcolfZwlL-LFN3n97yor0FzUc=.ed25519"),
+    ];
+
+    #[cfg(feature = "mnemonic")]
+    #[test]
+    fn bip39_seeds_match_the_reference_vectors() {
+        use bip39::{Language, Mnemonic, Seed as Bip39Seed};
+
+        let mnemonic = Mnemonic::from_phrase(MNEMONIC_VECTORS[0].0, Language::English).unwrap();
+        assert_eq!(crate::encoding::DigestEncoding::Hex.encode(Bip39Seed::new(&mnemonic, "TREZOR").as_bytes()),
+                   "c55257c360c07c72029aebc1b53c05ed0362ada38ead3e3e9efa3708e53495531f09a6987599d18264c1e1c92f2cf1\
+                    41630c7a3c4ab7c81b2f001698e7463b04");
+        assert_eq!(crate::encoding::DigestEncoding::Hex.encode(&Bip39Seed::new(&mnemonic, "").as_bytes()[..SEEDBYTES]),
+                   "5eb00bbddcf069084889a8ab9155568165f5c453ccb85e70811aaed6f6da5fc1");
+    }
+
+    #[cfg(feature = "mnemonic")]
+    #[test]
+    fn mnemonics_make_the_same_key_every_time() {
+        for (phrase, pkid) in MNEMONIC_VECTORS.iter() {
+            let (pk, sk) = mnemonic_keypair(phrase).unwrap();
+            assert_eq!(pkid_from_public_key(&pk), *pkid, "{}", phrase);
+            // the secret key is the seed followed by the public key
+            assert_eq!(&sk.0[SEEDBYTES..], &pk.0[..]);
+        }
+        // the words are what counts, not how they are spaced
+        let (pk, _) = mnemonic_keypair(&format!("  {}\n", MNEMONIC_VECTORS[1].0.replace(" ", "\t"))).unwrap();
+        assert_eq!(pkid_from_public_key(&pk), MNEMONIC_VECTORS[1].1);
+    }
+
+    #[cfg(feature = "mnemonic")]
+    #[test]
+    fn invalid_mnemonics_are_rejected() {
+        let bad_checksum = MNEMONIC_VECTORS[0].0.replace("about", "abandon");
+        for phrase in &[bad_checksum.as_str(), "abandon abandon abandon", "not bip39 words at all"] {
+            match mnemonic_keypair(phrase) {
+                Err(Error::InvalidEncoding(msg)) => assert!(msg.starts_with("not a valid BIP-39 mnemonic"), "{}", msg),
+                Err(e) => panic!("unexpected error for {}: {}", phrase, e),
+                Ok(_) => panic!("{} made a key", phrase)
+            }
+        }
+    }
+
+    #[test]
+    fn no_default_alias_in_an_empty_diddir() {
+        let diddir = empty_diddir("no-default");
+        match load_identity(&diddir, &None) {
+            Err(Error::InvalidMeta(msg)) => {
+                assert!(msg.contains("pass --id <pkid>, or make an identity the 'default' alias"), "{}", msg)
+            },
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => panic!("an empty DIDDir has no default identity")
+        }
+    }
+
+    #[cfg(unix)]
+    fn open_to_others(diddir: &Path) {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(diddir, std::fs::Permissions::from_mode(0o755)).unwrap();
+    }
+
+    #[cfg(unix)]
+    fn assert_refused(result: Result<Box<Identity>>, diddir: &Path) {
+        match result {
+            Err(Error::InvalidMeta(msg)) => {
+                assert!(msg.starts_with(&format!("the DIDDir {} is open to other users (mode 0755)",
+                                                 diddir.display())), "{}", msg);
+            },
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => panic!("a key was unlocked from a DIDDir open to other users")
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn diddirs_open_to_others_are_refused() {
+        let diddir = testing::scratch_dir("open-diddir");
+        seed_identity(&diddir, DEFAULT_PASSPHRASE).unwrap();
+        open_to_others(&diddir);
+        let kdroot = Some(diddir.to_string_lossy().into_owned());
+        assert_refused(from_pkid_or_alias(&kdroot, &None, false), &diddir);
+        assert!(from_pkid_or_alias(&kdroot, &None, true).is_ok());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn the_default_diddir_is_checked_too() {
+        let home = testing::scratch_dir("home");
+        let diddir = home.join(DEFAULT_DIDDIR);
+        seed_identity(&diddir, DEFAULT_PASSPHRASE).unwrap();
+        open_to_others(&diddir);
+
+        let saved = std::env::var_os("HOME");
+        std::env::set_var("HOME", &home);
+        let root = diddir_root(&None);
+        let result = from_pkid_or_alias(&None, &None, false);
+        match saved {
+            Some(saved) => std::env::set_var("HOME", saved),
+            None => std::env::remove_var("HOME")
+        }
+        assert_eq!(root, Ok(diddir.clone()));
+        assert_refused(result, &diddir);
+    }
+
+    // an identity with only some of its keys
+    struct Halves {
+        sign_key: bool,
+        verify_key: bool,
+    }
+
+    impl Identity for Halves {
+        fn pkid(&self) -> String {
+            "@halves.ed25519".to_string()
+        }
+
+        fn verify_key(&self) -> Option<VerifyKey> {
+            if self.verify_key { Some(VerifyKey([1; 32])) } else { None }
+        }
+
+        fn sign_key(&self) -> Option<SignKey> {
+            if self.sign_key { Some(SignKey([2; 64])) } else { None }
+        }
+    }
+
+    fn key_pair_error(identity: Halves) -> Error {
+        match key_pair(&identity) {
+            Err(e) => e,
+            Ok(_) => panic!("an identity without both keys was accepted")
+        }
+    }
+
+    #[test]
+    fn both_keys_are_needed_to_sign() {
+        match key_pair(&Halves { sign_key: true, verify_key: true }) {
+            Ok((signk, verifyk)) => assert_eq!((signk.0[0], verifyk.0[0]), (2, 1)),
+            Err(e) => panic!("unexpected error: {}", e)
+        }
+        assert_eq!(key_pair_error(Halves { sign_key: false, verify_key: true }),
+                   Error::InvalidMeta("the identity @halves.ed25519 has no signing key".to_string()));
+        assert_eq!(key_pair_error(Halves { sign_key: true, verify_key: false }),
+                   Error::InvalidMeta("the identity @halves.ed25519 has no verify key".to_string()));
+        assert_eq!(key_pair_error(Halves { sign_key: false, verify_key: false }),
+                   Error::InvalidMeta("the identity @halves.ed25519 has no signing key".to_string()));
+    }
+
+    #[test]
+    fn keyrings_skip_comments_and_blank_lines() {
+        let keyring = format!("# release keys\n\n  {}  \n\t\n{}\n", pkid(1), pkid(2));
+        for allow_empty in &[false, true] {
+            let keys = parse_keyring(&keyring, *allow_empty).ok().expect("the keyring didn't parse");
+            assert_eq!(keys.iter().map(|(line, VerifyKey(key))| (*line, key[0])).collect::<Vec<_>>(),
+                       vec![(3, 1), (5, 2)]);
+        }
+        match parse_keyring(&format!("{}\nnot a pkid\n", pkid(1)), true) {
+            Err(Error::InvalidEncoding(msg)) => assert!(msg.starts_with("keyring line 2: "), "{}", msg),
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => panic!("a malformed line was accepted")
+        }
+    }
+
+    #[test]
+    fn only_lists_that_allow_it_may_be_empty() {
+        assert!(parse_keyring("# nothing revoked yet\n\n", true).ok().map_or(false, |keys| keys.is_empty()));
+        match parse_keyring("# nothing revoked yet\n\n", false) {
+            Err(e) => assert_eq!(e, Error::InvalidEncoding("the keyring has no keys".to_string())),
+            Ok(_) => panic!("an empty keyring was accepted")
+        }
+    }
+
+    #[test]
+    fn pkids_parse_into_their_verify_key() {
+        for byte in &[0, 7, 255] {
+            let VerifyKey(key) = verify_key_from_pkid(&pkid(*byte)).ok().expect("a valid pkid was rejected");
+            assert_eq!(key, [*byte; PUBLICKEYBYTES]);
+        }
+        // a pkid read from a file can still have its newline
+        assert!(verify_key_from_pkid(&format!("{}\n", pkid(1))).is_ok());
+    }
+
+    #[test]
+    fn malformed_pkids_are_rejected() {
+        let valid = pkid(1);
+        let data = &valid[1..valid.len() - ".ed25519".len()];
+        let malformed = vec![
+            String::new(),
+            "@.ed25519".to_string(),
+            valid[1..].to_string(),
+            format!("@{}.ed448", data),
+            format!("@{}!ed25519", data),
+            format!("junk{}", valid),
+            format!("{}junk", valid),
+            format!("{} {}", valid, valid),
+            "@AAAA.ed25519".to_string(),
+            format!("@{}.ed25519", encode_config(&[1; PUBLICKEYBYTES + 1][..], URL_SAFE)),
+            format!("@{}.ed25519", base64::encode(&[0xfb; PUBLICKEYBYTES][..]))
+        ];
+        for pkid in &malformed {
+            assert!(verify_key_from_pkid(pkid).is_err(), "{:?} was accepted", pkid);
+        }
     }
 }
@@ -0,0 +1,152 @@
+use crate::{Error, Result};
+use crate::fs::{Algorithm, Hash, STDIN_PATH};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{debug, warn};
+
+static JOURNAL_VERSION: &'static str = "bs-journal/1";
+
+// a journaled hash and the size and modification time the file had when it
+// was hashed
+struct Entry {
+    hash: Hash,
+    size: u64,
+    modified: SystemTime,
+}
+
+/// The hashes of an interrupted run that are reused when it is resumed.
+/// Every hash is appended to the journal file as soon as it is done, so a
+/// run that is stopped at any point can pick up where it left off. A hash is
+/// only reused if the file still has the size and modification time it had
+/// when it was hashed.
+///
+/// The journal starts with a line naming the hashing options it was written
+/// with and can't be resumed with different ones. It doesn't know which
+/// files were asked for, resuming with a different set of files reuses the
+/// hashes of the files the runs have in common.
+pub struct Journal {
+    entries: HashMap<PathBuf, Entry>,
+    file: Mutex<File>,
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(s: &str) -> Option<[u8; 32]> {
+    if s.len() != 64 {
+        return None;
+    }
+    let mut bytes = [0u8; 32];
+    for (i, b) in bytes.iter_mut().enumerate() {
+        *b = u8::from_str_radix(s.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+    Some(bytes)
+}
+
+// "<algorithm>\t<digest>\t<hashed size>\t<file size>\t<secs>.<nanos>\t<path>"
+fn parse_entry(line: &str) -> Option<(PathBuf, Entry)> {
+    let mut fields = line.splitn(6, '\t');
+    let algorithm = Algorithm::from_str(fields.next()?).ok()?;
+    let digest = from_hex(fields.next()?)?;
+    let hashed_size = fields.next()?.parse().ok()?;
+    let size = fields.next()?.parse().ok()?;
+    let mut modified = fields.next()?.splitn(2, '.');
+    let secs = modified.next()?.parse().ok()?;
+    let nanos = modified.next()?.parse().ok()?;
+    let path = PathBuf::from(fields.next()?);
+    let entry = Entry {
+        hash: Hash::new(&path, &digest, hashed_size, algorithm),
+        size: size,
+        modified: UNIX_EPOCH + Duration::new(secs, nanos)
+    };
+    Some((path, entry))
+}
+
+impl Journal {
+    /// Opens the journal, reading the hashes already in it. A missing or
+    /// empty file is a new journal.
+    pub fn open(path: &Path, options: &str) -> Result<Self> {
+        let header = format!("{}\t{}", JOURNAL_VERSION, options);
+        let contents = match std::fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(From::from(e))
+        };
+
+        let mut entries = HashMap::new();
+        let mut lines = contents.split('\n');
+        match lines.next() {
+            Some("") | None => {},
+            Some(line) if line == header => {
+                // the last piece is either empty or a line cut short when the
+                // run was interrupted
+                let mut lines: Vec<&str> = lines.collect();
+                lines.pop();
+                for line in lines {
+                    match parse_entry(line) {
+                        Some((path, entry)) => { entries.insert(path, entry); },
+                        None => warn!(line = %line, "unreadable journal entry ignored")
+                    }
+                }
+            },
+            Some(_) => {
+                return Err(Error::Syntax(format!("{} was written with different options, remove it to start over",
+                                                 path.display())));
+            }
+        }
+        debug!(journal = %path.display(), hashes = entries.len(), "journal opened");
+
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        if contents.is_empty() {
+            file.write_all(format!("{}\n", header).as_bytes())?;
+        } else if !contents.ends_with('\n') {
+            // finish off the cut short line so it stays unreadable
+            file.write_all(b"\n")?;
+        }
+        Ok(Journal { entries: entries, file: Mutex::new(file) })
+    }
+
+    /// The number of hashes that can be reused.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// The journaled hash of a file that has the given size and modification
+    /// time.
+    pub fn lookup(&self, path: &PathBuf, size: u64, modified: SystemTime) -> Option<Hash> {
+        match self.entries.get(path) {
+            Some(entry) if entry.size == size && entry.modified == modified => Some(entry.hash.clone()),
+            _ => None
+        }
+    }
+
+    /// Appends a hash of a file that had the given size and modification
+    /// time when it was hashed. Stdin, symlink targets and paths that don't
+    /// fit on a line aren't journaled, failing to write only loses the hash
+    /// for a later resume so it is a warning.
+    pub fn record(&self, hash: &Hash, size: u64, modified: SystemTime) {
+        if hash.target.is_some() || hash.path == Path::new(STDIN_PATH) || self.lookup(&hash.path, size, modified).is_some() {
+            return;
+        }
+        let path = match hash.path.to_str() {
+            Some(path) if !path.contains('\n') => path,
+            _ => return
+        };
+        let since = modified.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let line = format!("{}\t{}\t{}\t{}\t{}.{}\t{}\n", hash.algorithm.name(), to_hex(&hash.hash), hash.size, size,
+                           since.as_secs(), since.subsec_nanos(), path);
+        let written = match self.file.lock() {
+            Ok(mut file) => file.write_all(line.as_bytes()),
+            Err(_) => return
+        };
+        if let Err(e) = written {
+            warn!(path = %hash.path.display(), error = %e, "hash not journaled");
+        }
+    }
+}
@@ -13,5 +13,55 @@ pub mod sign;
 pub use self::verify::*;
 pub mod verify;
 
+pub use self::diff::*;
+pub mod diff;
+
+pub use self::encoding::*;
+pub mod encoding;
+
 pub use self::fs::*;
 pub mod fs;
+
+pub use self::manifest::*;
+pub mod manifest;
+
+pub use self::report::*;
+pub mod report;
+
+pub mod journal;
+
+pub mod capabilities;
+
+pub mod merkle;
+
+pub mod minisign;
+
+pub mod reencode;
+
+pub mod rotate;
+
+#[cfg(feature = "sbom")]
+pub mod sbom;
+
+pub mod slsa;
+
+pub mod sshsig;
+
+pub mod tofu;
+
+#[cfg(feature = "remote")]
+pub mod remote;
+
+#[cfg(feature = "tsa")]
+pub mod tsa;
+
+#[cfg(test)]
+mod testing;
+
+/// Initializes libsodium. The signing, verifying and key generating entry
+/// points call it themselves, anything else using the sodiumoxide types
+/// directly must call it first. It is cheap to call more than once.
+pub fn init() -> Result<()> {
+    sodiumoxide::init()
+        .map_err(|_| Error::Syntax("failed to initialize libsodium; crypto unavailable".to_string()))
+}
@@ -0,0 +1,813 @@
+use base64::{decode_config, encode_config, URL_SAFE};
+use crate::{Error, Result};
+use crate::encoding::DigestEncoding;
+use crate::fs::{Algorithm, EntryKind, Hash, TreeEntry, STDIN_PATH};
+use serde_json::{self, Map as JsonMap, Value as JsonValue};
+use sha2::{Digest, Sha512Trunc256};
+use std::convert;
+use std::path::{Path, PathBuf, MAIN_SEPARATOR};
+use std::str::FromStr;
+use unicode_normalization::UnicodeNormalization;
+
+/// A manifest of file digests and the signatures over them. The entries are
+/// kept in the order they appear in because that order is part of the signed
+/// bytes. The default is an empty manifest without a version, the fields a
+/// manifest doesn't record are left at their defaults.
+#[derive(Clone, Default)]
+pub struct Manifest {
+    /// The format version, manifests from before versioning have none.
+    pub version: Option<String>,
+    /// The context the manifest was signed for, it is signed along with the
+    /// files and its tag is put in front of the signed bytes.
+    pub context: Option<String>,
+    pub files: Vec<(String, String)>,
+    pub signatures: Vec<(String, String)>,
+    /// An RFC 3161 timestamp token over the signature.
+    pub timestamp: Option<String>,
+    /// The directory structure, each path with its kind and permission bits,
+    /// empty unless it was recorded. It is signed along with the files.
+    pub tree: Vec<(String, String)>,
+    /// The permission bits of each file, empty unless they were recorded.
+    /// They are signed along with the files.
+    pub modes: Vec<(String, String)>,
+    /// The files that couldn't be hashed when signing and why, empty unless
+    /// they were recorded. They are signed along with the files.
+    pub errors: Vec<(String, String)>,
+    /// The empty directories, empty unless they were recorded. They are
+    /// signed along with the files.
+    pub dirs: Vec<String>,
+    /// The path patterns every file has to match, empty unless a policy was
+    /// given. It is signed along with the files.
+    pub policy: Vec<String>,
+    /// The size in bytes of each file, empty unless they were recorded. They
+    /// are signed along with the files.
+    pub sizes: Vec<(String, String)>,
+    /// A digest over all of the files, see `Manifest::files_digest`. It is
+    /// signed along with the files.
+    pub manifest_digest: Option<String>,
+    /// The names the signers go by in their DIDDirs. They are outside of the
+    /// signed bytes so they can't be trusted, they are only a hint.
+    pub signer_names: Vec<(String, String)>,
+}
+
+/// How the files are ordered in a new manifest. The order is part of the
+/// signed bytes but nothing else depends on it: verify rebuilds the signed
+/// bytes in the order the entries appear in the manifest, so any order
+/// verifies and it doesn't have to be recorded.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SortBy {
+    Path,
+    Hash,
+    Size
+}
+
+impl Default for SortBy {
+    fn default() -> Self {
+        SortBy::Path
+    }
+}
+
+impl FromStr for SortBy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "path" => Ok(SortBy::Path),
+            "hash" => Ok(SortBy::Hash),
+            "size" => Ok(SortBy::Size),
+            _ => Err(Error::InvalidMeta(format!("unknown sort order: {}", s)))
+        }
+    }
+}
+
+impl SortBy {
+    /// Sorts the hashes, ties are broken by path so the order is always the
+    /// same for the same files.
+    pub fn sort(&self, hashes: &mut Vec<Hash>) {
+        match self {
+            SortBy::Path => hashes.sort_by(|a, b| a.path.cmp(&b.path)),
+            SortBy::Hash => hashes.sort_by(|a, b| a.hash.cmp(&b.hash).then_with(|| a.path.cmp(&b.path))),
+            SortBy::Size => hashes.sort_by(|a, b| a.size.cmp(&b.size).then_with(|| a.path.cmp(&b.path)))
+        }
+    }
+}
+
+/// The manifest format version written by this version of bs.
+pub static MANIFEST_VERSION: &'static str = "bs/1";
+
+/// The domain separation tag put in front of the signed bytes of a manifest
+/// signed for a context, "bs-manifest-v1:<context>\n".
+pub static CONTEXT_TAG: &'static str = "bs-manifest-v1:";
+
+/// What the signatures are made over: the signed bytes with the context's
+/// tag in front if there is one, so a signature made for one context doesn't
+/// verify under another.
+pub fn signing_input(context: &Option<String>, signed: String) -> String {
+    match context {
+        Some(context) => format!("{}{}\n{}", CONTEXT_TAG, context, signed),
+        None => signed
+    }
+}
+
+/// Errors unless the context can go in the tag, it has to be one line.
+pub fn check_context(context: &str) -> Result<()> {
+    if context.is_empty() || context.contains(|c| c == '\n' || c == '\r') {
+        return Err(Error::Syntax("a context must be a single non-empty line".to_string()));
+    }
+    Ok(())
+}
+
+/// The form a path is recorded in, `/` is the separator on every platform
+/// so a manifest signed on Windows verifies anywhere.
+pub fn manifest_path(path: &Path) -> Result<String> {
+    let path = path.to_str()?;
+    if MAIN_SEPARATOR == '/' {
+        Ok(path.to_string())
+    } else {
+        Ok(path.replace(MAIN_SEPARATOR, "/"))
+    }
+}
+
+/// The native path of a recorded path.
+pub fn native_path(path: &str) -> PathBuf {
+    if MAIN_SEPARATOR == '/' {
+        PathBuf::from(path)
+    } else {
+        PathBuf::from(path.replace('/', &MAIN_SEPARATOR.to_string()))
+    }
+}
+
+/// Encodes a file digest as a `&<digest>.<algorithm>` sigil.
+pub fn digest_sigil(hash: &[u8], algorithm: Algorithm, encoding: DigestEncoding) -> String {
+    format!("&{}.{}", encoding.encode(hash), algorithm.name())
+}
+
+/// Decodes the digest from a digest sigil, guessing the encoding from the
+/// digest itself unless one is given.
+pub fn sigil_digest(sigil: &str, encoding: Option<DigestEncoding>) -> Result<Vec<u8>> {
+    let data = match (sigil.starts_with('&'), sigil.rfind('.')) {
+        (true, Some(i)) => &sigil[1..i],
+        _ => return Err(Error::InvalidEncoding(format!("not a valid digest sigil: {}", sigil)))
+    };
+    encoding.unwrap_or_else(|| DigestEncoding::detect(data)).decode(data)
+}
+
+/// Gets the algorithm from a digest sigil's suffix.
+pub fn sigil_algorithm(sigil: &str) -> Result<Algorithm> {
+    match sigil.rfind('.') {
+        Some(i) => Algorithm::from_str(&sigil[i + 1..]),
+        None => Err(Error::InvalidEncoding(format!("not a valid digest sigil: {}", sigil)))
+    }
+}
+
+/// Encodes a tree entry as `<kind>:<octal permission bits>`.
+pub fn tree_value(kind: EntryKind, mode: u32) -> String {
+    format!("{}:{:04o}", kind.name(), mode)
+}
+
+/// Encodes permission bits as four octal digits.
+pub fn mode_value(mode: u32) -> String {
+    format!("{:04o}", mode)
+}
+
+/// Decodes the recorded size of a file.
+pub fn parse_size_value(value: &str) -> Result<u64> {
+    value.parse().map_err(|_| Error::InvalidMeta(format!("not a valid file size: {}", value)))
+}
+
+/// Decodes the permission bits of a file.
+pub fn parse_mode_value(value: &str) -> Result<u32> {
+    u32::from_str_radix(value, 8).map_err(|_| Error::InvalidMeta(format!("not a valid file mode: {}", value)))
+}
+
+/// Decodes the kind and permission bits of a tree entry.
+pub fn parse_tree_value(value: &str) -> Result<(EntryKind, u32)> {
+    let mut parts = value.splitn(2, ':');
+    match (parts.next(), parts.next()) {
+        (Some(kind), Some(mode)) => {
+            let mode = u32::from_str_radix(mode, 8)
+                .map_err(|_| Error::InvalidMeta(format!("not a valid tree entry: {}", value)))?;
+            Ok((EntryKind::from_str(kind)?, mode))
+        },
+        _ => Err(Error::InvalidMeta(format!("not a valid tree entry: {}", value)))
+    }
+}
+
+static SYMLINK_SUFFIX: &'static str = ".symlink";
+
+/// Encodes a symlink's target as a `&<b64>.symlink` sigil.
+pub fn symlink_sigil(target: &Path) -> Result<String> {
+    Ok(format!("&{}{}", encode_config(target.to_str()?.as_bytes(), URL_SAFE), SYMLINK_SUFFIX))
+}
+
+/// Gets the symlink target from a sigil or `None` if it is a digest sigil.
+pub fn sigil_symlink(sigil: &str) -> Result<Option<PathBuf>> {
+    if !sigil.ends_with(SYMLINK_SUFFIX) {
+        return Ok(None);
+    }
+    let data = &sigil[..sigil.len() - SYMLINK_SUFFIX.len()];
+    if !data.starts_with('&') {
+        return Err(Error::InvalidEncoding(format!("not a valid symlink sigil: {}", sigil)));
+    }
+    let target = String::from_utf8(decode_config(&data[1..], URL_SAFE)?)
+        .map_err(|_| Error::InvalidEncoding(format!("symlink target is not UTF-8: {}", sigil)))?;
+    Ok(Some(PathBuf::from(target)))
+}
+
+// the manifest is built by hand so its bytes are canonical, strings are
+// escaped the way serde_json does it which leaves plain strings as they were
+fn json_string(s: &str) -> String {
+    JsonValue::String(s.to_string()).to_string()
+}
+
+fn entries_json(entries: &Vec<(String, String)>) -> String {
+    let mut json = String::new();
+    for i in 0..entries.len() {
+        let (ref k, ref v) = entries[i];
+        json.push_str(&format!("    {}: {}", json_string(k), json_string(v)));
+        if i < (entries.len() - 1) {
+            json.push_str(",\n");
+        } else {
+            json.push_str("\n");
+        }
+    }
+    json
+}
+
+fn list_json(list: &[String]) -> String {
+    let items: Vec<String> = list.iter().map(|s| format!("    {}", json_string(s))).collect();
+    format!("[\n{}\n  ]", items.join(",\n"))
+}
+
+fn entries_from(json: &JsonValue, name: &str) -> Result<Vec<(String, String)>> {
+    let map = match json[name].as_object() {
+        Some(map) => map,
+        None => {
+            return Err(Error::InvalidMeta(format!("manifest \"{}\" is not a JSON map", name)));
+        }
+    };
+
+    let mut entries = Vec::new();
+    for (k, v) in map {
+        match v.as_str() {
+            Some(v) => entries.push((k.to_owned(), v.to_owned())),
+            None => {
+                return Err(Error::InvalidMeta(format!("manifest \"{}\" entry {} is not a string", name, k)));
+            }
+        }
+    }
+    Ok(entries)
+}
+
+fn parse_json(s: &str) -> Result<JsonValue> {
+    let json: JsonValue = serde_json::from_str(s)?;
+    if !json.is_object() {
+        return Err(Error::InvalidEncoding("manifest is not a JSON map".to_string()));
+    }
+    Ok(json)
+}
+
+impl Manifest {
+    pub fn new(hashes: &[Hash], encoding: DigestEncoding) -> Result<Self> {
+        let mut files = Vec::new();
+        for hash in hashes {
+            let sigil = match hash.target {
+                Some(ref target) => symlink_sigil(target)?,
+                None => digest_sigil(&hash.hash, hash.algorithm, encoding)
+            };
+            files.push((manifest_path(&hash.path)?, sigil));
+        }
+        Ok(Manifest {
+            version: Some(MANIFEST_VERSION.to_string()),
+            files: files,
+            ..Default::default()
+        })
+    }
+
+    /// Records the directory structure, it must be sorted by path.
+    pub fn set_tree(&mut self, entries: &[TreeEntry]) -> Result<()> {
+        let mut tree = Vec::new();
+        for entry in entries {
+            tree.push((manifest_path(&entry.path)?, tree_value(entry.kind, entry.mode)));
+        }
+        self.tree = tree;
+        Ok(())
+    }
+
+    /// Records the permission bits of the files.
+    pub fn set_modes(&mut self, entries: &[TreeEntry]) -> Result<()> {
+        let mut modes = Vec::new();
+        for entry in entries {
+            modes.push((manifest_path(&entry.path)?, mode_value(entry.mode)));
+        }
+        self.modes = modes;
+        Ok(())
+    }
+
+    /// Records the sizes of the hashed files, streams and symlinks have none
+    /// to record.
+    pub fn set_sizes(&mut self, hashes: &[Hash]) -> Result<()> {
+        let mut sizes = Vec::new();
+        for hash in hashes {
+            if hash.target.is_none() && hash.path != Path::new(STDIN_PATH) {
+                sizes.push((manifest_path(&hash.path)?, hash.size.to_string()));
+            }
+        }
+        self.sizes = sizes;
+        Ok(())
+    }
+
+    /// The SHA-512/256 digest sigil of the files, one value that changes if
+    /// any path or digest does. It covers the files as a minified JSON
+    /// object in manifest order, `{"<path>":"<sigil>",...}` with the strings
+    /// escaped the same way as in the manifest, so it doesn't depend on how
+    /// the manifest is laid out. Nothing else in the manifest is covered.
+    pub fn files_digest(&self, encoding: DigestEncoding) -> String {
+        let entries: Vec<String> = self.files.iter()
+            .map(|(path, sigil)| format!("{}:{}", json_string(path), json_string(sigil)))
+            .collect();
+        let digest = Sha512Trunc256::digest(format!("{{{}}}", entries.join(",")).as_bytes());
+        digest_sigil(&digest, Algorithm::Sha512_256, encoding)
+    }
+
+    /// Records the digest over the files, it has to be set again whenever
+    /// the files change.
+    pub fn set_manifest_digest(&mut self, encoding: DigestEncoding) {
+        self.manifest_digest = Some(self.files_digest(encoding));
+    }
+
+    /// Checks the recorded digest over the files, if there is one.
+    pub fn check_manifest_digest(&self) -> Result<()> {
+        let recorded = match self.manifest_digest {
+            Some(ref recorded) => recorded,
+            None => return Ok(())
+        };
+        if sigil_algorithm(recorded)? != Algorithm::Sha512_256 {
+            return Err(Error::InvalidMeta(format!("the manifest digest is not a sha512_256 digest: {}", recorded)));
+        }
+        let expected = sigil_digest(&self.files_digest(DigestEncoding::default()), None)?;
+        if sigil_digest(recorded, None)? != expected {
+            return Err(Error::InvalidMeta("the manifest digest doesn't match the files it lists".to_string()));
+        }
+        Ok(())
+    }
+
+    /// Puts every recorded path into Unicode NFC so a path written
+    /// decomposed, the way macOS file systems hand them out, is recorded the
+    /// same as a composed one. Two paths that only differ in their
+    /// normalization are an error.
+    pub fn normalize_paths(&mut self) -> Result<()> {
+        fn nfc(path: &mut String) {
+            *path = path.nfc().collect();
+        }
+        for entries in &mut [&mut self.files, &mut self.tree, &mut self.modes, &mut self.errors, &mut self.sizes] {
+            for (path, _) in entries.iter_mut() {
+                nfc(path);
+            }
+        }
+        for dir in self.dirs.iter_mut() {
+            nfc(dir);
+        }
+        let mut seen = std::collections::HashSet::new();
+        for (path, _) in &self.files {
+            if !seen.insert(path) {
+                return Err(Error::DuplicatePath(format!("{} is in the manifest twice once normalized", path)));
+            }
+        }
+        Ok(())
+    }
+
+    /// Puts the prior manifest's files in front of these. A path in both is
+    /// an error unless it is overwritten with the new digest in place.
+    pub fn extend(&mut self, prior: &Manifest, overwrite: bool) -> Result<()> {
+        let mut files = prior.files.clone();
+        for (path, digest) in self.files.drain(..) {
+            match files.iter().position(|(p, _)| *p == path) {
+                Some(i) if overwrite => files[i].1 = digest,
+                Some(_) => {
+                    return Err(Error::DuplicatePath(format!("{} is already in the manifest being extended", path)));
+                },
+                None => files.push((path, digest))
+            }
+        }
+        self.files = files;
+        Ok(())
+    }
+
+    /// Re-encodes the file digests, the encoding they are in is guessed
+    /// unless it is given. Returns how many digests changed, the signatures
+    /// no longer verify if any did.
+    pub fn reencode(&mut self, from: Option<DigestEncoding>, to: DigestEncoding) -> Result<usize> {
+        let mut changed = 0;
+        for (_, sigil) in self.files.iter_mut() {
+            if sigil_symlink(sigil)?.is_some() {
+                continue;
+            }
+            let digest = sigil_digest(sigil, from)?;
+            if digest.len() != 32 {
+                return Err(Error::InvalidEncoding(format!("{} is not a 32 byte digest", sigil)));
+            }
+            let encoded = digest_sigil(&digest, sigil_algorithm(sigil)?, to);
+            if encoded != *sigil {
+                *sigil = encoded;
+                changed += 1;
+            }
+        }
+        if self.manifest_digest.is_some() {
+            self.set_manifest_digest(to);
+        }
+        Ok(changed)
+    }
+
+    fn files_json(&self) -> String {
+        let mut json = String::from("{\n");
+        if let Some(ref version) = self.version {
+            json.push_str(&format!("  \"version\": {},\n", json_string(version)));
+        }
+        if let Some(ref context) = self.context {
+            json.push_str(&format!("  \"context\": {},\n", json_string(context)));
+        }
+        json.push_str(&format!("  \"files\": {{\n{}  }}", entries_json(&self.files)));
+        if !self.tree.is_empty() {
+            json.push_str(&format!(",\n  \"tree\": {{\n{}  }}", entries_json(&self.tree)));
+        }
+        if !self.modes.is_empty() {
+            json.push_str(&format!(",\n  \"modes\": {{\n{}  }}", entries_json(&self.modes)));
+        }
+        if !self.errors.is_empty() {
+            json.push_str(&format!(",\n  \"errors\": {{\n{}  }}", entries_json(&self.errors)));
+        }
+        if !self.dirs.is_empty() {
+            json.push_str(&format!(",\n  \"dirs\": {}", list_json(&self.dirs)));
+        }
+        if !self.policy.is_empty() {
+            json.push_str(&format!(",\n  \"policy\": {}", list_json(&self.policy)));
+        }
+        if !self.sizes.is_empty() {
+            json.push_str(&format!(",\n  \"sizes\": {{\n{}  }}", entries_json(&self.sizes)));
+        }
+        if let Some(ref digest) = self.manifest_digest {
+            json.push_str(&format!(",\n  \"manifest_digest\": {}", json_string(digest)));
+        }
+        json
+    }
+
+    /// The bytes covered by the signatures: the manifest with only the
+    /// version, the files, the tree, the modes, the errors, the empty
+    /// directories, the policy, the sizes and the manifest digest.
+    pub fn signed_bytes(&self) -> String {
+        let mut json = self.files_json();
+        json.push_str("\n}");
+        json
+    }
+
+    fn signatures_block(&self) -> String {
+        let mut json = format!("  \"signatures\": {{\n{}  }}", entries_json(&self.signatures));
+        if let Some(ref timestamp) = self.timestamp {
+            json.push_str(&format!(",\n  \"timestamp\": {}", json_string(timestamp)));
+        }
+        if !self.signer_names.is_empty() {
+            json.push_str(&format!(",\n  \"signer_names\": {{\n{}  }}", entries_json(&self.signer_names)));
+        }
+        json
+    }
+
+    /// The full manifest with the signatures embedded.
+    pub fn to_json(&self) -> String {
+        let mut json = self.files_json();
+        json.push_str(",\n");
+        json.push_str(&self.signatures_block());
+        json.push_str("\n}");
+        json
+    }
+
+    /// The full manifest as minified JSON. The signatures are still over
+    /// the canonical `signed_bytes`, which verify rebuilds from the parsed
+    /// entries, so only the presentation differs.
+    pub fn to_compact_json(&self) -> String {
+        let entries = |entries: &Vec<(String, String)>| {
+            let map: JsonMap<String, JsonValue> = entries.iter()
+                .map(|(k, v)| (k.to_owned(), JsonValue::String(v.to_owned())))
+                .collect();
+            JsonValue::Object(map)
+        };
+        let mut json = JsonMap::new();
+        if let Some(ref version) = self.version {
+            json.insert("version".to_string(), JsonValue::String(version.to_owned()));
+        }
+        if let Some(ref context) = self.context {
+            json.insert("context".to_string(), JsonValue::String(context.to_owned()));
+        }
+        json.insert("files".to_string(), entries(&self.files));
+        if !self.tree.is_empty() {
+            json.insert("tree".to_string(), entries(&self.tree));
+        }
+        if !self.modes.is_empty() {
+            json.insert("modes".to_string(), entries(&self.modes));
+        }
+        if !self.errors.is_empty() {
+            json.insert("errors".to_string(), entries(&self.errors));
+        }
+        if !self.dirs.is_empty() {
+            json.insert("dirs".to_string(), JsonValue::Array(self.dirs.iter().cloned().map(JsonValue::String).collect()));
+        }
+        if !self.policy.is_empty() {
+            json.insert("policy".to_string(),
+                        JsonValue::Array(self.policy.iter().cloned().map(JsonValue::String).collect()));
+        }
+        if !self.sizes.is_empty() {
+            json.insert("sizes".to_string(), entries(&self.sizes));
+        }
+        if let Some(ref digest) = self.manifest_digest {
+            json.insert("manifest_digest".to_string(), JsonValue::String(digest.to_owned()));
+        }
+        json.insert("signatures".to_string(), entries(&self.signatures));
+        if let Some(ref timestamp) = self.timestamp {
+            json.insert("timestamp".to_string(), JsonValue::String(timestamp.to_owned()));
+        }
+        if !self.signer_names.is_empty() {
+            json.insert("signer_names".to_string(), entries(&self.signer_names));
+        }
+        JsonValue::Object(json).to_string()
+    }
+
+    /// Just the signatures, for writing a detached signature file.
+    pub fn signatures_json(&self) -> String {
+        format!("{{\n{}\n}}", self.signatures_block())
+    }
+}
+
+// The NDJSON form is one JSON object per line, each ending with "\n":
+//
+//     {"version":"bs/1","format":"ndjson"}
+//     {"path":<path>,"digest":<digest sigil>}        one line per file
+//     {"signatures":{<pkid>:<signature>,...}}        optional, always last
+//
+// The signatures cover the header and file lines including their newlines.
+// There is no whitespace inside the lines and the keys are always in this
+// order, so the signed bytes can be rebuilt from the parsed entries alone.
+static NDJSON_FORMAT: &'static str = "ndjson";
+
+impl Manifest {
+    /// The bytes covered by the signatures in the NDJSON form.
+    pub fn ndjson_signed_bytes(&self) -> String {
+        let mut lines = format!("{{\"version\":{},\"format\":{}}}\n",
+                                json_string(self.version.as_ref().map(String::as_str).unwrap_or(MANIFEST_VERSION)),
+                                json_string(NDJSON_FORMAT));
+        for (path, digest) in &self.files {
+            lines.push_str(&format!("{{\"path\":{},\"digest\":{}}}\n", json_string(path), json_string(digest)));
+        }
+        lines
+    }
+
+    /// The NDJSON form with the signature line, if there are signatures.
+    pub fn to_ndjson(&self) -> String {
+        let mut lines = self.ndjson_signed_bytes();
+        if !self.signatures.is_empty() {
+            let sigs: Vec<String> = self.signatures.iter()
+                .map(|(k, v)| format!("{}:{}", json_string(k), json_string(v)))
+                .collect();
+            lines.push_str(&format!("{{\"signatures\":{{{}}}}}\n", sigs.join(",")));
+        }
+        lines
+    }
+}
+
+/// Whether the manifest is in the NDJSON form, by its header line.
+pub fn is_ndjson(s: &str) -> bool {
+    let header = s.lines().next().unwrap_or("");
+    serde_json::from_str::<JsonValue>(header)
+        .map(|json| json["format"].as_str() == Some(NDJSON_FORMAT))
+        .unwrap_or(false)
+}
+
+/// Parses a manifest in the NDJSON form.
+pub fn parse_ndjson(s: &str) -> Result<Manifest> {
+    let mut lines = s.lines().filter(|line| !line.trim().is_empty());
+    let header: JsonValue = serde_json::from_str(lines.next().unwrap_or(""))?;
+    match header["version"].as_str() {
+        Some(v) if v == MANIFEST_VERSION => {},
+        Some(v) => {
+            return Err(Error::InvalidMeta(format!("unsupported manifest version {}, this bs supports {}",
+                                                  v, MANIFEST_VERSION)));
+        },
+        None => return Err(Error::InvalidMeta("NDJSON manifest header has no \"version\"".to_string()))
+    }
+
+    let mut files = Vec::new();
+    let mut signatures = Vec::new();
+    for line in lines {
+        if !signatures.is_empty() {
+            return Err(Error::InvalidMeta("NDJSON manifest has lines after the signatures".to_string()));
+        }
+        let json = parse_json(line)?;
+        if !json["signatures"].is_null() {
+            signatures = entries_from(&json, "signatures")?;
+            continue;
+        }
+        match (json["path"].as_str(), json["digest"].as_str()) {
+            (Some(path), Some(digest)) => files.push((path.to_string(), digest.to_string())),
+            _ => return Err(Error::InvalidMeta(format!("not a valid NDJSON manifest line: {}", line)))
+        }
+    }
+    Ok(Manifest {
+        version: Some(MANIFEST_VERSION.to_string()),
+        files: files,
+        signatures: signatures,
+        ..Default::default()
+    })
+}
+
+fn list_from(json: &JsonValue, name: &str) -> Result<Vec<String>> {
+    let items = match json.get(name) {
+        None => return Ok(Vec::new()),
+        Some(JsonValue::Array(items)) => items,
+        Some(_) => return Err(Error::InvalidMeta(format!("manifest \"{}\" is not a list", name)))
+    };
+    let mut out = Vec::new();
+    for item in items {
+        match item.as_str() {
+            Some(item) => out.push(item.to_string()),
+            None => return Err(Error::InvalidMeta(format!("manifest \"{}\" has an entry that is not a string", name)))
+        }
+    }
+    Ok(out)
+}
+
+fn timestamp_from(json: &JsonValue) -> Option<String> {
+    json["timestamp"].as_str().map(String::from)
+}
+
+fn signer_names_from(json: &JsonValue) -> Result<Vec<(String, String)>> {
+    if json["signer_names"].is_null() {
+        Ok(Vec::new())
+    } else {
+        entries_from(json, "signer_names")
+    }
+}
+
+/// Parses the signatures, timestamp and signer names out of a detached
+/// signature file.
+pub fn detached_signatures(s: &str) -> Result<(Vec<(String, String)>, Option<String>, Vec<(String, String)>)> {
+    let json = parse_json(s)?;
+    Ok((entries_from(&json, "signatures")?, timestamp_from(&json), signer_names_from(&json)?))
+}
+
+impl convert::TryFrom<&str> for Manifest {
+    type Error = Error;
+
+    fn try_from(s: &str) -> Result<Self> {
+        let json = parse_json(s)?;
+        let version = match json.get("version") {
+            None => None,
+            Some(JsonValue::String(v)) if v == MANIFEST_VERSION => Some(v.to_owned()),
+            Some(JsonValue::String(v)) => {
+                return Err(Error::InvalidMeta(format!("unsupported manifest version {}, this bs supports {}",
+                                                      v, MANIFEST_VERSION)));
+            },
+            Some(_) => {
+                return Err(Error::InvalidMeta("manifest \"version\" is not a string".to_string()));
+            }
+        };
+        let files = entries_from(&json, "files")?;
+        let signatures = if json["signatures"].is_null() {
+            Vec::new()
+        } else {
+            entries_from(&json, "signatures")?
+        };
+        let context = match json.get("context") {
+            None => None,
+            Some(JsonValue::String(c)) => Some(c.to_owned()),
+            Some(_) => return Err(Error::InvalidMeta("manifest \"context\" is not a string".to_string()))
+        };
+        Ok(Manifest {
+            version: version,
+            context: context,
+            files: files,
+            signatures: signatures,
+            timestamp: timestamp_from(&json),
+            tree: if json["tree"].is_null() { Vec::new() } else { entries_from(&json, "tree")? },
+            modes: if json["modes"].is_null() { Vec::new() } else { entries_from(&json, "modes")? },
+            errors: if json["errors"].is_null() { Vec::new() } else { entries_from(&json, "errors")? },
+            dirs: list_from(&json, "dirs")?,
+            policy: list_from(&json, "policy")?,
+            sizes: if json["sizes"].is_null() { Vec::new() } else { entries_from(&json, "sizes")? },
+            manifest_digest: match json.get("manifest_digest") {
+                None => None,
+                Some(JsonValue::String(digest)) => Some(digest.to_owned()),
+                Some(_) => return Err(Error::InvalidMeta("manifest \"manifest_digest\" is not a string".to_string()))
+            },
+            signer_names: signer_names_from(&json)?
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+
+    static AWKWARD_PATHS: [&'static str; 6] = [
+        "say \"hi\".txt",
+        "back\\slash\\",
+        "new\nline",
+        "tab\tand\rreturn",
+        "nul\u{0}and\u{1f}unit separator",
+        "ünïcödé/\u{2028}line separator"
+    ];
+
+    fn awkward_manifest() -> Manifest {
+        let mut m = Manifest::new(&[], DigestEncoding::default()).unwrap();
+        let sigil = digest_sigil(&[7; 32], Algorithm::Blake3, DigestEncoding::default());
+        m.files = AWKWARD_PATHS.iter().map(|path| (path.to_string(), sigil.to_owned())).collect();
+        m.dirs = vec![AWKWARD_PATHS[1].to_string()];
+        m.errors = vec![(AWKWARD_PATHS[0].to_string(), "a \"quoted\"\nreason".to_string())];
+        m
+    }
+
+    fn file_paths(m: &Manifest) -> Vec<&str> {
+        m.files.iter().map(|(path, _)| path.as_str()).collect()
+    }
+
+    fn assert_same_paths(parsed: &Manifest) {
+        assert_eq!(file_paths(parsed), AWKWARD_PATHS.to_vec());
+        assert_eq!(parsed.dirs, vec![AWKWARD_PATHS[1].to_string()]);
+        assert_eq!(parsed.errors[0].1, "a \"quoted\"\nreason");
+    }
+
+    #[test]
+    fn recorded_paths_are_slash_separated() {
+        assert_eq!(manifest_path(Path::new("a/b/c")), Ok("a/b/c".to_string()));
+        assert_eq!(manifest_path(&native_path("a/b/c")), Ok("a/b/c".to_string()));
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn windows_paths_are_recorded_with_slashes() {
+        assert_eq!(manifest_path(Path::new(r"a\b")), Ok("a/b".to_string()));
+        assert_eq!(manifest_path(Path::new(r"dir\sub\file.txt")), Ok("dir/sub/file.txt".to_string()));
+    }
+
+    #[test]
+    fn recorded_paths_are_native_paths_again() {
+        assert_eq!(native_path("a/b"), PathBuf::from(format!("a{}b", MAIN_SEPARATOR)));
+        assert_eq!(native_path("a/b"), Path::new("a").join("b"));
+        assert_eq!(native_path("file"), PathBuf::from("file"));
+    }
+
+    #[test]
+    fn awkward_paths_round_trip() {
+        let m = awkward_manifest();
+        for json in &[m.to_json(), m.to_compact_json()] {
+            // the whole manifest is still one valid JSON object
+            assert!(serde_json::from_str::<JsonValue>(json).is_ok(), "{}", json);
+            assert_same_paths(&Manifest::try_from(json.as_str()).unwrap());
+        }
+        // the NDJSON form only has the files
+        assert_eq!(file_paths(&parse_ndjson(&m.to_ndjson()).unwrap()), AWKWARD_PATHS.to_vec());
+    }
+
+    #[test]
+    fn awkward_paths_are_escaped() {
+        let json = awkward_manifest().to_json();
+        assert!(json.contains(r#""say \"hi\".txt""#));
+        assert!(json.contains(r#""back\\slash\\""#));
+        assert!(json.contains(r#""new\nline""#));
+        assert!(json.contains(r#"\u0000"#));
+        // the control characters never appear raw, only the layout's newlines
+        assert!(!json.chars().any(|c| c.is_control() && c != '\n'));
+    }
+
+    static COMPOSED: &'static str = "caf\u{e9}/na\u{ef}ve";
+    static DECOMPOSED: &'static str = "cafe\u{301}/nai\u{308}ve";
+
+    #[test]
+    fn paths_are_normalized_to_nfc() {
+        let mut m = Manifest::new(&[], DigestEncoding::default()).unwrap();
+        let sigil = digest_sigil(&[7; 32], Algorithm::Sha512_256, DigestEncoding::default());
+        m.files = vec![(DECOMPOSED.to_string(), sigil.to_owned()), ("plain".to_string(), sigil.to_owned())];
+        m.dirs = vec!["cafe\u{301}".to_string()];
+        m.errors = vec![("e\u{301}".to_string(), "unreadable".to_string())];
+        let before = m.signed_bytes();
+        m.normalize_paths().unwrap();
+        assert_eq!(file_paths(&m), vec![COMPOSED, "plain"]);
+        assert_eq!(m.dirs, vec!["caf\u{e9}".to_string()]);
+        assert_eq!(m.errors[0].0, "\u{e9}");
+        // the signed bytes have the normalized paths
+        assert_ne!(m.signed_bytes(), before);
+
+        // already composed paths stay as they are
+        let normalized = m.signed_bytes();
+        m.normalize_paths().unwrap();
+        assert_eq!(m.signed_bytes(), normalized);
+    }
+
+    #[test]
+    fn paths_that_only_differ_in_normalization_are_duplicates() {
+        let mut m = Manifest::new(&[], DigestEncoding::default()).unwrap();
+        let sigil = digest_sigil(&[7; 32], Algorithm::Sha512_256, DigestEncoding::default());
+        m.files = vec![(COMPOSED.to_string(), sigil.to_owned()), (DECOMPOSED.to_string(), sigil.to_owned())];
+        assert_eq!(m.normalize_paths(),
+                   Err(Error::DuplicatePath(format!("{} is in the manifest twice once normalized", COMPOSED))));
+    }
+}
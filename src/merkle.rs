@@ -0,0 +1,239 @@
+use base64::{decode_config, encode_config, URL_SAFE};
+use crate::{Error, Result};
+use crate::encoding::DigestEncoding;
+use crate::fs::{Algorithm, Hash};
+use crate::manifest::{self, MANIFEST_VERSION};
+use serde_json::{self, json, Value as JsonValue};
+use sha2::{Digest, Sha512Trunc256};
+
+// the leaf and node prefixes keep a leaf from being passed off as a node,
+// the tree is the one from RFC 6962 with SHA-512/256
+const LEAF_PREFIX: u8 = 0;
+const NODE_PREFIX: u8 = 1;
+
+pub type Node = [u8; 32];
+
+fn node_from(digest: &[u8]) -> Node {
+    let mut node = [0u8; 32];
+    node.copy_from_slice(digest);
+    node
+}
+
+/// The leaf for a file binds its path, digest algorithm and digest.
+pub fn leaf_hash(path: &str, algorithm: Algorithm, digest: &[u8]) -> Node {
+    let mut h = Sha512Trunc256::new();
+    h.input(&[LEAF_PREFIX]);
+    h.input(&(path.len() as u64).to_be_bytes());
+    h.input(path.as_bytes());
+    h.input(&(algorithm.name().len() as u64).to_be_bytes());
+    h.input(algorithm.name().as_bytes());
+    h.input(digest);
+    node_from(h.result().as_slice())
+}
+
+fn node_hash(left: &Node, right: &Node) -> Node {
+    let mut h = Sha512Trunc256::new();
+    h.input(&[NODE_PREFIX]);
+    h.input(left);
+    h.input(right);
+    node_from(h.result().as_slice())
+}
+
+// the largest power of two smaller than n
+fn split(n: usize) -> usize {
+    let mut k = 1;
+    while k << 1 < n {
+        k <<= 1;
+    }
+    k
+}
+
+/// The root of the tree over the leaves, there must be at least one.
+pub fn root(leaves: &[Node]) -> Node {
+    if leaves.len() == 1 {
+        return leaves[0];
+    }
+    let k = split(leaves.len());
+    node_hash(&root(&leaves[..k]), &root(&leaves[k..]))
+}
+
+/// The inclusion proof for the leaf at the index, from the leaf up.
+pub fn inclusion_proof(leaves: &[Node], index: usize) -> Vec<Node> {
+    if leaves.len() <= 1 {
+        return Vec::new();
+    }
+    let k = split(leaves.len());
+    if index < k {
+        let mut proof = inclusion_proof(&leaves[..k], index);
+        proof.push(root(&leaves[k..]));
+        proof
+    } else {
+        let mut proof = inclusion_proof(&leaves[k..], index - k);
+        proof.push(root(&leaves[..k]));
+        proof
+    }
+}
+
+/// Checks an inclusion proof the way RFC 9162 section 2.1.3.2 describes.
+pub fn verify_inclusion(leaf: &Node, index: usize, size: usize, proof: &[Node], root: &Node) -> bool {
+    if index >= size {
+        return false;
+    }
+    let mut fnode = index;
+    let mut snode = size - 1;
+    let mut r = *leaf;
+    for p in proof {
+        if snode == 0 {
+            return false;
+        }
+        if fnode & 1 == 1 || fnode == snode {
+            r = node_hash(p, &r);
+            while fnode & 1 == 0 && fnode != 0 {
+                fnode >>= 1;
+                snode >>= 1;
+            }
+        } else {
+            r = node_hash(&r, p);
+        }
+        fnode >>= 1;
+        snode >>= 1;
+    }
+    snode == 0 && r == *root
+}
+
+/// The proof that one file is in the tree.
+#[derive(Clone)]
+pub struct Proof {
+    pub path: String,
+    pub digest: String,
+    pub index: usize,
+    pub siblings: Vec<Node>,
+}
+
+/// A tree over the files sorted by path so the same files always give the
+/// same root.
+pub struct Tree {
+    pub root: Node,
+    pub size: usize,
+    pub proofs: Vec<Proof>,
+}
+
+impl Tree {
+    pub fn new(hashes: &[Hash]) -> Result<Self> {
+        if hashes.is_empty() {
+            return Err(Error::Syntax("there are no files to build a Merkle tree over".to_string()));
+        }
+        let mut sorted: Vec<&Hash> = hashes.iter().collect();
+        sorted.sort_by(|a, b| a.path.cmp(&b.path));
+
+        let mut leaves = Vec::new();
+        for hash in &sorted {
+            if hash.target.is_some() {
+                return Err(Error::Syntax("a Merkle tree can't record symlink targets".to_string()));
+            }
+            leaves.push(leaf_hash(&manifest::manifest_path(&hash.path)?, hash.algorithm, &hash.hash));
+        }
+
+        let mut proofs = Vec::new();
+        for (i, hash) in sorted.iter().enumerate() {
+            proofs.push(Proof {
+                path: manifest::manifest_path(&hash.path)?,
+                digest: manifest::digest_sigil(&hash.hash, hash.algorithm, DigestEncoding::Base64Url),
+                index: i,
+                siblings: inclusion_proof(&leaves, i)
+            });
+        }
+        Ok(Tree { root: root(&leaves), size: leaves.len(), proofs: proofs })
+    }
+}
+
+/// The bytes covered by the signatures on a root document.
+pub fn signed_bytes(root: &Node, size: usize) -> String {
+    format!("{{\n  \"version\": \"{}\",\n  \"merkle\": {{\n    \"root\": \"{}\",\n    \"size\": {}\n  }}\n}}",
+            MANIFEST_VERSION, encode_config(root, URL_SAFE), size)
+}
+
+/// The root document with the signatures over it.
+pub fn root_document(root: &Node, size: usize, signatures: &[(String, String)]) -> String {
+    let signed = signed_bytes(root, size);
+    let mut doc = signed[..signed.len() - 2].to_string();
+    doc.push_str(",\n  \"signatures\": {\n");
+    let entries: Vec<String> = signatures.iter()
+        .map(|(k, v)| format!("    {}: {}", JsonValue::String(k.to_owned()), JsonValue::String(v.to_owned())))
+        .collect();
+    doc.push_str(&entries.join(",\n"));
+    doc.push_str("\n  }\n}");
+    doc
+}
+
+/// Parses a root document into its root, size and signatures.
+pub fn parse_root_document(s: &str) -> Result<(Node, usize, Vec<(String, String)>)> {
+    let json: JsonValue = serde_json::from_str(s)?;
+    if json["version"].as_str() != Some(MANIFEST_VERSION) {
+        return Err(Error::InvalidMeta("unsupported Merkle root document version".to_string()));
+    }
+    let root = match json["merkle"]["root"].as_str() {
+        Some(root) => decode_config(root, URL_SAFE)?,
+        None => return Err(Error::InvalidMeta("the Merkle root document has no root".to_string()))
+    };
+    if root.len() != 32 {
+        return Err(Error::InvalidEncoding("not the right number of bytes for a Merkle root".to_string()));
+    }
+    let size = match json["merkle"]["size"].as_u64() {
+        Some(size) => size as usize,
+        None => return Err(Error::InvalidMeta("the Merkle root document has no size".to_string()))
+    };
+    let mut signatures = Vec::new();
+    if let Some(map) = json["signatures"].as_object() {
+        for (k, v) in map {
+            match v.as_str() {
+                Some(v) => signatures.push((k.to_owned(), v.to_owned())),
+                None => return Err(Error::InvalidMeta(format!("signature from {} is not a string", k)))
+            }
+        }
+    }
+    Ok((node_from(&root), size, signatures))
+}
+
+pub fn proofs_json(proofs: &[Proof]) -> String {
+    let proofs: Vec<JsonValue> = proofs.iter().map(|p| json!({
+        "path": p.path,
+        "digest": p.digest,
+        "index": p.index,
+        "siblings": p.siblings.iter().map(|s| encode_config(s, URL_SAFE)).collect::<Vec<String>>()
+    })).collect();
+    format!("{:#}\n", json!({ "proofs": proofs }))
+}
+
+pub fn parse_proofs(s: &str) -> Result<Vec<Proof>> {
+    let json: JsonValue = serde_json::from_str(s)?;
+    let entries = match json["proofs"].as_array() {
+        Some(entries) => entries,
+        None => return Err(Error::InvalidMeta("the proofs file has no \"proofs\" list".to_string()))
+    };
+    let mut proofs = Vec::new();
+    for entry in entries {
+        let (path, digest, index) = match (entry["path"].as_str(), entry["digest"].as_str(), entry["index"].as_u64()) {
+            (Some(path), Some(digest), Some(index)) => (path, digest, index),
+            _ => return Err(Error::InvalidMeta("a proof needs a path, digest and index".to_string()))
+        };
+        let mut siblings = Vec::new();
+        for sibling in entry["siblings"].as_array().map(|s| s.as_slice()).unwrap_or(&[]) {
+            let node = decode_config(sibling.as_str()?, URL_SAFE)?;
+            if node.len() != 32 {
+                return Err(Error::InvalidEncoding("not the right number of bytes for a Merkle node".to_string()));
+            }
+            siblings.push(node_from(&node));
+        }
+        proofs.push(Proof { path: path.to_string(), digest: digest.to_string(), index: index as usize, siblings: siblings });
+    }
+    Ok(proofs)
+}
+
+/// Checks that the proof's file and digest are in the tree with the root.
+pub fn check_proof(proof: &Proof, root: &Node, size: usize) -> Result<bool> {
+    let algorithm = manifest::sigil_algorithm(&proof.digest)?;
+    let digest = manifest::sigil_digest(&proof.digest, None)?;
+    let leaf = leaf_hash(&proof.path, algorithm, &digest);
+    Ok(verify_inclusion(&leaf, proof.index, size, &proof.siblings, root))
+}
@@ -0,0 +1,164 @@
+use base64::{decode, encode};
+use crate::{Error, Result};
+use sha2::{Digest, Sha512Trunc256};
+use sodiumoxide::crypto::generichash;
+use sodiumoxide::crypto::sign::{self, PublicKey, SecretKey, Signature, PUBLICKEYBYTES, SIGNATUREBYTES};
+
+// "ED" signatures are over the BLAKE2b-512 hash of the message, "Ed" is the
+// legacy algorithm that signs the message directly
+static PREHASHED: &'static [u8] = b"ED";
+static LEGACY: &'static [u8] = b"Ed";
+static KEY_ALGORITHM: &'static [u8] = b"Ed";
+
+static UNTRUSTED_PREFIX: &'static str = "untrusted comment: ";
+static TRUSTED_PREFIX: &'static str = "trusted comment: ";
+
+const KEYIDBYTES: usize = 8;
+
+fn invalid(what: &str) -> Error {
+    Error::InvalidEncoding(format!("not a valid minisign {}", what))
+}
+
+fn blake2b_512(msg: &[u8]) -> Result<Vec<u8>> {
+    let mut state = generichash::State::new(64, None)?;
+    state.update(msg)?;
+    Ok(state.finalize()?.as_ref().to_vec())
+}
+
+/// minisign key ids are random, for bs keys they are the first 8 bytes of the
+/// SHA-512/256 digest of the public key so they are stable.
+pub fn key_id(pk: &PublicKey) -> [u8; KEYIDBYTES] {
+    let PublicKey(ref pkb) = *pk;
+    let digest = Sha512Trunc256::digest(pkb);
+    let mut id = [0u8; KEYIDBYTES];
+    id.copy_from_slice(&digest[..KEYIDBYTES]);
+    id
+}
+
+// minisign prints key ids as a little endian hex number
+fn key_id_hex(id: &[u8]) -> String {
+    id.iter().rev().map(|b| format!("{:02X}", b)).collect()
+}
+
+/// The contents of a minisign public key file for the key.
+pub fn public_key(pk: &PublicKey) -> String {
+    let id = key_id(pk);
+    let PublicKey(ref pkb) = *pk;
+    let mut data = KEY_ALGORITHM.to_vec();
+    data.extend_from_slice(&id);
+    data.extend_from_slice(pkb);
+    format!("{}minisign public key {}\n{}\n", UNTRUSTED_PREFIX, key_id_hex(&id), encode(&data))
+}
+
+/// Creates a prehashed minisign signature over the message.
+pub fn sign(msg: &[u8], sk: &SecretKey, pk: &PublicKey, trusted_comment: &str) -> Result<String> {
+    let Signature(sig) = sign::sign_detached(&blake2b_512(msg)?, sk);
+
+    let mut data = PREHASHED.to_vec();
+    data.extend_from_slice(&key_id(pk));
+    data.extend_from_slice(&sig);
+
+    // the global signature covers the signature and the trusted comment
+    let mut global = sig.to_vec();
+    global.extend_from_slice(trusted_comment.as_bytes());
+    let Signature(global_sig) = sign::sign_detached(&global, sk);
+
+    Ok(format!("{}signature from bs secret key\n{}\n{}{}\n{}\n",
+               UNTRUSTED_PREFIX, encode(&data),
+               TRUSTED_PREFIX, trusted_comment, encode(&global_sig.to_vec())))
+}
+
+fn parse_public_key(public_key: &str) -> Result<([u8; KEYIDBYTES], PublicKey)> {
+    // the key is the first line that isn't a comment
+    let line = public_key.lines()
+        .map(str::trim)
+        .find(|l| !l.is_empty() && !l.starts_with(UNTRUSTED_PREFIX));
+    let data = decode(line.ok_or_else(|| invalid("public key"))?)?;
+    if data.len() != 2 + KEYIDBYTES + PUBLICKEYBYTES || &data[..2] != KEY_ALGORITHM {
+        return Err(invalid("public key"));
+    }
+    let mut id = [0u8; KEYIDBYTES];
+    id.copy_from_slice(&data[2..2 + KEYIDBYTES]);
+    let pk = PublicKey::from_slice(&data[2 + KEYIDBYTES..]).ok_or_else(|| invalid("public key"))?;
+    Ok((id, pk))
+}
+
+/// Verifies a minisign signature over the message with the minisign public
+/// key and returns the trusted comment.
+pub fn verify(msg: &[u8], minisig: &str, public_key: &str) -> Result<String> {
+    let (id, pk) = parse_public_key(public_key)?;
+
+    let lines: Vec<&str> = minisig.lines().collect();
+    if lines.len() < 4 || !lines[0].starts_with(UNTRUSTED_PREFIX) || !lines[2].starts_with(TRUSTED_PREFIX) {
+        return Err(invalid("signature"));
+    }
+    let data = decode(lines[1].trim())?;
+    if data.len() != 2 + KEYIDBYTES + SIGNATUREBYTES {
+        return Err(invalid("signature"));
+    }
+    if &data[2..2 + KEYIDBYTES] != &id[..] {
+        return Err(Error::SignatureInvalid(format!("signature is from key {} not {}",
+                                                   key_id_hex(&data[2..2 + KEYIDBYTES]),
+                                                   key_id_hex(&id))));
+    }
+    let sig_bytes = &data[2 + KEYIDBYTES..];
+    let sig = Signature::from_slice(sig_bytes).ok_or_else(|| invalid("signature"))?;
+
+    // check the signature over the message
+    let signed = match &data[..2] {
+        alg if alg == PREHASHED => blake2b_512(msg)?,
+        alg if alg == LEGACY => msg.to_vec(),
+        _ => return Err(invalid("signature algorithm"))
+    };
+    if !sign::verify_detached(&sig, &signed, &pk) {
+        return Err(Error::SignatureInvalid("invalid minisign signature".to_string()));
+    }
+
+    // check the global signature over the trusted comment
+    let trusted_comment = &lines[2][TRUSTED_PREFIX.len()..];
+    let global_sig = Signature::from_slice(&decode(lines[3].trim())?).ok_or_else(|| invalid("signature"))?;
+    let mut global = sig_bytes.to_vec();
+    global.extend_from_slice(trusted_comment.as_bytes());
+    if !sign::verify_detached(&global_sig, &global, &pk) {
+        return Err(Error::SignatureInvalid("invalid minisign trusted comment signature".to_string()));
+    }
+
+    Ok(trusted_comment.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static MANIFEST: &'static [u8] = b"{\"files\": []}\n";
+    static COMMENT: &'static str = "timestamp:1600000000\tsigner:@test.ed25519";
+
+    #[test]
+    fn signatures_round_trip() {
+        let (pk, sk) = sign::gen_keypair();
+        let minisig = sign(MANIFEST, &sk, &pk, COMMENT).unwrap();
+        assert_eq!(verify(MANIFEST, &minisig, &public_key(&pk)), Ok(COMMENT.to_string()));
+        assert_eq!(verify(b"{\"files\": [1]}\n", &minisig, &public_key(&pk)),
+                   Err(Error::SignatureInvalid("invalid minisign signature".to_string())));
+    }
+
+    #[test]
+    fn signatures_from_another_key_are_rejected() {
+        let (pk, sk) = sign::gen_keypair();
+        let (other, _) = sign::gen_keypair();
+        let minisig = sign(MANIFEST, &sk, &pk, COMMENT).unwrap();
+        assert_eq!(verify(MANIFEST, &minisig, &public_key(&other)),
+                   Err(Error::SignatureInvalid(format!("signature is from key {} not {}",
+                                                       key_id_hex(&key_id(&pk)), key_id_hex(&key_id(&other))))));
+    }
+
+    #[test]
+    fn tampered_trusted_comments_are_rejected() {
+        let (pk, sk) = sign::gen_keypair();
+        let minisig = sign(MANIFEST, &sk, &pk, COMMENT).unwrap();
+        let tampered = minisig.replace("timestamp:1600000000", "timestamp:1700000000");
+        assert_ne!(tampered, minisig);
+        assert_eq!(verify(MANIFEST, &tampered, &public_key(&pk)),
+                   Err(Error::SignatureInvalid("invalid minisign trusted comment signature".to_string())));
+    }
+}
@@ -0,0 +1,63 @@
+use base64::{encode_config, URL_SAFE};
+use crate::{Error, Result};
+use crate::encoding::DigestEncoding;
+use crate::identity;
+use crate::manifest::{self, Manifest};
+use sodiumoxide::crypto::sign::{self, SecretKey, Signature};
+use std::convert::TryFrom;
+use tracing::info;
+
+/// A manifest with its digests in another encoding.
+pub struct Reencoded {
+    pub manifest: String,
+    /// The number of digests that changed.
+    pub changed: usize,
+    /// Whether the manifest kept signatures that no longer verify.
+    pub stale_signatures: bool,
+}
+
+/// Re-encodes the digests of a manifest without hashing the files again.
+/// The encoding is part of the signed bytes so the old signatures no longer
+/// verify: the manifest is signed again by the given identity, or with
+/// `keep_signatures` the old ones are left in place anyway.
+pub fn reencode(kdroot: &Option<String>,
+                pkid_or_alias: &Option<String>,
+                contents: &str,
+                from: Option<DigestEncoding>,
+                to: DigestEncoding,
+                keep_signatures: bool,
+                insecure_permissions: bool) -> Result<Reencoded> {
+
+    crate::init()?;
+    if pkid_or_alias.is_some() == keep_signatures {
+        return Err(Error::Syntax("re-encoding invalidates the signatures, give either an identity to sign it again \
+                                  or keep the signatures".to_string()));
+    }
+
+    let ndjson = manifest::is_ndjson(contents);
+    let mut m = if ndjson { manifest::parse_ndjson(contents)? } else { Manifest::try_from(contents)? };
+    let changed = m.reencode(from, to)?;
+    info!(changed = changed, files = m.files.len(), "digests re-encoded");
+
+    // nothing changed so the signatures still verify
+    let stale = changed > 0 && !m.signatures.is_empty();
+    if changed > 0 && !keep_signatures {
+        let identity = identity::from_pkid_or_alias(kdroot, pkid_or_alias, insecure_permissions)?;
+        let (signk, _) = identity::key_pair(&*identity)?;
+        let sk: SecretKey = signk.into();
+
+        // the timestamp and names go with the old signatures
+        let signed = if ndjson { m.ndjson_signed_bytes() } else { m.signed_bytes() };
+        let Signature(raw_sig) = sign::sign_detached(manifest::signing_input(&m.context, signed).as_bytes(), &sk);
+        m.signatures = vec![(identity.pkid(), format!("{}.sig.ed25519", encode_config(&raw_sig.to_vec(), URL_SAFE)))];
+        m.timestamp = None;
+        m.signer_names = Vec::new();
+        info!(pkid = %identity.pkid(), "manifest signed again");
+    }
+
+    Ok(Reencoded {
+        manifest: if ndjson { m.to_ndjson() } else { m.to_json() },
+        changed: changed,
+        stale_signatures: stale && keep_signatures
+    })
+}
@@ -0,0 +1,45 @@
+use crate::{Error, Result};
+use reqwest::header::CONTENT_TYPE;
+use std::io::Read;
+
+/// The largest manifest that will be downloaded.
+pub const MAX_MANIFEST_SIZE: u64 = 64 * 1024 * 1024;
+
+fn is_json_content_type(content_type: &str) -> bool {
+    let mime = content_type.split(';').next().unwrap_or("").trim();
+    mime == "application/json" || mime == "text/plain" || mime.ends_with("+json")
+}
+
+/// Downloads a manifest over HTTPS, checking that it is JSON and no larger
+/// than `MAX_MANIFEST_SIZE`.
+pub fn fetch(url: &str) -> Result<String> {
+    if !url.starts_with("https://") {
+        return Err(Error::IoError(format!("remote manifests must use https: {}", url)));
+    }
+
+    let mut resp = reqwest::get(url)?;
+    if !resp.status().is_success() {
+        return Err(Error::IoError(format!("fetching {} returned {}", url, resp.status())));
+    }
+
+    let content_type = resp.headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("")
+        .to_string();
+    if !is_json_content_type(&content_type) {
+        return Err(Error::InvalidEncoding(format!("{} is not JSON ({})", url, content_type)));
+    }
+
+    // check the advertised size and then make sure it doesn't lie
+    let too_big = Error::IoError(format!("{} is larger than {} bytes", url, MAX_MANIFEST_SIZE));
+    if resp.content_length().unwrap_or(0) > MAX_MANIFEST_SIZE {
+        return Err(too_big);
+    }
+    let mut body = String::new();
+    resp.by_ref().take(MAX_MANIFEST_SIZE + 1).read_to_string(&mut body)?;
+    if body.len() as u64 > MAX_MANIFEST_SIZE {
+        return Err(too_big);
+    }
+    Ok(body)
+}
@@ -0,0 +1,416 @@
+use crate::{Error, Result};
+use std::fmt;
+use std::path::PathBuf;
+
+/// The result of checking one of the files listed in a manifest.
+#[derive(Clone, Debug, PartialEq)]
+pub enum FileStatus {
+    /// The file's digest matches the manifest.
+    Ok,
+    /// The file's digest doesn't match the manifest.
+    Modified,
+    /// The file doesn't exist.
+    Missing,
+    /// The file exists but couldn't be read.
+    Unreadable(String),
+    /// The file wasn't checked because it didn't match the filter.
+    Filtered,
+    /// The entry isn't in the manifest's tree but in a directory that is.
+    Added,
+    /// The entry's permission bits changed, the recorded and the current
+    /// ones.
+    PermissionsChanged(u32, u32),
+    /// The signer couldn't hash the file and recorded why, it isn't checked.
+    SignerError(String),
+    /// The entry doesn't match any of the patterns of the signed policy.
+    OutsidePolicy,
+    /// The file wasn't checked because verifying stopped at the first
+    /// failure.
+    NotChecked,
+}
+
+impl fmt::Display for FileStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FileStatus::Ok => write!(f, "OK"),
+            FileStatus::Modified => write!(f, "Modified"),
+            FileStatus::Missing => write!(f, "Missing"),
+            FileStatus::Unreadable(reason) => write!(f, "Unreadable ({})", reason),
+            FileStatus::Filtered => write!(f, "Skipped (filtered)"),
+            FileStatus::Added => write!(f, "Added"),
+            FileStatus::PermissionsChanged(old, new) => write!(f, "Permissions changed ({:04o} -> {:04o})", old, new),
+            FileStatus::SignerError(reason) => write!(f, "Unreadable when signed ({})", reason),
+            FileStatus::OutsidePolicy => write!(f, "Outside the signed policy"),
+            FileStatus::NotChecked => write!(f, "Skipped (stopped early)")
+        }
+    }
+}
+
+/// The result of checking one of the signatures on a manifest.
+#[derive(Clone, Debug, PartialEq)]
+pub enum SignatureStatus {
+    Valid,
+    Invalid,
+    /// The signer isn't one of the given keys so the signature wasn't
+    /// checked.
+    Untrusted,
+    /// The DIDDir has no identity for the signer so the signature couldn't
+    /// be checked.
+    UnknownSigner,
+    /// The signature is valid but the signer's key was revoked.
+    Revoked,
+}
+
+impl fmt::Display for SignatureStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SignatureStatus::Valid => write!(f, "Valid"),
+            SignatureStatus::Invalid => write!(f, "Invalid"),
+            SignatureStatus::Untrusted => write!(f, "Untrusted"),
+            SignatureStatus::UnknownSigner => write!(f, "Unknown signer"),
+            SignatureStatus::Revoked => write!(f, "Revoked")
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct FileReport {
+    pub path: String,
+    pub status: FileStatus,
+}
+
+#[derive(Clone, Debug)]
+pub struct SignatureReport {
+    pub signer: String,
+    /// The name the manifest gives the signer, it isn't signed.
+    pub name: Option<String>,
+    /// The line of the given keyring with the signer's key.
+    pub keyring_line: Option<usize>,
+    pub status: SignatureStatus,
+}
+
+/// Everything that was checked when verifying a manifest.
+#[derive(Clone, Debug)]
+pub struct VerifyReport {
+    pub manifest: PathBuf,
+    pub files: Vec<FileReport>,
+    pub signatures: Vec<SignatureReport>,
+    /// When the manifest was timestamped, if the timestamp was checked.
+    pub timestamp: Option<String>,
+    /// The number of files that may not match without failing.
+    pub allowed_mismatches: usize,
+    /// Whether the manifest is laid out exactly the way bs writes it. The
+    /// signatures are checked over the rebuilt bytes, so a manifest that was
+    /// reformatted or written by a different version fails even though its
+    /// contents weren't tampered with.
+    pub canonical: bool,
+}
+
+impl FileReport {
+    fn check(&self) -> Result<()> {
+        match self.status {
+            FileStatus::Ok |
+            FileStatus::Filtered |
+            FileStatus::NotChecked |
+            FileStatus::SignerError(_) => Ok(()),
+            FileStatus::Modified => {
+                Err(Error::DigestMismatch(format!("{} has been modified", self.path)))
+            },
+            FileStatus::Missing => {
+                Err(Error::DigestMismatch(format!("{} is missing", self.path)))
+            },
+            FileStatus::Unreadable(ref reason) => {
+                Err(Error::DigestMismatch(format!("{} could not be read: {}", self.path, reason)))
+            },
+            FileStatus::Added => {
+                Err(Error::DigestMismatch(format!("{} was added", self.path)))
+            },
+            FileStatus::PermissionsChanged(old, new) => {
+                Err(Error::DigestMismatch(format!("{} permissions changed from {:04o} to {:04o}", self.path, old, new)))
+            },
+            FileStatus::OutsidePolicy => {
+                Err(Error::InvalidMeta(format!("{} is outside the signed policy", self.path)))
+            }
+        }
+    }
+}
+
+impl VerifyReport {
+    /// The files that were modified, are missing, couldn't be read or are
+    /// outside the signed policy.
+    pub fn mismatches(&self) -> Vec<&FileReport> {
+        self.files.iter().filter(|f| f.check().is_err()).collect()
+    }
+
+    /// Succeeds if no more than the allowed number of files don't match and
+    /// there is at least one valid signature from a trusted signer and none
+    /// invalid. Otherwise it returns the first failure. An entry outside the
+    /// signed policy always fails, no matter how many mismatches are allowed.
+    pub fn check(&self) -> Result<()> {
+        if let Some(file) = self.files.iter().find(|f| f.status == FileStatus::OutsidePolicy) {
+            return file.check();
+        }
+        let mismatches = self.mismatches();
+        if mismatches.len() > self.allowed_mismatches {
+            if self.allowed_mismatches == 0 {
+                return mismatches[0].check();
+            }
+            return Err(Error::DigestMismatch(format!("{} files don't match the manifest, at most {} allowed",
+                                                     mismatches.len(), self.allowed_mismatches)));
+        }
+
+        if self.signatures.is_empty() {
+            return Err(Error::SignatureInvalid("manifest is not signed".to_string()));
+        }
+        if let Some(sig) = self.signatures.iter().find(|s| s.status == SignatureStatus::Invalid) {
+            if !self.canonical {
+                return Err(Error::SignatureInvalid(format!(
+                    "invalid signature from {}; the manifest is not in canonical form, it may have been \
+                     reformatted or written by a different version of bs", sig.signer)));
+            }
+            return Err(Error::SignatureInvalid(format!("invalid signature from {}", sig.signer)));
+        }
+        if !self.signatures.iter().any(|s| s.status == SignatureStatus::Valid) {
+            let revoked: Vec<&str> = self.signatures.iter()
+                .filter(|s| s.status == SignatureStatus::Revoked)
+                .map(|s| s.signer.as_str())
+                .collect();
+            if !revoked.is_empty() {
+                return Err(Error::SignatureInvalid(format!(
+                    "no signatures from a signer that isn't revoked, {} revoked", revoked.join(", "))));
+            }
+            let unknown: Vec<&str> = self.unknown_signers();
+            if !unknown.is_empty() {
+                return Err(Error::SignatureInvalid(format!(
+                    "no signatures from a trusted signer, the DIDDir has no identity for {}; \
+                     add it to the DIDDir or pass --key to trust it", unknown.join(", "))));
+            }
+            return Err(Error::SignatureInvalid("no signatures from a trusted signer".to_string()));
+        }
+        Ok(())
+    }
+
+    /// The files the signer recorded as unreadable, they are only
+    /// informational.
+    pub fn signer_errors(&self) -> Vec<&FileReport> {
+        self.files.iter().filter(|f| if let FileStatus::SignerError(_) = f.status { true } else { false }).collect()
+    }
+
+    /// The pkids of the signers whose identities aren't in the DIDDir.
+    pub fn unknown_signers(&self) -> Vec<&str> {
+        self.signatures.iter()
+            .filter(|s| s.status == SignatureStatus::UnknownSigner)
+            .map(|s| s.signer.as_str())
+            .collect()
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.check().is_ok()
+    }
+}
+
+/// The worst outcome of verifying a manifest, ordered from best to worst.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Outcome {
+    Ok,
+    /// Files don't match the manifest.
+    Mismatch,
+    /// The signatures don't check out.
+    SignatureInvalid,
+    /// The manifest couldn't be verified at all.
+    Error,
+}
+
+impl Outcome {
+    /// The outcome of a checked report, or of a manifest whose check failed
+    /// some other way.
+    pub fn of(checked: &Result<()>) -> Self {
+        match checked {
+            Ok(()) => Outcome::Ok,
+            Err(Error::DigestMismatch(_)) => Outcome::Mismatch,
+            Err(Error::SignatureInvalid(_)) => Outcome::SignatureInvalid,
+            Err(_) => Outcome::Error
+        }
+    }
+
+    /// The process exit code, 0 for success and higher the worse it is.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Outcome::Ok => 0,
+            Outcome::Mismatch => 1,
+            Outcome::SignatureInvalid => 2,
+            Outcome::Error => 3
+        }
+    }
+}
+
+impl Default for Outcome {
+    fn default() -> Self {
+        Outcome::Ok
+    }
+}
+
+/// The totals over all of the manifests verified in one run.
+#[derive(Clone, Debug, Default)]
+pub struct VerifySummary {
+    pub files: usize,
+    pub modified: usize,
+    pub missing: usize,
+    pub unreadable: usize,
+    pub added: usize,
+    pub outside_policy: usize,
+    pub signatures: usize,
+    pub valid_signatures: usize,
+    /// The manifests that couldn't be verified at all.
+    pub errors: usize,
+    pub worst: Outcome,
+}
+
+impl VerifySummary {
+    /// Adds a manifest's report, if there is one, and how its check went.
+    pub fn add(&mut self, report: Option<&VerifyReport>, checked: &Result<()>) {
+        if let Some(report) = report {
+            for file in &report.files {
+                match file.status {
+                    FileStatus::Ok => self.files += 1,
+                    FileStatus::Modified |
+                    FileStatus::PermissionsChanged(_, _) => {
+                        self.files += 1;
+                        self.modified += 1;
+                    },
+                    FileStatus::Missing => {
+                        self.files += 1;
+                        self.missing += 1;
+                    },
+                    FileStatus::Unreadable(_) => {
+                        self.files += 1;
+                        self.unreadable += 1;
+                    },
+                    FileStatus::Added => self.added += 1,
+                    FileStatus::OutsidePolicy => self.outside_policy += 1,
+                    FileStatus::Filtered |
+                    FileStatus::NotChecked |
+                    FileStatus::SignerError(_) => {}
+                }
+            }
+            self.signatures += report.signatures.len();
+            self.valid_signatures += report.signatures.iter().filter(|s| s.status == SignatureStatus::Valid).count();
+        } else {
+            self.errors += 1;
+        }
+        self.worst = std::cmp::max(self.worst, Outcome::of(checked));
+    }
+}
+
+impl fmt::Display for VerifySummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Verified {} files, {} modified, {} missing", self.files, self.modified, self.missing)?;
+        if self.unreadable > 0 {
+            write!(f, ", {} unreadable", self.unreadable)?;
+        }
+        if self.added > 0 {
+            write!(f, ", {} added", self.added)?;
+        }
+        if self.outside_policy > 0 {
+            write!(f, ", {} outside the policy", self.outside_policy)?;
+        }
+        write!(f, "; {}/{} signatures valid", self.valid_signatures, self.signatures)?;
+        if self.errors > 0 {
+            write!(f, "; {} manifests could not be verified", self.errors)?;
+        }
+        write!(f, ".")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report(files: &[FileStatus], signatures: &[SignatureStatus]) -> VerifyReport {
+        VerifyReport {
+            manifest: PathBuf::from("bs.json"),
+            files: files.iter().enumerate()
+                .map(|(i, status)| FileReport { path: format!("file{}", i), status: status.clone() })
+                .collect(),
+            signatures: signatures.iter()
+                .map(|status| SignatureReport { signer: "@key.ed25519".to_string(), name: None, keyring_line: None,
+                                                status: status.clone() })
+                .collect(),
+            timestamp: None,
+            allowed_mismatches: 0,
+            canonical: true,
+        }
+    }
+
+    fn summarize(reports: &[Option<VerifyReport>]) -> VerifySummary {
+        let mut summary = VerifySummary::default();
+        for report in reports {
+            let checked = match report {
+                Some(report) => report.check(),
+                None => Err(Error::IoError("bs.json: No such file or directory".to_string()))
+            };
+            summary.add(report.as_ref(), &checked);
+        }
+        summary
+    }
+
+    #[test]
+    fn all_valid() {
+        let summary = summarize(&[
+            Some(report(&[FileStatus::Ok, FileStatus::Ok, FileStatus::Ok], &[SignatureStatus::Valid])),
+            Some(report(&[FileStatus::Ok, FileStatus::Filtered], &[SignatureStatus::Valid])),
+        ]);
+        assert_eq!(summary.to_string(), "Verified 4 files, 0 modified, 0 missing; 2/2 signatures valid.");
+        assert_eq!(summary.worst.exit_code(), 0);
+    }
+
+    #[test]
+    fn mismatches() {
+        let summary = summarize(&[
+            Some(report(&[FileStatus::Ok, FileStatus::Modified, FileStatus::PermissionsChanged(0o644, 0o755),
+                          FileStatus::Missing, FileStatus::Unreadable("Permission denied".to_string()),
+                          FileStatus::Added],
+                        &[SignatureStatus::Valid])),
+        ]);
+        assert_eq!(summary.to_string(),
+                   "Verified 5 files, 2 modified, 1 missing, 1 unreadable, 1 added; 1/1 signatures valid.");
+        assert_eq!(summary.worst, Outcome::Mismatch);
+        assert_eq!(summary.worst.exit_code(), 1);
+    }
+
+    #[test]
+    fn outside_the_policy() {
+        let summary = summarize(&[
+            Some(report(&[FileStatus::Ok, FileStatus::OutsidePolicy], &[SignatureStatus::Valid])),
+        ]);
+        assert_eq!(summary.to_string(),
+                   "Verified 1 files, 0 modified, 0 missing, 1 outside the policy; 1/1 signatures valid.");
+        assert_eq!(summary.worst, Outcome::Error);
+    }
+
+    #[test]
+    fn invalid_signatures_are_worse_than_mismatches() {
+        let summary = summarize(&[
+            Some(report(&[FileStatus::Modified], &[SignatureStatus::Valid])),
+            Some(report(&[FileStatus::Ok], &[SignatureStatus::Invalid, SignatureStatus::Untrusted])),
+        ]);
+        assert_eq!(summary.to_string(), "Verified 2 files, 1 modified, 0 missing; 1/3 signatures valid.");
+        assert_eq!(summary.worst, Outcome::SignatureInvalid);
+        assert_eq!(summary.worst.exit_code(), 2);
+    }
+
+    #[test]
+    fn manifests_that_could_not_be_verified() {
+        let summary = summarize(&[Some(report(&[FileStatus::Ok], &[SignatureStatus::Valid])), None, None]);
+        assert_eq!(summary.to_string(),
+                   "Verified 1 files, 0 modified, 0 missing; 1/1 signatures valid; 2 manifests could not be verified.");
+        assert_eq!(summary.worst, Outcome::Error);
+        assert_eq!(summary.worst.exit_code(), 3);
+    }
+
+    #[test]
+    fn nothing_verified() {
+        assert_eq!(summarize(&[]).to_string(), "Verified 0 files, 0 modified, 0 missing; 0/0 signatures valid.");
+        assert_eq!(summarize(&[]).worst.exit_code(), 0);
+    }
+}
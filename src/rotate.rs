@@ -0,0 +1,159 @@
+use base64::{decode_config, encode_config, URL_SAFE};
+use crate::{Error, Result};
+use crate::identity;
+use serde_json::{self, Value as JsonValue};
+use sodiumoxide::crypto::sign::{self, PublicKey, SecretKey, Signature};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+static ROTATION_TYPE: &'static str = "bs/rotation/1";
+
+/// A new identity and the statement, signed by the old key, that it
+/// supersedes the old one.
+pub struct Rotation {
+    pub pkid: String,
+    pub identity: String,
+    pub statement: String,
+    /// The alias that was moved from the old identity to the new one.
+    pub alias: Option<String>,
+}
+
+// the signature covers the statement without the signature, the fields are
+// always in the same order
+fn signed_bytes(old: &str, new: &str, created: u64) -> String {
+    format!("{{\n  \"type\": \"{}\",\n  \"old\": \"{}\",\n  \"new\": \"{}\",\n  \"created\": {}\n}}",
+            ROTATION_TYPE, old, new, created)
+}
+
+/// Generates a new keypair, cross-signs it with the identity being rotated
+/// out and stores it in the DIDDir next to the old one. The alias the old
+/// identity was given by, or "default" if it was given by pkid or not at
+/// all, is moved to the new identity if it pointed at the old one.
+pub fn rotate(kdroot: &Option<String>, pkid_or_alias: &Option<String>, insecure_permissions: bool) -> Result<Rotation> {
+    crate::init()?;
+
+    // 1. unlock the old key
+    let old = identity::from_pkid_or_alias(kdroot, pkid_or_alias, insecure_permissions)?;
+    let (signk, _) = identity::key_pair(&*old)?;
+    let sk: SecretKey = signk.into();
+
+    // 2. generate the new identity
+    let (pkid, identity) = identity::generate()?;
+
+    // 3. sign the statement that the new pkid supersedes the old one
+    let created = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let msg = signed_bytes(&old.pkid(), &pkid, created);
+    let Signature(sb) = sign::sign_detached(msg.as_bytes(), &sk);
+    let mut statement = msg[..msg.len() - 2].to_string();
+    statement.push_str(&format!(",\n  \"signature\": \"{}.sig.ed25519\"\n}}\n", encode_config(&sb.to_vec(), URL_SAFE)));
+
+    // 4. store the new identity and move the alias over to it
+    let alias = match pkid_or_alias {
+        Some(poa) if identity::verify_key_from_pkid(poa).is_err() => poa.to_string(),
+        _ => "default".to_string()
+    };
+    let alias = if identity::alias_target(kdroot, &alias)? == Some(old.pkid()) { Some(alias) } else { None };
+    identity::store_identity(kdroot, &pkid, &identity, alias.as_ref().map(String::as_str))?;
+
+    Ok(Rotation { pkid: pkid, identity: identity, statement: statement, alias: alias })
+}
+
+/// Checks a rotation statement's signature by the old key and returns the
+/// old and the new pkid.
+pub fn verify_rotation(statement: &str) -> Result<(String, String)> {
+    let json: JsonValue = serde_json::from_str(statement)?;
+    let field = |name: &str| -> Result<String> {
+        match json[name].as_str() {
+            Some(v) => Ok(v.to_string()),
+            None => Err(Error::InvalidMeta(format!("rotation statement has no \"{}\"", name)))
+        }
+    };
+    if field("type")? != ROTATION_TYPE {
+        return Err(Error::InvalidMeta("not a bs rotation statement".to_string()));
+    }
+    let old = field("old")?;
+    let new = field("new")?;
+    let created = match json["created"].as_u64() {
+        Some(created) => created,
+        None => return Err(Error::InvalidMeta("rotation statement has no \"created\"".to_string()))
+    };
+
+    let sig = field("signature")?;
+    let data = match sig.rfind(".sig.ed25519") {
+        Some(i) => decode_config(&sig[..i], URL_SAFE)?,
+        None => return Err(Error::InvalidEncoding("not valid sb signature".to_string()))
+    };
+    let sig = Signature::from_slice(&data)
+        .ok_or_else(|| Error::SignatureInvalid("not the right number of bytes for a signature".to_string()))?;
+    let pk: PublicKey = identity::verify_key_from_pkid(&old)?.into();
+    if !sign::verify_detached(&sig, signed_bytes(&old, &new, created).as_bytes(), &pk) {
+        return Err(Error::SignatureInvalid(format!("rotation statement is not signed by {}", old)));
+    }
+    Ok((old, new))
+}
+
+/// Follows a chain of rotation statements from a pkid to the pkid that
+/// currently supersedes it.
+pub fn follow(pkid: &str, statements: &[String]) -> Result<String> {
+    let mut rotations = Vec::new();
+    for statement in statements {
+        rotations.push(verify_rotation(statement)?);
+    }
+
+    let mut current = pkid.to_string();
+    let mut seen = vec![current.clone()];
+    while let Some((_, new)) = rotations.iter().find(|(old, _)| *old == current) {
+        if seen.contains(new) {
+            return Err(Error::InvalidMeta(format!("rotation chain loops back to {}", new)));
+        }
+        current = new.clone();
+        seen.push(current.clone());
+    }
+    Ok(current)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use std::path::PathBuf;
+
+    fn seeded(name: &str) -> (Option<String>, String) {
+        let diddir: PathBuf = testing::scratch_dir(name);
+        let pkid = identity::seed_identity(&diddir, b"test").unwrap();
+        (Some(diddir.to_string_lossy().into_owned()), pkid)
+    }
+
+    fn rotated(kdroot: &Option<String>, pkid_or_alias: &Option<String>) -> Rotation {
+        match rotate(kdroot, pkid_or_alias, false) {
+            Ok(rotation) => rotation,
+            Err(e) => panic!("the identity couldn't be rotated: {}", e)
+        }
+    }
+
+    #[test]
+    fn the_alias_moves_to_the_new_identity() {
+        let (kdroot, old) = seeded("rotate");
+        let rotation = rotated(&kdroot, &None);
+        assert_eq!(rotation.alias, Some("default".to_string()));
+        assert_eq!(identity::alias_target(&kdroot, "default"), Ok(Some(rotation.pkid.clone())));
+        assert_eq!(verify_rotation(&rotation.statement), Ok((old.clone(), rotation.pkid.clone())));
+
+        // the default now unlocks the new key and the old one is still there
+        let current = identity::from_pkid_or_alias(&kdroot, &None, false).ok().expect("no default identity");
+        assert_eq!(current.pkid(), rotation.pkid);
+        assert!(identity::from_pkid_or_alias(&kdroot, &Some(old.clone()), false).is_ok());
+        assert_eq!(follow(&old, &[rotation.statement]), Ok(rotation.pkid));
+    }
+
+    #[test]
+    fn only_an_alias_of_the_old_identity_moves() {
+        let (kdroot, old) = seeded("rotate-by-pkid");
+        let first = rotated(&kdroot, &Some(old.clone()));
+        assert_eq!(first.alias, Some("default".to_string()));
+
+        // the default points at the first rotation now, not at the old pkid
+        let second = rotated(&kdroot, &Some(old));
+        assert_eq!(second.alias, None);
+        assert_eq!(identity::alias_target(&kdroot, "default"), Ok(Some(first.pkid)));
+    }
+}
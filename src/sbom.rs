@@ -0,0 +1,53 @@
+use crate::Result;
+use crate::encoding::DigestEncoding;
+use crate::fs::Algorithm;
+use crate::manifest::{self, Manifest};
+use crate::report::{FileStatus, SignatureStatus, VerifyReport};
+use serde_json::json;
+
+static BOM_FORMAT: &'static str = "CycloneDX";
+static SPEC_VERSION: &'static str = "1.4";
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Lists the verified files of a manifest as a CycloneDX BOM of file
+/// components, the signers whose signatures are valid are its authors.
+/// CycloneDX has no name for SHA-512/256 so every digest is also given as
+/// the manifest's sigil in a "bs:digest" property.
+pub fn cyclonedx(m: &Manifest, report: &VerifyReport, encoding: Option<DigestEncoding>) -> Result<String> {
+    let mut components = Vec::new();
+    for (path, digest) in &m.files {
+        let verified = report.files.iter().any(|f| f.path == *path && f.status == FileStatus::Ok);
+        if !verified || manifest::sigil_symlink(digest)?.is_some() {
+            continue;
+        }
+        let hashes = match manifest::sigil_algorithm(digest)? {
+            Algorithm::Blake3 => vec![json!({ "alg": "BLAKE3", "content": to_hex(&manifest::sigil_digest(digest, encoding)?) })],
+            Algorithm::Sha512_256 => Vec::new()
+        };
+        components.push(json!({
+            "type": "file",
+            "name": path,
+            "hashes": hashes,
+            "properties": [{ "name": "bs:digest", "value": digest }]
+        }));
+    }
+
+    let authors: Vec<_> = report.signatures.iter()
+        .filter(|s| s.status == SignatureStatus::Valid)
+        .map(|s| json!({ "name": s.signer }))
+        .collect();
+
+    Ok(format!("{:#}\n", json!({
+        "bomFormat": BOM_FORMAT,
+        "specVersion": SPEC_VERSION,
+        "version": 1,
+        "metadata": {
+            "authors": authors,
+            "tools": [{ "name": "bs", "version": env!("CARGO_PKG_VERSION") }]
+        },
+        "components": components
+    })))
+}
@@ -1,95 +1,915 @@
 extern crate diddir;
 
 use base64::{encode_config, URL_SAFE};
-use indicatif::{ProgressBar, ProgressStyle};
-use diddir::{Config, DIDDir};
-use crate::Result;
+use indicatif::{HumanBytes, HumanDuration, ProgressBar, ProgressStyle};
+use glob::Pattern;
+use crate::{Error, Result};
 use crate::identity;
-use crate::fs;
+use crate::encoding::DigestEncoding;
+use crate::fs::{self, Algorithm, AlgorithmChoice, AlgorithmSet, Compression, Count, HardlinkMode, Hash, Hashed, Hasher,
+                ProgressTemplate, ReadStrategy, SymlinkMode, TreeEntry, STDIN_PATH};
+use crate::manifest::{self, Manifest, SortBy};
+use crate::merkle;
+use crate::minisign;
+use crate::slsa;
+use crate::sshsig;
 use sodiumoxide::crypto::sign::{self, PublicKey, SecretKey, Signature };
-use std::path::{Path, PathBuf};
+use std::convert::TryFrom;
+use std::io;
+use std::process::{Command, Stdio};
+use std::path::{Component, Path, PathBuf};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use tracing::{info, info_span, warn};
 
-fn get_config(diddir: &Option<String>) -> Result<Config> {
-    match diddir {
-        Some(root) => Ok(Config::with_path(Path::new(root))),
-        None => Ok(Config::new())
+/// The output of signing. The signature is only kept separate from the
+/// manifest when a detached signature was requested, it is written to the
+/// manifest's path with the suffix appended. In Merkle mode the manifest is
+/// the signed root and the inclusion proofs take the signature's place.
+pub struct Signed {
+    pub manifest: String,
+    pub signature: Option<String>,
+    pub signature_suffix: &'static str,
+}
+
+/// Where the output of signing is written to.
+pub trait SignatureSink {
+    /// Writes the manifest, or the envelope for formats that embed the
+    /// signature.
+    fn write_manifest(&mut self, manifest: &[u8]) -> Result<()>;
+
+    /// Writes a detached signature, the suffix is the extension it
+    /// conventionally gets when stored next to the manifest.
+    fn write_signature(&mut self, signature: &[u8], suffix: &str) -> Result<()>;
+}
+
+impl Signed {
+    /// Writes the manifest and the detached signature, if there is one, to
+    /// the sink.
+    pub fn write_to<S: SignatureSink>(&self, sink: &mut S) -> Result<()> {
+        sink.write_manifest(self.manifest.as_bytes())?;
+        if let Some(ref sig) = self.signature {
+            sink.write_signature(sig.as_bytes(), self.signature_suffix)?;
+        }
+        Ok(())
     }
 }
 
-pub fn sign(_verbose: bool,
-            _status_fd: &Option<u32>,
-            kdroot: &Option<String>, 
-            pkid_or_alias: &Option<String>, 
-            files: Vec<PathBuf>) -> Result<String> {
+#[derive(Clone, Copy, PartialEq)]
+enum Format {
+    Manifest,
+    Slsa,
+    Minisign,
+    Ssh,
+    Ndjson
+}
 
-    // scan the files recursively and hash them
-    let hashes = fs::hash(files);
+impl Format {
+    fn from_name(fmt: &Option<String>) -> Result<Self> {
+        match fmt.as_ref().map(String::as_str) {
+            None => Ok(Format::Manifest),
+            Some("slsa") => Ok(Format::Slsa),
+            Some("minisign") => Ok(Format::Minisign),
+            Some("ssh") => Ok(Format::Ssh),
+            Some("ndjson") => Ok(Format::Ndjson),
+            Some(other) => {
+                Err(Error::Syntax(format!("unsupported signature format: {}", other)))
+            }
+        }
+    }
+}
+
+/// The options that control what gets signed and how it is output.
+#[derive(Clone, Default)]
+pub struct SignOptions {
+    /// The signature format, the manifest if unspecified.
+    pub format: Option<String>,
+    /// The namespace of an SSH signature, "file" if unspecified.
+    pub ssh_namespace: Option<String>,
+    /// Output the signature separately from the manifest.
+    pub detached: bool,
+    /// A leading path prefix that is stripped from every recorded path.
+    pub trim_prefix: Option<PathBuf>,
+    /// The number of retries after a transient IO error while reading files.
+    pub retries: Option<u32>,
+    /// The URL of an RFC 3161 Time Stamping Authority to timestamp the
+    /// signature with.
+    pub tsa: Option<String>,
+    /// Error on files given more than once instead of signing them once.
+    pub strict: bool,
+    /// Error on fifos, sockets and devices instead of leaving them out.
+    pub strict_file_types: bool,
+    /// Re-hash files that changed while they were hashed and skip the ones
+    /// that keep changing.
+    pub verify_stable: bool,
+    /// Error if two files with different contents have the same digest.
+    pub unique_content: bool,
+    /// The number of threads to hash with, 0 for all cores but one and one
+    /// per core if unset.
+    pub threads: Option<usize>,
+    /// Whether the files are read in parallel or one at a time in path
+    /// order.
+    pub read_strategy: ReadStrategy,
+    /// How the files are compressed, their uncompressed contents are hashed.
+    pub decompress: Compression,
+    /// Don't print the summary of what was hashed.
+    pub quiet: bool,
+    /// How the progress is shown while hashing and signing.
+    pub progress_template: ProgressTemplate,
+    /// The digest algorithm to hash the files with.
+    pub algorithm: AlgorithmChoice,
+    /// Refuse to sign with digest algorithms outside of this set.
+    pub allowed_algorithms: Option<AlgorithmSet>,
+    /// How symlinks are handled.
+    pub symlink_mode: SymlinkMode,
+    /// How paths that are hardlinks to the same file are hashed.
+    pub hardlink_mode: HardlinkMode,
+    /// How many directories deep to recurse, unlimited if unset.
+    pub max_depth: Option<usize>,
+    /// Only sign the files modified at or after the time, the older ones
+    /// are skipped.
+    pub modified_since: Option<SystemTime>,
+    /// How the digests are encoded in the manifest.
+    pub digest_encoding: DigestEncoding,
+    /// The order of the files in the manifest.
+    pub sort_by: SortBy,
+    /// Sign only the root of a Merkle tree over the files and output an
+    /// inclusion proof for each of them.
+    pub merkle: bool,
+    /// Record the alias the signer was picked by next to the signature. It
+    /// is left out of the signed bytes so the manifest doesn't depend on
+    /// anyone's local aliases, which also means it can't be trusted.
+    pub include_signer_name: bool,
+    /// Also sign the directory structure: every entry under the given paths
+    /// with its kind and permission bits.
+    pub include_tree: bool,
+    /// Also sign the permission bits of each file, only on unix.
+    pub include_mode: bool,
+    /// Also sign the size of each file so verify can catch changed files
+    /// without hashing them.
+    pub include_size: bool,
+    /// Also sign a single digest over all of the files, see
+    /// `Manifest::files_digest`.
+    pub manifest_digest: bool,
+    /// Record every path in Unicode NFC, see `Manifest::normalize_paths`.
+    pub normalize_paths: bool,
+    /// Also sign the empty directories, which otherwise leave no trace in
+    /// the manifest.
+    pub hash_empty_dirs: bool,
+    /// Record the files that couldn't be hashed and why in the signed
+    /// manifest instead of only leaving them out.
+    pub record_errors: bool,
+    /// The context the manifest is for, its domain separation tag is signed
+    /// so the signature doesn't verify for any other context.
+    pub context: Option<String>,
+    /// A manifest whose files are kept and added to, its signatures are
+    /// dropped.
+    pub extend: Option<PathBuf>,
+    /// Replace the digests of files already in the extended manifest instead
+    /// of erroring.
+    pub overwrite: bool,
+    /// A command to run instead of hashing files, its stdout is signed under
+    /// the command line.
+    pub exec: Option<Vec<String>>,
+    /// The journal the hashes are recorded in as they are done, an
+    /// interrupted run reuses the hashes already in it.
+    pub resume: Option<PathBuf>,
+    /// Hash at a lower priority so foreground work isn't starved, only on
+    /// unix.
+    pub nice: bool,
+    /// Write the manifest as minified JSON, the signatures are over the same
+    /// canonical bytes either way.
+    pub compact: bool,
+    /// Unlock the signing key even if the DIDDir is open to other users.
+    pub insecure_permissions: bool,
+    /// The directory the files are resolved under and recorded relative to,
+    /// nothing outside of it is read.
+    pub root: Option<PathBuf>,
+    /// The path patterns every signed file has to match, they are signed
+    /// with the manifest and verify enforces them too.
+    pub policy: Vec<String>,
+    /// An age-encrypted file holding the signing key, used instead of an
+    /// identity from the DIDDir.
+    pub age_key: Option<PathBuf>,
+    /// The age identity file that decrypts `age_key` when it was encrypted
+    /// to recipients rather than with a passphrase.
+    pub age_identity: Option<PathBuf>,
+}
+
+fn compile_policy(policy: &[String]) -> Result<Vec<Pattern>> {
+    let mut patterns = Vec::new();
+    for pattern in policy {
+        match Pattern::new(pattern) {
+            Ok(pattern) => patterns.push(pattern),
+            Err(e) => return Err(Error::Syntax(format!("the policy pattern {} is invalid: {}", pattern, e)))
+        }
+    }
+    Ok(patterns)
+}
+
+// nothing is signed if any path, trimmed the way it is recorded, falls
+// outside the policy
+fn check_policy(policy: &[Pattern], manifest: &Manifest) -> Result<()> {
+    let paths = manifest.files.iter().map(|(path, _)| path)
+        .chain(manifest.errors.iter().map(|(path, _)| path))
+        .chain(manifest.dirs.iter());
+    for path in paths {
+        if !policy.iter().any(|p| p.matches(path)) {
+            return Err(Error::Syntax(format!("{} is outside the policy", path)));
+        }
+    }
+    Ok(())
+}
+
+fn print_summary(hashes: &[Hash], start: Instant) {
+    let elapsed = start.elapsed();
+    let bytes: u64 = hashes.iter().map(|h| h.size).sum();
+    let secs = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+    let rate = if secs > 0.0 { (bytes as f64 / secs) as u64 } else { bytes };
+    eprintln!("Hashed {} files ({}) in {} at {}/s",
+              hashes.len(), HumanBytes(bytes), HumanDuration(elapsed), HumanBytes(rate));
+}
 
+// the spinner is only hidden with the rest of the progress display
+fn spinner(opts: &SignOptions) -> ProgressBar {
+    if opts.progress_template.template().is_none() {
+        return ProgressBar::hidden();
+    }
     let pb = ProgressBar::new_spinner();
     pb.set_style(ProgressStyle::default_spinner()
         .template("{spinner} {wide_msg}"));
     pb.enable_steady_tick(100);
-    pb.set_message("Loading DIDDir...");
+    pb
+}
 
-    let config = get_config(kdroot)?;
-    let diddir = DIDDir::open_or_init(&config)?;
+fn trim_path_prefix(path: &Path, prefix: &PathBuf) -> Result<PathBuf> {
+    match path.strip_prefix(prefix) {
+        Ok(path) => Ok(path.to_path_buf()),
+        Err(_) => Err(Error::InvalidMeta(format!("{} does not start with {}", path.display(), prefix.display())))
+    }
+}
 
-    pb.set_message("Unlocking signing key...");
-    let identity = identity::from_pkid_or_alias(&diddir, pkid_or_alias)?;
+fn trim_prefix(hashes: Vec<Hash>, prefix: &PathBuf) -> Result<Vec<Hash>> {
+    let mut trimmed = Vec::new();
+    for mut hash in hashes {
+        // stdin isn't under any prefix
+        if hash.path == Path::new(STDIN_PATH) {
+            trimmed.push(hash);
+            continue;
+        }
+        hash.path = match hash.path.strip_prefix(prefix) {
+            Ok(path) => path.to_path_buf(),
+            Err(_) => {
+                return Err(Error::InvalidMeta(format!("{} does not start with {}",
+                                                      hash.path.display(), prefix.display())));
+            }
+        };
+        trimmed.push(hash);
+    }
+    Ok(trimmed)
+}
 
-    // construct the JSON to sign
-    let mut json = "{\n  \"files\": {\n".to_string();
-    for i in 0..hashes.len() {
-        let hash = &hashes[i];
-        let sb = format!("    \"{}\": \"&{}.sha512_256\"", hash.path.to_str()?,
-                         encode_config(&hash.hash, URL_SAFE));
-        json.push_str(&sb);
-        if i < (hashes.len() - 1) {
-            json.push_str(",\n");
-        } else {
-            json.push_str("\n");
+fn trim_tree_prefix(entries: Vec<TreeEntry>, prefix: &PathBuf) -> Result<Vec<TreeEntry>> {
+    let mut trimmed = Vec::new();
+    for mut entry in entries {
+        entry.path = match entry.path.strip_prefix(prefix) {
+            Ok(path) => path.to_path_buf(),
+            Err(_) => {
+                return Err(Error::InvalidMeta(format!("{} does not start with {}",
+                                                      entry.path.display(), prefix.display())));
+            }
+        };
+        trimmed.push(entry);
+    }
+    Ok(trimmed)
+}
+
+// the modes are read after hashing, the files' own and not their symlinks'
+#[cfg(unix)]
+fn file_modes(hashes: &[Hash]) -> Result<Vec<TreeEntry>> {
+    let mut modes = Vec::new();
+    for hash in hashes {
+        if hash.target.is_none() {
+            let meta = std::fs::metadata(&hash.path)?;
+            let kind = fs::EntryKind::from_meta(&meta);
+            modes.push(TreeEntry { path: hash.path.clone(), kind: kind, mode: fs::entry_mode(&meta) });
         }
     }
-    json.push_str("  }");
+    modes.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(modes)
+}
 
-    let mut sign_json = json.to_owned();
-    sign_json.push_str("\n}");
+// only the read-only flag is known off unix, it isn't worth signing
+#[cfg(not(unix))]
+fn file_modes(_hashes: &[Hash]) -> Result<Vec<TreeEntry>> {
+    tracing::warn!("file modes aren't available on this platform and aren't recorded");
+    Ok(Vec::new())
+}
 
-    pb.set_message("Signing JSON Manifest...");
+#[cfg(feature = "tsa")]
+fn timestamp(url: &str, sig: &[u8]) -> Result<String> {
+    Ok(encode_config(&crate::tsa::timestamp(url, sig)?, URL_SAFE))
+}
 
-    // get the JSON signature
-    let signature = {
-        if let Some(signk) = identity.sign_key() {
-            let sk: SecretKey = signk.into();
-            let sig = sign::sign_detached(sign_json.as_bytes(), &sk);
-            let Signature(ref sb) = sig;
-            format!("{}.sig.ed25519", encode_config(&sb.to_vec(), URL_SAFE))
+#[cfg(not(feature = "tsa"))]
+fn timestamp(_url: &str, _sig: &[u8]) -> Result<String> {
+    Err(Error::Syntax("bs was built without the \"tsa\" feature".to_string()))
+}
+
+fn hasher(opts: &SignOptions) -> Hasher {
+    let hasher = Hasher::new()
+        .strict(opts.strict)
+        .strict_file_types(opts.strict_file_types)
+        .verify_stable(opts.verify_stable)
+        .unique_content(opts.unique_content)
+        .algorithm(opts.algorithm)
+        .symlink_mode(opts.symlink_mode)
+        .hardlink_mode(opts.hardlink_mode)
+        .max_depth(opts.max_depth)
+        .modified_since(opts.modified_since)
+        .read_strategy(opts.read_strategy)
+        .decompress(opts.decompress)
+        .empty_dirs(opts.hash_empty_dirs)
+        .journal(opts.resume.clone())
+        .progress_template(opts.progress_template.clone())
+        .nice(opts.nice)
+        .root(opts.root.as_ref().and_then(|root| std::fs::canonicalize(root).ok()));
+    let hasher = match opts.threads {
+        Some(threads) => hasher.threads(threads),
+        None => hasher
+    };
+    match opts.retries {
+        Some(retries) => hasher.retries(retries),
+        None => hasher
+    }
+}
+
+// the child's stdout is hashed as it streams in, nothing is signed unless the
+// command succeeds since the output could be partial otherwise
+fn hash_command(hasher: &Hasher, command: &[String]) -> Result<Hash> {
+    let (program, args) = match command.split_first() {
+        Some(split) => split,
+        None => return Err(Error::Syntax("there is no command to run".to_string()))
+    };
+    let mut child = Command::new(program).args(args).stdout(Stdio::piped()).spawn()?;
+    let hash = hasher.hash_reader(&PathBuf::from(command.join(" ")), child.stdout.take()?);
+    let status = child.wait()?;
+    if !status.success() {
+        return Err(Error::IoError(format!("{} failed with {}, its output wasn't signed", program, status)));
+    }
+    hash
+}
+
+fn hash_files(hasher: &Hasher, opts: &SignOptions, files: Vec<PathBuf>) -> Result<Hashed> {
+    if files.is_empty() {
+        Ok(Hashed::new(Vec::new()))
+    } else if opts.sort_by == SortBy::Path {
+        hasher.hash_sorted(files)
+    } else {
+        hasher.hash(files)
+    }
+}
+
+// resolves the files under the canonical root, a path that leaves the root
+// through ".." or a symlink is an error. ".." is resolved before looking at
+// the file system so a path is recorded as given and not as where its
+// symlinks lead
+fn resolve_under_root(root: &Path, files: Vec<PathBuf>) -> Result<Vec<PathBuf>> {
+    let mut resolved = Vec::new();
+    for file in files {
+        if file == Path::new(STDIN_PATH) {
+            return Err(Error::Syntax("stdin has no path under the root".to_string()));
+        }
+        let outside = || Error::Syntax(format!("{} is outside of the root {}", file.display(), root.display()));
+        let relative = if file.is_absolute() {
+            match file.strip_prefix(root) {
+                Ok(relative) => relative.to_path_buf(),
+                Err(_) => return Err(outside())
+            }
         } else {
-            "no sign key".to_string()
+            file.to_path_buf()
+        };
+        let mut path = PathBuf::new();
+        for component in relative.components() {
+            match component {
+                Component::Normal(name) => path.push(name),
+                Component::CurDir => {},
+                Component::ParentDir => {
+                    if !path.pop() {
+                        return Err(outside());
+                    }
+                },
+                Component::RootDir | Component::Prefix(_) => return Err(outside())
+            }
         }
+        let path = root.join(path);
+        if !std::fs::canonicalize(&path)?.starts_with(root) {
+            return Err(outside());
+        }
+        resolved.push(path);
+    }
+    Ok(resolved)
+}
+
+// the root is canonicalized so the recorded paths don't depend on how it was
+// given
+fn canonical_root(opts: &SignOptions) -> Result<Option<PathBuf>> {
+    match opts.root {
+        Some(ref root) => match std::fs::canonicalize(root) {
+            Ok(root) if root.is_dir() => Ok(Some(root)),
+            Ok(_) => Err(Error::Syntax(format!("the root {} is not a directory", root.display()))),
+            Err(e) => Err(Error::IoError(format!("the root {}: {}", root.display(), e)))
+        },
+        None => Ok(None)
+    }
+}
+
+/// Counts the files that signing would hash and their bytes without reading
+/// them or unlocking a key.
+pub fn count(opts: &SignOptions, files: Vec<PathBuf>) -> Result<Count> {
+    let files = match canonical_root(opts)? {
+        Some(ref root) => resolve_under_root(root, files)?,
+        None => files
     };
+    hasher(opts).count(files)
+}
 
-    // get the pkid
-    let pkid = {
-        if let Some(verifyk) = identity.verify_key() {
-            let pk: PublicKey = verifyk.into();
-            let PublicKey(ref pkb) = pk;
-            format!("@{}.ed25519", encode_config(pkb, URL_SAFE))
-        } else {
-            "no verify key".to_string()
+pub fn sign(_verbose: bool,
+            _status_fd: &Option<u32>,
+            kdroot: &Option<String>, 
+            pkid_or_alias: &Option<String>, 
+            opts: &SignOptions,
+            files: Vec<PathBuf>) -> Result<Signed> {
+
+    let span = info_span!("sign", files = files.len());
+    let _enter = span.enter();
+    crate::init()?;
+    let detached = opts.detached;
+
+    // check the output format before doing any work
+    let format = Format::from_name(&opts.format)?;
+    if format == Format::Slsa && detached {
+        return Err(Error::Syntax("a SLSA envelope can't have a detached signature".to_string()));
+    }
+    if format == Format::Slsa && opts.symlink_mode == SymlinkMode::Target {
+        return Err(Error::Syntax("a SLSA statement can't record symlink targets".to_string()));
+    }
+    match opts.ssh_namespace {
+        Some(_) if format != Format::Ssh => {
+            return Err(Error::Syntax("a namespace is only given to SSH signatures".to_string()));
+        },
+        Some(ref namespace) if namespace.is_empty() => {
+            return Err(Error::Syntax("an SSH signature namespace can't be empty".to_string()));
+        },
+        _ => {}
+    }
+    if opts.decompress != Compression::None && !cfg!(feature = "decompress") {
+        return Err(Error::Syntax("decompressing requires the \"decompress\" feature".to_string()));
+    }
+    if opts.tsa.is_some() && (format != Format::Manifest || !cfg!(feature = "tsa")) {
+        return Err(Error::Syntax("timestamping requires the \"tsa\" feature and a manifest".to_string()));
+    }
+    if let Some(ref allowed) = opts.allowed_algorithms {
+        // auto mode may pick either algorithm depending on the file sizes
+        match opts.algorithm {
+            AlgorithmChoice::Fixed(algorithm) => allowed.check(algorithm)?,
+            AlgorithmChoice::Auto(_) => {
+                allowed.check(Algorithm::Sha512_256)?;
+                allowed.check(Algorithm::Blake3)?;
+            }
         }
+    }
+    if opts.merkle && (format != Format::Manifest || detached || opts.tsa.is_some()) {
+        return Err(Error::Syntax("a Merkle root can't be combined with other formats, detached signatures or timestamps".to_string()));
+    }
+    if opts.include_signer_name && (format != Format::Manifest || opts.merkle) {
+        return Err(Error::Syntax("signer names are only recorded in manifests".to_string()));
+    }
+    if opts.include_signer_name && opts.age_key.is_some() {
+        return Err(Error::Syntax("an age-encrypted key isn't picked by an alias, it has no signer name".to_string()));
+    }
+    if opts.include_tree && (format != Format::Manifest || opts.merkle || opts.max_depth.is_some()) {
+        return Err(Error::Syntax("the tree is only recorded in manifests of whole directories".to_string()));
+    }
+    if files.iter().filter(|f| *f == Path::new(STDIN_PATH)).count() > 1 {
+        return Err(Error::Syntax("stdin can only be signed once".to_string()));
+    }
+    if opts.include_tree && files.iter().any(|f| f == Path::new(STDIN_PATH)) {
+        return Err(Error::Syntax("stdin has no directory structure to record".to_string()));
+    }
+    if opts.exec.is_some() && (!files.is_empty() || opts.include_tree) {
+        return Err(Error::Syntax("a command's output is signed on its own".to_string()));
+    }
+    if opts.resume.is_some() && opts.exec.is_some() {
+        return Err(Error::Syntax("a command's output can't be resumed".to_string()));
+    }
+    if opts.include_mode && (format != Format::Manifest || opts.merkle || opts.extend.is_some()) {
+        return Err(Error::Syntax("file modes are only recorded in new manifests".to_string()));
+    }
+    if opts.include_mode && (opts.exec.is_some() || files.iter().any(|f| f == Path::new(STDIN_PATH))) {
+        return Err(Error::Syntax("a stream has no file mode to record".to_string()));
+    }
+    if opts.include_size && (format != Format::Manifest || opts.merkle || opts.extend.is_some()) {
+        return Err(Error::Syntax("file sizes are only recorded in new manifests".to_string()));
+    }
+    if opts.include_size && opts.decompress != Compression::None {
+        return Err(Error::Syntax("the sizes of decompressed files don't match the files on disk".to_string()));
+    }
+    if opts.normalize_paths && (format == Format::Slsa || opts.merkle) {
+        return Err(Error::Syntax("paths are only normalized in manifests".to_string()));
+    }
+    if opts.manifest_digest && (format != Format::Manifest || opts.merkle) {
+        return Err(Error::Syntax("a manifest digest is only recorded in manifests".to_string()));
+    }
+    if opts.hash_empty_dirs && (format != Format::Manifest || opts.merkle || opts.extend.is_some()) {
+        return Err(Error::Syntax("empty directories are only recorded in new manifests".to_string()));
+    }
+    if opts.hardlink_mode == HardlinkMode::Share && !cfg!(unix) {
+        warn!("--hardlink-mode share is only supported on unix, hashing every path");
+    }
+    if opts.nice && !cfg!(unix) {
+        warn!("--nice is only supported on unix, hashing at the normal priority");
+    }
+    if opts.compact && (format != Format::Manifest || opts.merkle || detached) {
+        return Err(Error::Syntax("only a manifest with embedded signatures can be compact, a detached signature is \
+                                  over the manifest as it is written".to_string()));
+    }
+    let policy = compile_policy(&opts.policy)?;
+    if !policy.is_empty() && (format != Format::Manifest || opts.merkle) {
+        return Err(Error::Syntax("a policy is only recorded in manifests".to_string()));
+    }
+    if opts.record_errors && (format != Format::Manifest || opts.merkle) {
+        return Err(Error::Syntax("errors are only recorded in manifests".to_string()));
+    }
+    if opts.record_errors && opts.modified_since.is_some() {
+        return Err(Error::Syntax("files skipped for being unmodified would be recorded as errors".to_string()));
+    }
+    if let Some(ref context) = opts.context {
+        if format != Format::Manifest || opts.merkle {
+            return Err(Error::Syntax("a context is only recorded in manifests".to_string()));
+        }
+        manifest::check_context(context)?;
+    }
+    if opts.extend.is_some() && (format != Format::Manifest || opts.merkle || opts.include_tree) {
+        return Err(Error::Syntax("only a plain manifest can be extended".to_string()));
+    }
+    if opts.merkle && opts.symlink_mode == SymlinkMode::Target {
+        return Err(Error::Syntax("a Merkle tree can't record symlink targets".to_string()));
+    }
+    if opts.root.is_some() && (opts.trim_prefix.is_some() || opts.exec.is_some()) {
+        return Err(Error::Syntax("the paths are already recorded relative to the root".to_string()));
+    }
+
+    // the files are read under the root and recorded relative to it, which
+    // is trimmed like a prefix
+    let root = canonical_root(opts)?;
+    let files = match root {
+        Some(ref root) => resolve_under_root(root, files)?,
+        None => files
+    };
+    let trim = root.or_else(|| opts.trim_prefix.clone());
+
+    // load the manifest being extended up front so a bad one fails early
+    let prior = match opts.extend {
+        Some(ref path) => {
+            let prior = Manifest::try_from(std::fs::read_to_string(path)?.as_str())?;
+            if !prior.tree.is_empty() || !prior.modes.is_empty() || !prior.errors.is_empty()
+                || !prior.dirs.is_empty() || !prior.policy.is_empty() || !prior.sizes.is_empty() {
+                return Err(Error::Syntax("a manifest with a tree, file modes, errors, empty directories, a policy or \
+                                          file sizes can't be extended".to_string()));
+            }
+            Some(prior)
+        },
+        None => None
+    };
+
+    // unlock the signing key before hashing so a public-only identity fails
+    // right away instead of after all of the files were read
+    // an age passphrase is prompted for before the spinner starts drawing
+    let age_identity = match opts.age_key {
+        Some(_) if pkid_or_alias.is_some() => {
+            return Err(Error::Syntax("an age-encrypted key is used instead of an identity".to_string()));
+        },
+        Some(ref age_key) => Some(identity::from_age(age_key, &opts.age_identity)?),
+        None => None
+    };
+    let pb = spinner(opts);
+    let identity = if let Some(identity) = age_identity {
+        identity
+    } else {
+        pb.set_message("Unlocking signing key...");
+        identity::from_pkid_or_alias(kdroot, pkid_or_alias, opts.insecure_permissions)?
+    };
+    let (signk, verifyk) = identity::key_pair(&*identity)?;
+    info!(pkid = %identity.pkid(), "key unlocked");
+    pb.finish_and_clear();
+
+    // the structure is walked before hashing so both see the same paths
+    let tree = if opts.include_tree { fs::tree(&files)? } else { Vec::new() };
+
+    // scan the files recursively and hash them
+    let start = Instant::now();
+    let hasher = hasher(opts);
+    let hashed = if let Some(ref command) = opts.exec {
+        Hashed::new(vec![hash_command(&hasher, command)?])
+    } else if files.len() == 1 && files[0] == Path::new(STDIN_PATH) {
+        // a single stream doesn't need the directory scanning machinery
+        let stdin = io::stdin();
+        Hashed::new(vec![hasher.hash_reader(&files[0], stdin.lock())?])
+    } else if files.iter().any(|f| f == Path::new(STDIN_PATH)) {
+        // stdin is hashed in the same run as the files, so it is checked
+        // against them for duplicates and the same contents
+        hash_files(&hasher.clone().stdin(io::stdin()), opts, files)?
+    } else {
+        hash_files(&hasher, opts, files)?
+    };
+    for skipped in &hashed.skipped {
+        eprintln!("Skipped {}: {}", skipped.path.display(), skipped.reason);
+    }
+    let hashes = hashed.hashes;
+    let mut errors = Vec::new();
+    if opts.record_errors {
+        for skipped in &hashed.skipped {
+            let path = match trim {
+                Some(ref prefix) => trim_path_prefix(&skipped.path, prefix)?,
+                None => skipped.path.to_path_buf()
+            };
+            errors.push((manifest::manifest_path(&path)?, skipped.reason.to_owned()));
+        }
+        errors.sort();
+    }
+    let mut dirs = Vec::new();
+    for dir in &hashed.empty_dirs {
+        let dir = match trim {
+            Some(ref prefix) => trim_path_prefix(dir, prefix)?,
+            None => dir.to_path_buf()
+        };
+        dirs.push(manifest::manifest_path(&dir)?);
+    }
+    dirs.sort();
+    if !opts.quiet {
+        print_summary(&hashes, start);
+    }
+    let modes = if opts.include_mode { file_modes(&hashes)? } else { Vec::new() };
+    let mut hashes = match trim {
+        Some(ref prefix) => trim_prefix(hashes, prefix)?,
+        None => hashes
     };
+    // the hashes already are in path order, trimming the same prefix off
+    // of every path doesn't change it
+    if opts.sort_by != SortBy::Path {
+        opts.sort_by.sort(&mut hashes);
+    }
+
+    let pb = spinner(opts);
+    let sk: SecretKey = signk.into();
+
+    if format == Format::Slsa {
+        pb.set_message("Signing SLSA provenance...");
+        let statement = slsa::statement(&hashes)?;
+        let envelope = slsa::envelope(&statement, &identity.pkid(), &sk)?;
+        info!(format = "slsa", subjects = hashes.len(), "signature created");
+        pb.finish_and_clear();
+        return Ok(Signed { manifest: envelope, signature: None, signature_suffix: "" });
+    }
+
+    if opts.merkle {
+        pb.set_message("Signing Merkle root...");
+        let tree = merkle::Tree::new(&hashes)?;
+        let Signature(raw_sig) = sign::sign_detached(merkle::signed_bytes(&tree.root, tree.size).as_bytes(), &sk);
+        let signature = format!("{}.sig.ed25519", encode_config(&raw_sig.to_vec(), URL_SAFE));
+        let root = merkle::root_document(&tree.root, tree.size, &[(identity.pkid(), signature)]);
+        info!(format = "merkle", files = tree.size, "signature created");
+        pb.finish_and_clear();
+        return Ok(Signed { manifest: root, signature: Some(merkle::proofs_json(&tree.proofs)), signature_suffix: ".proofs" });
+    }
+
+    // construct the JSON to sign
+    let mut manifest = Manifest::new(&hashes, opts.digest_encoding)?;
+    manifest.context = opts.context.clone();
+    manifest.errors = errors;
+    manifest.dirs = dirs;
+    if opts.include_size {
+        manifest.set_sizes(&hashes)?;
+    }
+    if let Some(ref prior) = prior {
+        manifest.extend(prior, opts.overwrite)?;
+        info!(prior = prior.files.len(), files = manifest.files.len(), "manifest extended");
+    }
+    if opts.include_tree {
+        let tree = match trim {
+            Some(ref prefix) => trim_tree_prefix(tree, prefix)?,
+            None => tree
+        };
+        manifest.set_tree(&tree)?;
+    }
+    if opts.include_mode {
+        let modes = match trim {
+            Some(ref prefix) => trim_tree_prefix(modes, prefix)?,
+            None => modes
+        };
+        manifest.set_modes(&modes)?;
+    }
+    if opts.normalize_paths {
+        manifest.normalize_paths()?;
+    }
+    if !policy.is_empty() {
+        check_policy(&policy, &manifest)?;
+        manifest.policy = opts.policy.clone();
+    }
+    if opts.manifest_digest {
+        manifest.set_manifest_digest(opts.digest_encoding);
+    }
+    let sign_json = if format == Format::Ndjson { manifest.ndjson_signed_bytes() } else { manifest.signed_bytes() };
+
+    if format == Format::Minisign {
+        pb.set_message("Signing minisign signature...");
+        let pk: PublicKey = verifyk.into();
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        let comment = format!("timestamp:{}\tsigner:{}", now, identity.pkid());
+        let minisig = minisign::sign(sign_json.as_bytes(), &sk, &pk, &comment)?;
+        info!(format = "minisign", files = manifest.files.len(), "signature created");
+        pb.finish_and_clear();
+        return Ok(Signed { manifest: sign_json, signature: Some(minisig), signature_suffix: ".minisig" });
+    }
+
+    if format == Format::Ssh {
+        pb.set_message("Signing SSH signature...");
+        let pk: PublicKey = verifyk.into();
+        let namespace = opts.ssh_namespace.as_ref().map(String::as_str).unwrap_or(sshsig::DEFAULT_NAMESPACE);
+        let sshsig = sshsig::sign(sign_json.as_bytes(), &sk, &pk, namespace);
+        info!(format = "ssh", namespace = %namespace, files = manifest.files.len(), "signature created");
+        pb.finish_and_clear();
+        return Ok(Signed { manifest: sign_json, signature: Some(sshsig), signature_suffix: ".sshsig" });
+    }
+
+    pb.set_message("Signing JSON Manifest...");
+
+    // get the JSON signature
+    let signing_input = manifest::signing_input(&manifest.context, sign_json.clone());
+    let Signature(raw_sig) = sign::sign_detached(signing_input.as_bytes(), &sk);
+    let signature = format!("{}.sig.ed25519", encode_config(&raw_sig.to_vec(), URL_SAFE));
+
+    // get the pkid
+    let pk: PublicKey = verifyk.into();
+    let pkid = identity::pkid_from_public_key(&pk);
+
+    info!(format = if format == Format::Ndjson { "ndjson" } else { "manifest" },
+          files = manifest.files.len(), detached = detached, "signature created");
+
+    // timestamp the signature
+    if let Some(ref url) = opts.tsa {
+        pb.set_message("Timestamping signature...");
+        manifest.timestamp = Some(timestamp(url, &raw_sig)?);
+        info!(tsa = %url, "signature timestamped");
+    }
+
+    // a pkid given literally has no name to record
+    if opts.include_signer_name {
+        let name = match pkid_or_alias {
+            Some(ref poa) if identity::verify_key_from_pkid(poa).is_ok() => None,
+            Some(ref alias) => Some(alias.to_owned()),
+            None => Some("default".to_string())
+        };
+        if let Some(name) = name {
+            manifest.signer_names.push((pkid.clone(), name));
+        }
+    }
 
     // create the final JSON
-    let mut final_json = json.to_owned();
-    final_json.push_str(",\n  \"signatures\": {\n");
-    let sig = format!("    \"{}\": \"{}\"", pkid, signature);
-    final_json.push_str(&sig);
-    final_json.push_str("\n  }\n}");
+    manifest.signatures.push((pkid, signature));
+    let signed = if detached {
+        Signed {
+            manifest: sign_json,
+            signature: Some(manifest.signatures_json()),
+            signature_suffix: ".sig"
+        }
+    } else if format == Format::Ndjson {
+        Signed { manifest: manifest.to_ndjson(), signature: None, signature_suffix: "" }
+    } else if opts.compact {
+        Signed { manifest: manifest.to_compact_json(), signature: None, signature_suffix: "" }
+    } else {
+        Signed { manifest: manifest.to_json(), signature: None, signature_suffix: "" }
+    };
     
     pb.set_message("Done.");
     
     pb.finish_and_clear();
 
-    Ok(final_json)
+    Ok(signed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use std::str::FromStr;
+
+    // signs without a DIDDir, for options that are rejected before the key
+    // is loaded
+    fn sign_error(opts: &SignOptions, files: Vec<PathBuf>) -> Error {
+        match sign(false, &None, &None, &None, opts, files) {
+            Err(e) => e,
+            Ok(_) => panic!("signing should have failed")
+        }
+    }
+
+    #[test]
+    fn age_keys_have_no_signer_name() {
+        let opts = SignOptions {
+            include_signer_name: true,
+            age_key: Some(PathBuf::from("key.age")),
+            ..Default::default()
+        };
+        assert_eq!(sign_error(&opts, Vec::new()),
+                   Error::Syntax("an age-encrypted key isn't picked by an alias, it has no signer name".to_string()));
+    }
+
+    #[test]
+    fn refuses_a_disallowed_algorithm() {
+        let opts = SignOptions {
+            algorithm: AlgorithmChoice::Fixed(Algorithm::Blake3),
+            allowed_algorithms: Some(AlgorithmSet::from_str("sha512_256").unwrap()),
+            ..Default::default()
+        };
+        assert_eq!(sign_error(&opts, Vec::new()),
+                   Error::InvalidMeta("the blake3 digest algorithm is not allowed".to_string()));
+    }
+
+    // a root with a file in it next to a directory outside of it
+    fn root_and_outside(name: &str) -> (PathBuf, PathBuf) {
+        let dir = std::fs::canonicalize(testing::scratch_dir(name)).unwrap();
+        testing::write(&dir.join("root").join("a").join("file"), b"inside");
+        testing::write(&dir.join("outside").join("secret"), b"outside");
+        (dir.join("root"), dir.join("outside"))
+    }
+
+    fn assert_outside(root: &Path, file: &str) {
+        match resolve_under_root(root, vec![PathBuf::from(file)]) {
+            Err(Error::Syntax(msg)) => assert!(msg.contains("is outside of the root"), "{}", msg),
+            Err(e) => panic!("unexpected error for {}: {}", file, e),
+            Ok(paths) => panic!("{} escaped the root as {:?}", file, paths)
+        }
+    }
+
+    #[test]
+    fn dot_dot_stays_under_the_root() {
+        let (root, outside) = root_and_outside("root-dot-dot");
+        let inside = vec![PathBuf::from("a/../a/./file"), root.join("a").join("..").join("a")];
+        assert_eq!(resolve_under_root(&root, inside).unwrap(), vec![root.join("a").join("file"), root.join("a")]);
+
+        assert_outside(&root, "..");
+        assert_outside(&root, "../outside/secret");
+        assert_outside(&root, "a/../../outside/secret");
+        assert_outside(&root, &root.join("..").join("outside").join("secret").to_string_lossy());
+        assert_outside(&root, &outside.join("secret").to_string_lossy());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinks_cannot_leave_the_root() {
+        use std::os::unix::fs::symlink;
+
+        let (root, outside) = root_and_outside("root-symlinks");
+        symlink(outside.join("secret"), root.join("file-link")).unwrap();
+        symlink(&outside, root.join("dir-link")).unwrap();
+        symlink("../../outside", root.join("a").join("relative-link")).unwrap();
+        symlink(root.join("a").join("file"), root.join("inside-link")).unwrap();
+
+        assert_outside(&root, "file-link");
+        assert_outside(&root, "dir-link");
+        assert_outside(&root, "dir-link/secret");
+        assert_outside(&root, "a/relative-link/secret");
+        assert_eq!(resolve_under_root(&root, vec![PathBuf::from("inside-link")]).unwrap(),
+                   vec![root.join("inside-link")]);
+
+        // a link found while hashing the root fails the run before it is read
+        let hashed = Hasher::new()
+            .progress(false)
+            .symlink_mode(SymlinkMode::Content)
+            .root(Some(root.to_path_buf()))
+            .hash(vec![root.to_path_buf()]);
+        match hashed {
+            Err(Error::IoError(msg)) => {
+                assert_eq!(msg, format!("{} points outside of the root {}", root.join("file-link").display(),
+                                        root.display()))
+            },
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => panic!("the link out of the root was followed")
+        }
+    }
+
+    #[test]
+    fn the_root_must_be_a_directory() {
+        let (root, _) = root_and_outside("root-not-dir");
+        let opts = |root: PathBuf| SignOptions { root: Some(root), ..Default::default() };
+        assert_eq!(canonical_root(&opts(root.join("a").join("..").join("a"))).unwrap(), Some(root.join("a")));
+        match canonical_root(&opts(root.join("a").join("file"))) {
+            Err(Error::Syntax(msg)) => assert!(msg.ends_with("is not a directory"), "{}", msg),
+            other => panic!("unexpected result: {:?}", other)
+        }
+        match canonical_root(&opts(root.join("missing"))) {
+            Err(Error::IoError(_)) => {},
+            other => panic!("unexpected result: {:?}", other)
+        }
+    }
 }
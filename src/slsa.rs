@@ -0,0 +1,77 @@
+use base64::encode;
+use crate::Result;
+use crate::fs::Hash;
+use crate::manifest;
+use serde_json::{self, json, Value as JsonValue};
+use sodiumoxide::crypto::sign::{self, SecretKey, Signature};
+
+static PAYLOAD_TYPE: &'static str = "application/vnd.in-toto+json";
+static STATEMENT_TYPE: &'static str = "https://in-toto.io/Statement/v1";
+static PREDICATE_TYPE: &'static str = "https://slsa.dev/provenance/v1";
+static BUILD_TYPE: &'static str = "https://github.com/dhuseby/bs/sign@v1";
+static BUILDER_ID: &'static str = "https://github.com/dhuseby/bs";
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Builds an in-toto Statement with a SLSA provenance predicate where each
+/// hashed file is a subject.
+pub fn statement(hashes: &[Hash]) -> Result<JsonValue> {
+    let mut subjects = Vec::new();
+    for hash in hashes {
+        let name = manifest::manifest_path(&hash.path)?;
+        subjects.push(json!({
+            "name": name,
+            "digest": { hash.algorithm.name(): to_hex(&hash.hash) }
+        }));
+    }
+
+    Ok(json!({
+        "_type": STATEMENT_TYPE,
+        "subject": subjects,
+        "predicateType": PREDICATE_TYPE,
+        "predicate": {
+            "buildDefinition": {
+                "buildType": BUILD_TYPE,
+                "externalParameters": {},
+                "internalParameters": {},
+                "resolvedDependencies": []
+            },
+            "runDetails": {
+                "builder": {
+                    "id": BUILDER_ID,
+                    "version": { "bs": env!("CARGO_PKG_VERSION") }
+                },
+                "metadata": {}
+            }
+        }
+    }))
+}
+
+/// The DSSE pre-authentication encoding of the payload, these are the bytes
+/// that actually get signed.
+pub fn pae(payload_type: &str, payload: &[u8]) -> Vec<u8> {
+    let mut buf = format!("DSSEv1 {} {} {} ",
+                          payload_type.len(), payload_type, payload.len()).into_bytes();
+    buf.extend_from_slice(payload);
+    buf
+}
+
+/// Serializes the statement and wraps it in a DSSE envelope signed with the
+/// given ed25519 key. The keyid is the signer's pkid.
+pub fn envelope(statement: &JsonValue, keyid: &str, sk: &SecretKey) -> Result<String> {
+    let payload = serde_json::to_vec(statement)?;
+    let sig = sign::sign_detached(&pae(PAYLOAD_TYPE, &payload), sk);
+    let Signature(ref sb) = sig;
+
+    let env = json!({
+        "payloadType": PAYLOAD_TYPE,
+        "payload": encode(&payload),
+        "signatures": [
+            { "keyid": keyid, "sig": encode(&sb.to_vec()) }
+        ]
+    });
+
+    Ok(serde_json::to_string_pretty(&env)?)
+}
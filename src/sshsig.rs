@@ -0,0 +1,142 @@
+use base64::encode;
+use sha2::{Digest, Sha512};
+use sodiumoxide::crypto::sign::{self, PublicKey, SecretKey, Signature};
+
+static MAGIC: &'static [u8] = b"SSHSIG";
+static KEY_TYPE: &'static str = "ssh-ed25519";
+static HASH_ALGORITHM: &'static str = "sha512";
+const SIG_VERSION: u32 = 1;
+
+/// The namespace signatures are made in unless another one is given, it is
+/// the one `ssh-keygen -Y sign` suggests for files.
+pub static DEFAULT_NAMESPACE: &'static str = "file";
+
+// ssh wire strings are prefixed with their length as a big endian u32
+fn put_string(buf: &mut Vec<u8>, data: &[u8]) {
+    buf.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    buf.extend_from_slice(data);
+}
+
+fn public_key_blob(pk: &PublicKey) -> Vec<u8> {
+    let PublicKey(ref pkb) = *pk;
+    let mut blob = Vec::new();
+    put_string(&mut blob, KEY_TYPE.as_bytes());
+    put_string(&mut blob, pkb);
+    blob
+}
+
+/// The key as an OpenSSH public key line, the comment is usually the pkid.
+/// Prefixed with a principal it is an allowed signers line for
+/// `ssh-keygen -Y verify`.
+pub fn public_key(pk: &PublicKey, comment: &str) -> String {
+    format!("{} {} {}\n", KEY_TYPE, encode(&public_key_blob(pk)), comment)
+}
+
+/// Creates an armored SSHSIG signature over the message in the namespace,
+/// the message is hashed with SHA-512 first the way OpenSSH does it.
+pub fn sign(msg: &[u8], sk: &SecretKey, pk: &PublicKey, namespace: &str) -> String {
+    // the signed data names the namespace so a signature can't be reused in
+    // another one
+    let mut signed = MAGIC.to_vec();
+    put_string(&mut signed, namespace.as_bytes());
+    put_string(&mut signed, b"");
+    put_string(&mut signed, HASH_ALGORITHM.as_bytes());
+    put_string(&mut signed, &Sha512::digest(msg));
+    let Signature(sig) = sign::sign_detached(&signed, sk);
+
+    let mut sig_blob = Vec::new();
+    put_string(&mut sig_blob, KEY_TYPE.as_bytes());
+    put_string(&mut sig_blob, &sig);
+
+    let mut blob = MAGIC.to_vec();
+    blob.extend_from_slice(&SIG_VERSION.to_be_bytes());
+    put_string(&mut blob, &public_key_blob(pk));
+    put_string(&mut blob, namespace.as_bytes());
+    put_string(&mut blob, b"");
+    put_string(&mut blob, HASH_ALGORITHM.as_bytes());
+    put_string(&mut blob, &sig_blob);
+
+    // armored like ssh-keygen writes it, 70 characters per line
+    let encoded = encode(&blob);
+    let lines: Vec<&str> = encoded.as_bytes().chunks(70).map(|l| std::str::from_utf8(l).unwrap_or("")).collect();
+    format!("-----BEGIN SSH SIGNATURE-----\n{}\n-----END SSH SIGNATURE-----\n", lines.join("\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use std::fs::File;
+    use std::io;
+    use std::path::Path;
+    use std::process::{Command, Stdio};
+
+    static MANIFEST: &'static [u8] = b"{\"files\": []}\n";
+
+    fn unarmor(armored: &str) -> Vec<u8> {
+        let lines: Vec<&str> = armored.lines().collect();
+        assert_eq!(lines.first(), Some(&"-----BEGIN SSH SIGNATURE-----"));
+        assert_eq!(lines.last(), Some(&"-----END SSH SIGNATURE-----"));
+        assert!(lines.iter().all(|line| line.len() <= 70));
+        base64::decode(&lines[1..lines.len() - 1].concat()).unwrap()
+    }
+
+    #[test]
+    fn signatures_are_sshsig_blobs() {
+        let (pk, sk) = sign::gen_keypair();
+        let blob = unarmor(&sign(MANIFEST, &sk, &pk, DEFAULT_NAMESPACE));
+
+        let mut expected = MAGIC.to_vec();
+        expected.extend_from_slice(&SIG_VERSION.to_be_bytes());
+        put_string(&mut expected, &public_key_blob(&pk));
+        put_string(&mut expected, b"file");
+        put_string(&mut expected, b"");
+        put_string(&mut expected, b"sha512");
+        assert!(blob.starts_with(&expected));
+
+        // the rest is the signature over the namespaced digest
+        let mut signed = MAGIC.to_vec();
+        put_string(&mut signed, b"file");
+        put_string(&mut signed, b"");
+        put_string(&mut signed, b"sha512");
+        put_string(&mut signed, &Sha512::digest(MANIFEST));
+        let sig_blob = &blob[expected.len() + 4..];
+        let sig = Signature::from_slice(&sig_blob[sig_blob.len() - 64..]).unwrap();
+        assert!(sign::verify_detached(&sig, &signed, &pk));
+        assert!(!sign::verify_detached(&sig, MANIFEST, &pk));
+    }
+
+    // runs ssh-keygen -Y verify on the message, None if it isn't installed
+    fn ssh_keygen_verify(dir: &Path, msg: &[u8], namespace: &str) -> Option<bool> {
+        testing::write(&dir.join("msg"), msg);
+        let output = Command::new("ssh-keygen")
+            .args(&["-Y", "verify", "-f", "allowed_signers", "-I", "bs-test", "-n", namespace, "-s", "msg.sshsig"])
+            .current_dir(dir)
+            .stdin(Stdio::from(File::open(dir.join("msg")).unwrap()))
+            .output();
+        match output {
+            Ok(output) => Some(output.status.success()),
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => None,
+            Err(err) => panic!("ssh-keygen could not be run: {}", err)
+        }
+    }
+
+    #[test]
+    fn ssh_keygen_verifies_signatures() {
+        let dir = testing::scratch_dir("sshsig");
+        let (pk, sk) = sign::gen_keypair();
+        let allowed = format!("bs-test {}", public_key(&pk, "@pkid"));
+        testing::write(&dir.join("allowed_signers"), allowed.as_bytes());
+        testing::write(&dir.join("msg.sshsig"), sign(MANIFEST, &sk, &pk, DEFAULT_NAMESPACE).as_bytes());
+
+        match ssh_keygen_verify(&dir, MANIFEST, DEFAULT_NAMESPACE) {
+            Some(verified) => assert!(verified, "ssh-keygen rejected the signature"),
+            None => {
+                eprintln!("ssh-keygen isn't installed, skipping the interop test");
+                return;
+            }
+        }
+        assert_eq!(ssh_keygen_verify(&dir, b"{\"files\": [1]}\n", DEFAULT_NAMESPACE), Some(false));
+        assert_eq!(ssh_keygen_verify(&dir, MANIFEST, "git"), Some(false));
+    }
+}
@@ -0,0 +1,107 @@
+use crate::{Error, Result};
+use crate::report::{SignatureStatus, VerifyReport};
+use std::io;
+use std::path::Path;
+
+/// What a trust on first use check found for a source.
+#[derive(Clone, Debug, PartialEq)]
+pub enum TofuStatus {
+    /// The source wasn't in the store, its signer is now.
+    FirstSeen(String),
+    /// The manifest is signed by the signer recorded for the source.
+    Matched(String),
+}
+
+/// Sources are single words so they fit in the store file.
+pub fn check_source(source: &str) -> Result<()> {
+    if source.is_empty() || source.contains(char::is_whitespace) || source.starts_with('#') {
+        return Err(Error::Syntax(format!("not a valid TOFU source: \"{}\"", source)));
+    }
+    Ok(())
+}
+
+/// The first signer seen for each source. The store file has one
+/// "<source> <pkid>" line per source, blank lines and lines starting with
+/// "#" are ignored.
+#[derive(Clone, Debug, Default)]
+pub struct TofuStore {
+    entries: Vec<(String, String)>,
+    changed: bool,
+}
+
+impl TofuStore {
+    /// Loads the store, a missing file is an empty store.
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = match std::fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(TofuStore::default()),
+            Err(e) => return Err(From::from(e))
+        };
+        let mut entries = Vec::new();
+        for (i, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut fields = line.split_whitespace();
+            match (fields.next(), fields.next(), fields.next()) {
+                (Some(source), Some(pkid), None) => entries.push((source.to_string(), pkid.to_string())),
+                _ => {
+                    return Err(Error::InvalidMeta(format!("{} line {} is not \"<source> <pkid>\"",
+                                                          path.display(), i + 1)));
+                }
+            }
+        }
+        Ok(TofuStore { entries: entries, changed: false })
+    }
+
+    /// The signer recorded for the source.
+    pub fn get(&self, source: &str) -> Option<&str> {
+        self.entries.iter().find(|(s, _)| s == source).map(|(_, pkid)| pkid.as_str())
+    }
+
+    /// Checks the valid signers of a verified manifest against the one
+    /// recorded for the source, recording the first valid signer if there is
+    /// none yet. A manifest without a signature from the recorded signer is
+    /// an error, its key may have been substituted.
+    pub fn check(&mut self, source: &str, report: &VerifyReport) -> Result<TofuStatus> {
+        check_source(source)?;
+        let signers: Vec<&str> = report.signatures.iter()
+            .filter(|s| s.status == SignatureStatus::Valid)
+            .map(|s| s.signer.as_str())
+            .collect();
+
+        match self.get(source) {
+            Some(pkid) if signers.contains(&pkid) => Ok(TofuStatus::Matched(pkid.to_string())),
+            Some(pkid) => {
+                Err(Error::SignatureInvalid(format!(
+                    "the signer for {} changed from {} to {}; the key may have been substituted, if it \
+                     was rotated on purpose remove {} from the TOFU store", source, pkid,
+                     signers.join(", "), source)))
+            },
+            None => {
+                let first = match signers.first() {
+                    Some(first) => first.to_string(),
+                    None => return Err(Error::SignatureInvalid("no valid signature to trust on first use".to_string()))
+                };
+                self.entries.push((source.to_string(), first.clone()));
+                self.changed = true;
+                Ok(TofuStatus::FirstSeen(first))
+            }
+        }
+    }
+
+    /// Whether a source was recorded since the store was loaded.
+    pub fn is_changed(&self) -> bool {
+        self.changed
+    }
+
+    /// The store file's contents.
+    pub fn contents(&self) -> String {
+        let mut s = String::new();
+        for (source, pkid) in &self.entries {
+            s.push_str(&format!("{} {}\n", source, pkid));
+        }
+        s
+    }
+}
@@ -0,0 +1,182 @@
+use crate::{Error, Result};
+use reqwest::header::CONTENT_TYPE;
+use sha2::{Digest, Sha256};
+use sodiumoxide::randombytes::randombytes;
+use std::io::Read;
+
+// 2.16.840.1.101.3.4.2.1
+static SHA256_OID: [u8; 9] = [0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x01];
+// 1.2.840.113549.1.7.2
+static SIGNED_DATA_OID: [u8; 9] = [0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x07, 0x02];
+// 1.2.840.113549.1.9.16.1.4
+static TSTINFO_OID: [u8; 11] = [0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x09, 0x10, 0x01, 0x04];
+
+const TAG_BOOLEAN: u8 = 0x01;
+const TAG_INTEGER: u8 = 0x02;
+const TAG_OCTET_STRING: u8 = 0x04;
+const TAG_NULL: u8 = 0x05;
+const TAG_OID: u8 = 0x06;
+const TAG_GENERALIZED_TIME: u8 = 0x18;
+const TAG_SEQUENCE: u8 = 0x30;
+const TAG_SET: u8 = 0x31;
+const TAG_EXPLICIT_0: u8 = 0xa0;
+
+fn invalid(what: &str) -> Error {
+    Error::TimestampInvalid(format!("malformed timestamp: {}", what))
+}
+
+fn der(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut out = vec![tag];
+    let len = content.len();
+    if len < 0x80 {
+        out.push(len as u8);
+    } else {
+        let bytes: Vec<u8> = len.to_be_bytes().iter().cloned().skip_while(|b| *b == 0).collect();
+        out.push(0x80 | bytes.len() as u8);
+        out.extend_from_slice(&bytes);
+    }
+    out.extend_from_slice(content);
+    out
+}
+
+// splits the next DER element off the front of buf into its tag, content and
+// the remaining bytes
+fn parse(buf: &[u8]) -> Result<(u8, &[u8], &[u8])> {
+    if buf.len() < 2 {
+        return Err(invalid("truncated element"));
+    }
+    let (len, hdr) = if buf[1] < 0x80 {
+        (buf[1] as usize, 2)
+    } else {
+        let n = (buf[1] & 0x7f) as usize;
+        if n == 0 || n > 4 || buf.len() < 2 + n {
+            return Err(invalid("bad length"));
+        }
+        let mut len = 0usize;
+        for b in &buf[2..2 + n] {
+            len = (len << 8) | *b as usize;
+        }
+        (len, 2 + n)
+    };
+    if buf.len() < hdr + len {
+        return Err(invalid("truncated element"));
+    }
+    Ok((buf[0], &buf[hdr..hdr + len], &buf[hdr + len..]))
+}
+
+fn expect(buf: &[u8], tag: u8) -> Result<(&[u8], &[u8])> {
+    let (t, content, rest) = parse(buf)?;
+    if t != tag {
+        return Err(invalid(&format!("expected tag {:#04x}, found {:#04x}", tag, t)));
+    }
+    Ok((content, rest))
+}
+
+fn message_imprint(signature: &[u8]) -> Vec<u8> {
+    let mut alg = der(TAG_OID, &SHA256_OID);
+    alg.extend(der(TAG_NULL, &[]));
+    let mut imprint = der(TAG_SEQUENCE, &alg);
+    imprint.extend(der(TAG_OCTET_STRING, Sha256::digest(signature).as_slice()));
+    der(TAG_SEQUENCE, &imprint)
+}
+
+struct TstInfo<'a> {
+    imprint: &'a [u8],
+    gen_time: &'a [u8],
+    nonce: Option<&'a [u8]>,
+}
+
+fn tst_info(token: &[u8]) -> Result<TstInfo> {
+    // ContentInfo with SignedData content
+    let (content_info, _) = expect(token, TAG_SEQUENCE)?;
+    let (oid, rest) = expect(content_info, TAG_OID)?;
+    if oid != &SIGNED_DATA_OID[..] {
+        return Err(invalid("not a CMS SignedData"));
+    }
+    let (content, _) = expect(rest, TAG_EXPLICIT_0)?;
+    let (signed_data, _) = expect(content, TAG_SEQUENCE)?;
+    let (_, rest) = expect(signed_data, TAG_INTEGER)?;
+    let (_, rest) = expect(rest, TAG_SET)?;
+
+    // the encapsulated TSTInfo
+    let (encap, _) = expect(rest, TAG_SEQUENCE)?;
+    let (oid, rest) = expect(encap, TAG_OID)?;
+    if oid != &TSTINFO_OID[..] {
+        return Err(invalid("not a TSTInfo"));
+    }
+    let (content, _) = expect(rest, TAG_EXPLICIT_0)?;
+    let (econtent, _) = expect(content, TAG_OCTET_STRING)?;
+    let (tst, _) = expect(econtent, TAG_SEQUENCE)?;
+    let (_, rest) = expect(tst, TAG_INTEGER)?;
+    let (_, rest) = expect(rest, TAG_OID)?;
+    let (_, _, after) = parse(rest)?;
+    let imprint = &rest[..rest.len() - after.len()];
+    let (_, rest) = expect(after, TAG_INTEGER)?;
+    let (gen_time, mut rest) = expect(rest, TAG_GENERALIZED_TIME)?;
+
+    // skip the optional accuracy and ordering to find the nonce
+    let mut nonce = None;
+    while !rest.is_empty() {
+        let (tag, content, next) = parse(rest)?;
+        if tag == TAG_INTEGER {
+            nonce = Some(content);
+            break;
+        }
+        rest = next;
+    }
+
+    Ok(TstInfo { imprint: imprint, gen_time: gen_time, nonce: nonce })
+}
+
+/// Checks that the RFC 3161 token is a timestamp over the signature and
+/// returns the time it was issued. This does not validate the TSA's CMS
+/// signature or certificate chain, that should be checked with a tool that
+/// trusts the TSA's certificates.
+pub fn check(token: &[u8], signature: &[u8]) -> Result<String> {
+    let info = tst_info(token)?;
+    if info.imprint != message_imprint(signature).as_slice() {
+        return Err(Error::TimestampInvalid("timestamp is not over the signature".to_string()));
+    }
+    Ok(String::from_utf8_lossy(info.gen_time).into_owned())
+}
+
+/// Requests an RFC 3161 timestamp token for the signature from the Time
+/// Stamping Authority at `url`.
+pub fn timestamp(url: &str, signature: &[u8]) -> Result<Vec<u8>> {
+    // a positive, minimally encoded nonce
+    let mut nonce = randombytes(8);
+    nonce[0] = (nonce[0] & 0x7f) | 0x40;
+
+    let mut req = der(TAG_INTEGER, &[1]);
+    req.extend(message_imprint(signature));
+    req.extend(der(TAG_INTEGER, &nonce));
+    req.extend(der(TAG_BOOLEAN, &[0xff]));
+    let req = der(TAG_SEQUENCE, &req);
+
+    let client = reqwest::Client::new();
+    let mut resp = client.post(url)
+        .header(CONTENT_TYPE, "application/timestamp-query")
+        .body(req)
+        .send()?;
+    if !resp.status().is_success() {
+        return Err(Error::TimestampInvalid(format!("TSA returned {}", resp.status())));
+    }
+    let mut body = Vec::new();
+    resp.read_to_end(&mut body)?;
+
+    // TimeStampResp is the status followed by the token
+    let (content, _) = expect(&body, TAG_SEQUENCE)?;
+    let (status_info, rest) = expect(content, TAG_SEQUENCE)?;
+    let (status, _) = expect(status_info, TAG_INTEGER)?;
+    if status != &[0][..] && status != &[1][..] {
+        return Err(Error::TimestampInvalid("TSA rejected the timestamp request".to_string()));
+    }
+    let (_, _, after) = parse(rest)?;
+    let token = rest[..rest.len() - after.len()].to_vec();
+
+    check(&token, signature)?;
+    if tst_info(&token)?.nonce != Some(&nonce[..]) {
+        return Err(Error::TimestampInvalid("timestamp nonce does not match the request".to_string()));
+    }
+    Ok(token)
+}
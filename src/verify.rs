@@ -1,8 +1,1087 @@
-use crate::Result;
+use base64::{decode_config, encode_config, URL_SAFE};
+use crate::{Error, Result};
+use crate::encoding::DigestEncoding;
+use crate::fs::{self, AlgorithmChoice, AlgorithmSet, CancelToken, Compression, EntryKind, Hash, Hashed, Hasher,
+                ProgressTemplate, Sink, Skipped, SymlinkMode, STDIN_PATH};
+use crate::identity::{self, VerifyKey};
+use crate::manifest::{self, Manifest};
+use crate::merkle;
+use crate::minisign;
+use crate::report::{FileReport, FileStatus, SignatureReport, SignatureStatus, VerifyReport};
+use diddir::DIDDir;
+use glob::Pattern;
+use once_cell::sync::Lazy;
+use rayon::prelude::*;
+use regex::Regex;
+use serde_json::json;
+use sodiumoxide::crypto::sign::{self, PublicKey, Signature, PUBLICKEYBYTES, SIGNATUREBYTES};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::io;
 use std::path::PathBuf;
+use tracing::{debug, info, info_span};
 
-pub fn verify(_verbose: bool,
-              _status_fd: &Option<u32>, 
-              _manifest: &PathBuf) -> Result<()> {
+static SIGNATURE_REGEX: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"^(?P<data>[A-Za-z0-9-_=]+)\.sig\.ed25519\n*$").unwrap()
+});
+
+/// The options that control how manifests are verified.
+#[derive(Clone, Default)]
+pub struct VerifyOptions {
+    /// The detached signature file for the manifest.
+    pub sig: Option<PathBuf>,
+    /// A bare 64 byte Ed25519 signature over the manifest bytes, as other
+    /// tools write them. The signer is the one given key.
+    pub sig_raw: Option<PathBuf>,
+    /// A path prefix that is added to every manifest path before hashing.
+    pub prepend: Option<PathBuf>,
+    /// Check the manifest's RFC 3161 timestamp token.
+    pub check_tsa: bool,
+    /// A minisign signature file for the manifest.
+    pub minisig: Option<PathBuf>,
+    /// The minisign public key file to check the minisign signature with.
+    pub minisign_key: Option<PathBuf>,
+    /// The encoding of the manifest digests, guessed per digest if unset.
+    pub digest_encoding: Option<DigestEncoding>,
+    /// Only check the files matching one of these, all of them if empty. The
+    /// signatures are still checked over the whole manifest.
+    pub only: Vec<Pattern>,
+    /// The number of files that may not match the manifest without failing.
+    pub allow_mismatch: usize,
+    /// Reject manifests with digests from algorithms outside of this set.
+    pub allowed_algorithms: Option<AlgorithmSet>,
+    /// The manifest is a signed Merkle root, the files are checked through
+    /// their inclusion proofs.
+    pub merkle: bool,
+    /// The inclusion proofs to check against the Merkle root.
+    pub proof: Option<PathBuf>,
+    /// How the listed files are compressed, they are decompressed before
+    /// hashing to match digests of the uncompressed contents.
+    pub decompress: Compression,
+    /// The context the manifest must have been signed for, it must not have
+    /// one if unset.
+    pub context: Option<String>,
+    /// How the progress is shown while the files are hashed.
+    pub progress_template: ProgressTemplate,
+    /// Find files whose names are stored in another Unicode normalization
+    /// than the NFC the manifest recorded them in.
+    pub normalize_paths: bool,
+    /// Compare the sizes recorded in the manifest first, a file whose size
+    /// differs is reported modified without hashing it.
+    pub size_precheck: bool,
+    /// The manifest path whose contents are read from stdin instead of the
+    /// file, it doesn't have to exist on disk.
+    pub stdin_as: Option<String>,
+    /// A file of revoked pkids, one per line like a keyring. A valid
+    /// signature by one of them doesn't count.
+    pub revoked: Option<PathBuf>,
+    /// Stop hashing as soon as more files fail than are allowed to, the
+    /// files that weren't checked by then are reported as such.
+    pub fail_fast: bool,
+}
+
+fn local_path(opts: &VerifyOptions, path: &str) -> PathBuf {
+    let local = match opts.prepend {
+        Some(ref prefix) => prefix.join(manifest::native_path(path)),
+        None => manifest::native_path(path)
+    };
+    if opts.normalize_paths { fs::find_normalized(&local) } else { local }
+}
+
+// compares the hashes as they come in, with a cancel token the hashing is
+// cancelled once more files failed than are allowed to
+struct CheckingSink<'a> {
+    expected: &'a HashMap<PathBuf, Vec<u8>>,
+    hashed: Hashed,
+    failures: usize,
+    allowed: usize,
+    cancel: Option<CancelToken>,
+}
+
+impl<'a> CheckingSink<'a> {
+    fn failed(&mut self) {
+        self.failures += 1;
+        if let Some(ref cancel) = self.cancel {
+            if self.failures > self.allowed && !cancel.is_cancelled() {
+                info!(failures = self.failures, allowed = self.allowed, "too many files failed, hashing stopped");
+                cancel.cancel();
+            }
+        }
+    }
+}
+
+impl<'a> Sink for CheckingSink<'a> {
+    fn hashed(&mut self, hash: Hash) {
+        if self.expected.get(&hash.path).map_or(false, |d| d[..] != hash.hash[..]) {
+            self.failed();
+        }
+        self.hashed.hashes.push(hash);
+    }
+
+    fn skipped(&mut self, skipped: Skipped) {
+        self.failed();
+        self.hashed.skipped.push(skipped);
+    }
+
+    fn empty_dir(&mut self, _dir: PathBuf) {}
+}
+
+fn check_files(opts: &VerifyOptions, m: &Manifest) -> Result<Vec<FileReport>> {
+    // the whole manifest is rejected for a disallowed algorithm, even if the
+    // file it is for won't be checked
+    if let Some(ref allowed) = opts.allowed_algorithms {
+        for (path, digest) in &m.files {
+            if manifest::sigil_symlink(digest)?.is_none() {
+                allowed.check(manifest::sigil_algorithm(digest)?)
+                    .map_err(|e| Error::InvalidMeta(format!("{} in {}", e, path)))?;
+            }
+        }
+    }
+
+    if let Some(ref stdin_as) = opts.stdin_as {
+        if !m.files.iter().any(|(path, _)| path == stdin_as) {
+            return Err(Error::InvalidMeta(format!("{} is not in the manifest", stdin_as)));
+        }
+        if m.files.iter().any(|(path, _)| path == STDIN_PATH) {
+            return Err(Error::Syntax("the manifest already reads stdin for its \"-\" entry".to_string()));
+        }
+    }
+
+    let sizes: HashMap<&str, &str> = if opts.size_precheck {
+        if m.sizes.is_empty() {
+            debug!("the manifest has no sizes to precheck");
+        }
+        m.sizes.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect()
+    } else {
+        HashMap::new()
+    };
+
+    // group the files by the algorithm they were hashed with, symlinks are
+    // checked by where they point instead and filtered out files not at all
+    let mut by_algorithm = Vec::new();
+    let mut settled: HashMap<PathBuf, FileStatus> = HashMap::new();
+    let mut expected: HashMap<PathBuf, Vec<u8>> = HashMap::new();
+    for (path, digest) in &m.files {
+        if !opts.only.is_empty() && !opts.only.iter().any(|p| p.matches(path)) {
+            settled.insert(local_path(opts, path), FileStatus::Filtered);
+            continue;
+        }
+        if path == STDIN_PATH || opts.stdin_as.as_ref() == Some(path) {
+            // stdin is hashed as it streams in, the prefix doesn't apply to it
+            let algorithm = manifest::sigil_algorithm(digest)?;
+            let stdin = io::stdin();
+            let hashed = Hasher::new()
+                .algorithm(AlgorithmChoice::Fixed(algorithm))
+                .hash_reader(&PathBuf::from(path), stdin.lock());
+            let status = match hashed {
+                Ok(ref h) if h.hash[..] == manifest::sigil_digest(digest, opts.digest_encoding)?[..] => FileStatus::Ok,
+                Ok(_) => FileStatus::Modified,
+                Err(e) => FileStatus::Unreadable(format!("{}", e))
+            };
+            settled.insert(local_path(opts, path), status);
+            continue;
+        }
+        if let Some(target) = manifest::sigil_symlink(digest)? {
+            let local = local_path(opts, path);
+            let status = match std::fs::read_link(&local) {
+                Ok(ref t) if *t == target => FileStatus::Ok,
+                Ok(_) => FileStatus::Modified,
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => FileStatus::Missing,
+                Err(e) => FileStatus::Unreadable(format!("{}", e))
+            };
+            settled.insert(local, status);
+            continue;
+        }
+        if let Some(size) = sizes.get(path.as_str()) {
+            // a file that is missing or unreadable is left for the hashing to
+            // report
+            let local = local_path(opts, path);
+            match std::fs::metadata(&local) {
+                Ok(ref meta) if meta.len() != manifest::parse_size_value(size)? => {
+                    debug!(path = %path, "file size changed");
+                    settled.insert(local, FileStatus::Modified);
+                    continue;
+                },
+                _ => {}
+            }
+        }
+        let local = local_path(opts, path);
+        if opts.fail_fast {
+            // a missing file is never hashed, so it is found before the
+            // hashing can stop without it
+            if let Err(ref e) = local.symlink_metadata() {
+                if e.kind() == io::ErrorKind::NotFound {
+                    settled.insert(local, FileStatus::Missing);
+                    continue;
+                }
+            }
+            expected.insert(local.to_path_buf(), manifest::sigil_digest(digest, opts.digest_encoding)?);
+        }
+        let algorithm = manifest::sigil_algorithm(digest)?;
+        match by_algorithm.iter().position(|(a, _)| *a == algorithm) {
+            Some(i) => by_algorithm[i].1.push(local),
+            None => by_algorithm.push((algorithm, vec![local]))
+        }
+    }
+
+    // the files settled without hashing count towards the first failures
+    let cancel = if opts.fail_fast { Some(CancelToken::new()) } else { None };
+    let mut failures = settled.values().filter(|status| match status {
+        FileStatus::Modified | FileStatus::Missing | FileStatus::Unreadable(_) => true,
+        _ => false
+    }).count();
+
+    // re-hash the listed files
+    let mut hashes: HashMap<PathBuf, Vec<u8>> = HashMap::new();
+    let mut skipped: HashMap<PathBuf, String> = HashMap::new();
+    for (algorithm, paths) in by_algorithm {
+        if let Some(ref cancel) = cancel {
+            if failures > opts.allow_mismatch {
+                cancel.cancel();
+            }
+            if cancel.is_cancelled() {
+                break;
+            }
+        }
+        let mut sink = CheckingSink {
+            expected: &expected,
+            hashed: Hashed::new(Vec::new()),
+            failures: failures,
+            allowed: opts.allow_mismatch,
+            cancel: cancel.clone()
+        };
+        // the recorded symlinks are already checked, anything else that is a
+        // symlink now was hashed through it or replaced a file, either way
+        // its contents are what has to match
+        Hasher::new()
+            .algorithm(AlgorithmChoice::Fixed(algorithm))
+            .symlink_mode(SymlinkMode::Content)
+            .decompress(opts.decompress)
+            .progress_template(opts.progress_template.clone())
+            .cancel(cancel.clone())
+            .hash_into(paths, &mut sink)?;
+        failures = sink.failures;
+        let hashed = sink.hashed;
+        for h in hashed.hashes {
+            hashes.insert(h.path.clone(), h.hash.to_vec());
+        }
+        for s in hashed.skipped {
+            skipped.insert(s.path, s.reason);
+        }
+    }
+
+    // compare them to the digests in the manifest
+    let mut reports = Vec::new();
+    for (path, digest) in &m.files {
+        let local = local_path(opts, path);
+        if let Some(status) = settled.remove(&local) {
+            debug!(path = %path, status = %status, "file checked");
+            reports.push(FileReport { path: path.to_owned(), status: status });
+            continue;
+        }
+        let expected = manifest::sigil_digest(digest, opts.digest_encoding)?;
+        let status = match hashes.get(&local) {
+            Some(d) if *d == expected => FileStatus::Ok,
+            Some(_) => FileStatus::Modified,
+            None if skipped.contains_key(&local) => FileStatus::Unreadable(skipped[&local].clone()),
+            None if cancel.as_ref().map_or(false, |c| c.is_cancelled()) => FileStatus::NotChecked,
+            None => FileStatus::Missing
+        };
+        debug!(path = %path, status = %status, "file checked");
+        reports.push(FileReport { path: path.to_owned(), status: status });
+    }
+    Ok(reports)
+}
+
+// walks the recorded directories, only the entries that don't match the tree
+// are reported since the files are already reported by their digests
+fn check_tree(opts: &VerifyOptions, m: &Manifest) -> Result<Vec<FileReport>> {
+    let recorded: HashMap<&str, &str> = m.tree.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect();
+    let mut reports = Vec::new();
+    for (path, value) in &m.tree {
+        if !opts.only.is_empty() && !opts.only.iter().any(|p| p.matches(path)) {
+            continue;
+        }
+        let (kind, mode) = manifest::parse_tree_value(value)?;
+        let local = local_path(opts, path);
+        let meta = match local.symlink_metadata() {
+            Ok(meta) => meta,
+            // missing files were already reported with the digests
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                if !m.files.iter().any(|(p, _)| p == path) {
+                    reports.push(FileReport { path: path.to_owned(), status: FileStatus::Missing });
+                }
+                continue;
+            },
+            Err(e) => {
+                reports.push(FileReport { path: path.to_owned(), status: FileStatus::Unreadable(format!("{}", e)) });
+                continue;
+            }
+        };
+        let status = if EntryKind::from_meta(&meta) != kind {
+            FileStatus::Modified
+        } else if fs::entry_mode(&meta) != mode {
+            FileStatus::PermissionsChanged(mode, fs::entry_mode(&meta))
+        } else {
+            FileStatus::Ok
+        };
+        let is_dir = status != FileStatus::Modified && kind == EntryKind::Dir;
+        if status != FileStatus::Ok {
+            debug!(path = %path, status = %status, "tree entry checked");
+            reports.push(FileReport { path: path.to_owned(), status: status });
+        }
+
+        // anything in a recorded directory that isn't in the tree was added
+        if is_dir {
+            for entry in local.read_dir()? {
+                let child = manifest::manifest_path(&manifest::native_path(path).join(entry?.file_name()))?;
+                let child = child.as_str();
+                if !recorded.contains_key(child) {
+                    debug!(path = %child, "tree entry added");
+                    reports.push(FileReport { path: child.to_string(), status: FileStatus::Added });
+                }
+            }
+        }
+    }
+    Ok(reports)
+}
+
+// a file whose contents match but whose permission bits changed is reported
+// with the new ones, the modes can't be checked off unix
+#[cfg(unix)]
+fn check_modes(opts: &VerifyOptions, m: &Manifest, files: &mut Vec<FileReport>) -> Result<()> {
+    let recorded: HashMap<&str, &str> = m.modes.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect();
+    for report in files.iter_mut() {
+        // piped contents have no mode to check
+        if report.status != FileStatus::Ok || opts.stdin_as.as_ref() == Some(&report.path) {
+            continue;
+        }
+        if let Some(value) = recorded.get(report.path.as_str()) {
+            let mode = manifest::parse_mode_value(value)?;
+            let current = fs::entry_mode(&std::fs::metadata(local_path(opts, &report.path))?);
+            if current != mode {
+                debug!(path = %report.path, "file mode changed");
+                report.status = FileStatus::PermissionsChanged(mode, current);
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn check_modes(_opts: &VerifyOptions, m: &Manifest, _files: &mut Vec<FileReport>) -> Result<()> {
+    if !m.modes.is_empty() {
+        tracing::warn!("file modes can't be checked on this platform");
+    }
+    Ok(())
+}
+
+// the recorded empty directories have to still be there and be empty
+fn check_dirs(opts: &VerifyOptions, m: &Manifest) -> Result<Vec<FileReport>> {
+    let mut reports = Vec::new();
+    for dir in &m.dirs {
+        if !opts.only.is_empty() && !opts.only.iter().any(|p| p.matches(dir)) {
+            continue;
+        }
+        let local = local_path(opts, dir);
+        match local.symlink_metadata() {
+            Ok(ref meta) if meta.is_dir() => {
+                for entry in local.read_dir()? {
+                    let child = manifest::manifest_path(&manifest::native_path(dir).join(entry?.file_name()))?;
+                    debug!(path = %child, "entry added to an empty directory");
+                    reports.push(FileReport { path: child, status: FileStatus::Added });
+                }
+            },
+            Ok(_) => reports.push(FileReport { path: dir.to_owned(), status: FileStatus::Modified }),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                reports.push(FileReport { path: dir.to_owned(), status: FileStatus::Missing });
+            },
+            Err(e) => reports.push(FileReport { path: dir.to_owned(), status: FileStatus::Unreadable(format!("{}", e)) })
+        }
+    }
+    Ok(reports)
+}
+
+// every file, recorded error and empty directory has to match one of the
+// patterns of the signed policy
+fn check_policy(m: &Manifest) -> Result<Vec<FileReport>> {
+    if m.policy.is_empty() {
+        return Ok(Vec::new());
+    }
+    let mut patterns = Vec::new();
+    for pattern in &m.policy {
+        match Pattern::new(pattern) {
+            Ok(pattern) => patterns.push(pattern),
+            Err(e) => return Err(Error::InvalidMeta(format!("the policy pattern {} is invalid: {}", pattern, e)))
+        }
+    }
+    let paths = m.files.iter().map(|(path, _)| path).chain(m.errors.iter().map(|(path, _)| path)).chain(m.dirs.iter());
+    Ok(paths
+        .filter(|path| !patterns.iter().any(|p| p.matches(path)))
+        .map(|path| {
+            debug!(path = %path, "entry outside the signed policy");
+            FileReport { path: path.to_owned(), status: FileStatus::OutsidePolicy }
+        })
+        .collect())
+}
+
+fn decode_signature(sig: &str) -> Result<Signature> {
+    // 1. use regex to extract the base64 encoded signature
+    let re = &*SIGNATURE_REGEX;
+    if !re.is_match(sig) {
+        return Err(Error::SignatureInvalid("not an ed25519 signature, the sigil must be .sig.ed25519".to_string()));
+    }
+    let caps = re.captures(sig)?;
+    let data = caps.name("data")?;
+
+    // 2. decode the base64 into a signature, its length is checked here so
+    // a signature of another type fails clearly instead of in libsodium
+    let data = decode_config(data.as_str(), URL_SAFE)?;
+    if data.len() != SIGNATUREBYTES {
+        return Err(Error::SignatureInvalid(format!("the ed25519 signature is {} bytes instead of {}",
+                                                   data.len(), SIGNATUREBYTES)));
+    }
+    Ok(Signature::from_slice(&data)?)
+}
+
+// a raw signature has no signer of its own, it is the one given key
+fn raw_signature(key: &Option<String>, path: &PathBuf) -> Result<(String, String)> {
+    let keyring = match key {
+        Some(key) => identity::parse_keyring(key, false)?,
+        None => return Err(Error::Syntax("a raw signature needs the signer's key".to_string()))
+    };
+    if keyring.len() != 1 {
+        return Err(Error::Syntax("a raw signature is checked with a single key, not a keyring".to_string()));
+    }
+    let sig = std::fs::read(path)?;
+    if sig.len() != SIGNATUREBYTES {
+        return Err(Error::SignatureInvalid(format!("{} is {} bytes, a raw ed25519 signature is {}",
+                                                   path.display(), sig.len(), SIGNATUREBYTES)));
+    }
+    Ok((keyring[0].1.pkid(), format!("{}.sig.ed25519", encode_config(&sig, URL_SAFE))))
+}
+
+// the signer's key gets the same checks as the signature
+fn decode_signer(pkid: &str) -> Result<PublicKey> {
+    let pkid = pkid.trim_end_matches('\n');
+    if !pkid.starts_with('@') || !pkid.ends_with(".ed25519") {
+        return Err(Error::SignatureInvalid(format!("the signer {} is not an @<key>.ed25519 pkid", pkid)));
+    }
+    let data = decode_config(&pkid[1..pkid.len() - ".ed25519".len()], URL_SAFE)?;
+    if data.len() != PUBLICKEYBYTES {
+        return Err(Error::SignatureInvalid(format!("the signer's ed25519 key is {} bytes instead of {}",
+                                                   data.len(), PUBLICKEYBYTES)));
+    }
+    Ok(VerifyKey::from_bytes(&data)?.into())
+}
+
+fn check_signature(signed: &[u8], pkid: &String, sig: &str) -> Result<bool> {
+    let sig = decode_signature(sig)?;
+    let pk = decode_signer(pkid)?;
+    Ok(sign::verify_detached(&sig, signed, &pk))
+}
+
+// the revocation list is laid out like a keyring but may be empty
+fn revoked_keys(opts: &VerifyOptions) -> Result<Vec<VerifyKey>> {
+    let path = match opts.revoked {
+        Some(ref path) => path,
+        None => return Ok(Vec::new())
+    };
+    match identity::parse_keyring(&std::fs::read_to_string(path)?, true) {
+        Ok(keys) => Ok(keys.into_iter().map(|(_, vk)| vk).collect()),
+        Err(e) => Err(Error::InvalidEncoding(format!("{}: {}", path.display(), e)))
+    }
+}
+
+fn check_signatures(kdroot: &Option<String>,
+                    key: &Option<String>,
+                    opts: &VerifyOptions,
+                    signatures: &[(String, String)],
+                    names: &[(String, String)],
+                    signed: &[u8]) -> Result<Vec<SignatureReport>> {
+
+    if signatures.is_empty() {
+        return Ok(Vec::new());
+    }
+    let revoked = revoked_keys(opts)?;
+
+    // signers are trusted if they are in the given keyring or, without one,
+    // if their identity is in the DIDDir
+    let (keyring, diddir) = match key {
+        Some(k) => (identity::parse_keyring(k, false)?, None),
+        None => (Vec::new(), Some(DIDDir::open_or_init(&identity::get_config(kdroot)?)?))
+    };
+
+    let mut reports = Vec::new();
+    for (pkid, sig) in signatures {
+        // the keys are compared rather than the pkids so any base64 padding
+        // matches
+        let keyring_line = identity::verify_key_from_pkid(pkid).ok().and_then(|vk| {
+            keyring.iter().find(|(_, k)| k.as_bytes() == vk.as_bytes()).map(|(line, _)| *line)
+        });
+        let trusted = match (keyring_line, &diddir) {
+            (Some(_), _) => Ok(()),
+            (None, Some(dd)) => dd.get_identity(pkid).map(|_| ()).map_err(|_| SignatureStatus::UnknownSigner),
+            (None, None) => Err(SignatureStatus::Untrusted)
+        };
+        let is_revoked = identity::verify_key_from_pkid(pkid).map_or(false, |vk| {
+            revoked.iter().any(|k| k.as_bytes() == vk.as_bytes())
+        });
+        let status = if let Err(status) = trusted {
+            status
+        } else if check_signature(signed, pkid, sig)? {
+            // revoked keys are still checked so a forged signature is
+            // reported as invalid rather than revoked
+            if is_revoked { SignatureStatus::Revoked } else { SignatureStatus::Valid }
+        } else {
+            SignatureStatus::Invalid
+        };
+        debug!(signer = %pkid, status = ?status, "signature checked");
+        let name = names.iter().find(|(k, _)| k == pkid).map(|(_, v)| v.to_owned());
+        reports.push(SignatureReport { signer: pkid.to_owned(), name: name, keyring_line: keyring_line, status: status });
+    }
+    Ok(reports)
+}
+
+#[cfg(feature = "tsa")]
+fn check_timestamp(m: &Manifest) -> Result<String> {
+    let token = match m.timestamp {
+        Some(ref token) => decode_config(token, URL_SAFE)?,
+        None => return Err(Error::TimestampInvalid("manifest has no timestamp".to_string()))
+    };
+
+    // the timestamp must be over one of the signatures
+    for (_, sig) in &m.signatures {
+        let Signature(ref sb) = decode_signature(sig)?;
+        if let Ok(gen_time) = crate::tsa::check(&token, sb) {
+            return Ok(gen_time);
+        }
+    }
+    Err(Error::TimestampInvalid("timestamp is not over any of the signatures".to_string()))
+}
+
+#[cfg(not(feature = "tsa"))]
+fn check_timestamp(_m: &Manifest) -> Result<String> {
+    Err(Error::Syntax("bs was built without the \"tsa\" feature".to_string()))
+}
+
+#[cfg(feature = "remote")]
+fn fetch(url: &str) -> Result<String> {
+    crate::remote::fetch(url)
+}
+
+#[cfg(not(feature = "remote"))]
+fn fetch(_url: &str) -> Result<String> {
+    Err(Error::Syntax("bs was built without the \"remote\" feature".to_string()))
+}
+
+fn read_manifest(manifest: &PathBuf) -> Result<String> {
+    match manifest.to_str() {
+        Some(url) if url.starts_with("https://") || url.starts_with("http://") => fetch(url),
+        _ => Ok(std::fs::read_to_string(manifest)?)
+    }
+}
+
+fn describe_context(context: &Option<String>) -> String {
+    match context {
+        Some(context) => format!("the context \"{}\"", context),
+        None => "no context".to_string()
+    }
+}
+
+// returns the manifest, the bytes that were signed and whether the manifest
+// is laid out the way bs writes it, an embedded signature is checked against
+// the rebuilt bytes so a reformatted manifest fails even if nothing changed
+fn load(opts: &VerifyOptions, manifest: &PathBuf) -> Result<(Manifest, String, bool)> {
+    // load the manifest and figure out which bytes were signed
+    let contents = read_manifest(manifest)?;
+    let ndjson = manifest::is_ndjson(&contents);
+    let mut m = if ndjson { manifest::parse_ndjson(&contents)? } else { Manifest::try_from(contents.as_str())? };
+    let (signed, canonical) = match opts.sig {
+        Some(ref path) => {
+            // a detached signature covers the manifest bytes exactly
+            if !m.signatures.is_empty() {
+                return Err(Error::InvalidMeta("manifest has both embedded and detached signatures".to_string()));
+            }
+            let (signatures, timestamp, names) = manifest::detached_signatures(&std::fs::read_to_string(path)?)?;
+            m.signatures = signatures;
+            m.timestamp = timestamp;
+            m.signer_names = names;
+            (contents, true)
+        },
+        // the raw signature is added once the signer's key is known and a
+        // minisign signature is checked on its own
+        None if opts.sig_raw.is_some() || opts.minisig.is_some() => {
+            if !m.signatures.is_empty() {
+                return Err(Error::InvalidMeta("manifest has both embedded and detached signatures".to_string()));
+            }
+            (contents, true)
+        },
+        None if ndjson => {
+            let canonical = contents == m.to_ndjson();
+            (m.ndjson_signed_bytes(), canonical)
+        },
+        None => {
+            // the compact form is just another presentation of the same
+            // signed bytes
+            let canonical = contents.trim_end() == m.to_json() || contents.trim_end() == m.to_compact_json();
+            (m.signed_bytes(), canonical)
+        }
+    };
+    // a minisign signature is over the manifest bytes as they are, the
+    // context is only checked against the manifest
+    let signed = if opts.minisig.is_some() { signed } else { manifest::signing_input(&m.context, signed) };
+    Ok((m, signed, canonical))
+}
+
+// a minisign signature is trusted through the given minisign public key
+// instead of the identities in the DIDDir
+fn check_minisig(opts: &VerifyOptions, minisig: &PathBuf, signed: &[u8]) -> Result<SignatureReport> {
+    let public_key = match opts.minisign_key {
+        Some(ref path) => std::fs::read_to_string(path)?,
+        None => return Err(Error::Syntax("a minisign signature needs a minisign public key".to_string()))
+    };
+    let status = match minisign::verify(signed, &std::fs::read_to_string(minisig)?, &public_key) {
+        Ok(_) => SignatureStatus::Valid,
+        Err(Error::SignatureInvalid(_)) => SignatureStatus::Invalid,
+        Err(e) => return Err(e)
+    };
+    Ok(SignatureReport {
+        signer: format!("{}", minisig.display()),
+        name: None,
+        keyring_line: None,
+        status: status
+    })
+}
+
+/// Loads the manifest the way verifying does, with the signatures from the
+/// detached signature file if there is one.
+pub fn load_manifest(opts: &VerifyOptions, manifest: &PathBuf) -> Result<Manifest> {
+    let (m, _, _) = load(opts, manifest)?;
+    Ok(m)
+}
+
+/// The pkids of the manifest's signers in the order they signed, without
+/// verifying anything. Each one is checked to be an ed25519 key so the list
+/// can go straight into a keyring.
+pub fn signers(opts: &VerifyOptions, manifest: &PathBuf) -> Result<Vec<String>> {
+    let m = load_manifest(opts, manifest)?;
+    let mut pkids: Vec<String> = Vec::new();
+    for (pkid, _) in &m.signatures {
+        decode_signer(pkid)?;
+        if !pkids.contains(pkid) {
+            pkids.push(pkid.to_owned());
+        }
+    }
+    Ok(pkids)
+}
+
+/// The signers as a JSON list of pkids.
+pub fn signers_json(pkids: &[String]) -> String {
+    format!("{:#}\n", json!(pkids))
+}
+
+// a digest sigil has to name a known algorithm and decode to a 32 byte digest
+fn check_digest_sigil(sigil: &str, encoding: Option<DigestEncoding>) -> Result<()> {
+    manifest::sigil_algorithm(sigil)?;
+    let digest = manifest::sigil_digest(sigil, encoding)?;
+    if digest.len() != 32 {
+        return Err(Error::InvalidEncoding(format!("the digest is {} bytes instead of 32", digest.len())));
+    }
     Ok(())
 }
+
+/// Checks that the manifest is well-formed without verifying anything or
+/// reading the files: every digest and symlink sigil decodes, digests are
+/// 32 bytes and each signature and signer is a well-formed ed25519 sigil of
+/// the right length. The first malformed entry is the error.
+pub fn check_manifest(opts: &VerifyOptions, manifest: &PathBuf) -> Result<Manifest> {
+    let m = load_manifest(opts, manifest)?;
+    for (path, sigil) in &m.files {
+        let checked = match manifest::sigil_symlink(sigil) {
+            Ok(Some(_)) => Ok(()),
+            Ok(None) => check_digest_sigil(sigil, opts.digest_encoding),
+            Err(e) => Err(e)
+        };
+        if let Err(e) = checked {
+            return Err(Error::InvalidSigil(format!("the files entry {} has a malformed sigil {}: {}", path, sigil, e)));
+        }
+    }
+    if let Some(ref sigil) = m.manifest_digest {
+        check_digest_sigil(sigil, opts.digest_encoding)
+            .map_err(|e| Error::InvalidSigil(format!("the manifest digest {} is malformed: {}", sigil, e)))?;
+    }
+    for (i, (pkid, sig)) in m.signatures.iter().enumerate() {
+        decode_signer(pkid).map_err(|e| Error::InvalidSigil(format!("signature {} is malformed: {}", i + 1, e)))?;
+        decode_signature(sig)
+            .map_err(|e| Error::InvalidSigil(format!("signature {} by {} is malformed: {}", i + 1, pkid, e)))?;
+    }
+    Ok(m)
+}
+
+/// Reconstructs the bytes the manifest signatures are over without verifying
+/// anything, for diagnosing signature mismatches.
+pub fn signed_payload(opts: &VerifyOptions, manifest: &PathBuf) -> Result<String> {
+    if opts.merkle {
+        let (root, size, _) = merkle::parse_root_document(&read_manifest(manifest)?)?;
+        return Ok(merkle::signed_bytes(&root, size));
+    }
+    let (_, signed, _) = load(opts, manifest)?;
+    Ok(signed)
+}
+
+// the files in the proofs are hashed like the files in a manifest and then
+// have to be included in the signed root
+fn verify_merkle(kdroot: &Option<String>,
+                 key: &Option<String>,
+                 opts: &VerifyOptions,
+                 manifest: &PathBuf) -> Result<VerifyReport> {
+
+    let (root, size, signatures) = merkle::parse_root_document(&read_manifest(manifest)?)?;
+    let proofs = match opts.proof {
+        Some(ref path) => merkle::parse_proofs(&std::fs::read_to_string(path)?)?,
+        None => return Err(Error::Syntax("a Merkle root needs the inclusion proofs to check".to_string()))
+    };
+
+    let m = Manifest {
+        files: proofs.iter().map(|p| (p.path.clone(), p.digest.clone())).collect(),
+        ..Default::default()
+    };
+    let mut files = check_files(opts, &m)?;
+    for (report, proof) in files.iter_mut().zip(proofs.iter()) {
+        if report.status == FileStatus::Ok && !merkle::check_proof(proof, &root, size)? {
+            debug!(path = %proof.path, "file is not in the Merkle tree");
+            report.status = FileStatus::Modified;
+        }
+    }
+    let signed = merkle::signed_bytes(&root, size);
+    let signatures = check_signatures(kdroot, key, opts, &signatures, &[], signed.as_bytes())?;
+    Ok(VerifyReport {
+        manifest: manifest.clone(),
+        files: files,
+        signatures: signatures,
+        timestamp: None,
+        allowed_mismatches: opts.allow_mismatch,
+        canonical: true
+    })
+}
+
+/// Verifies a manifest and reports the result of every check without
+/// printing anything. Errors are only returned when the manifest, signatures
+/// or keys couldn't be loaded at all. The key is a keyring of one or more
+/// pkids, without one the signers are looked up in the DIDDir.
+pub fn verify_manifest(kdroot: &Option<String>,
+                       key: &Option<String>,
+                       opts: &VerifyOptions,
+                       manifest: &PathBuf) -> Result<VerifyReport> {
+
+    let span = info_span!("verify", manifest = %manifest.display());
+    let _enter = span.enter();
+    crate::init()?;
+    if opts.decompress != Compression::None && !cfg!(feature = "decompress") {
+        return Err(Error::Syntax("decompressing requires the \"decompress\" feature".to_string()));
+    }
+
+    if opts.merkle {
+        return verify_merkle(kdroot, key, opts, manifest);
+    }
+
+    let (mut m, signed, canonical) = load(opts, manifest)?;
+    if let Some(ref path) = opts.sig_raw {
+        m.signatures = vec![raw_signature(key, path)?];
+    }
+    if m.context != opts.context {
+        return Err(Error::SignatureInvalid(format!("the manifest was signed for {}, not {}",
+                                                   describe_context(&m.context), describe_context(&opts.context))));
+    }
+    m.check_manifest_digest()?;
+    let mut files = check_files(opts, &m)?;
+    check_modes(opts, &m, &mut files)?;
+    files.extend(m.errors.iter().map(|(path, reason)| {
+        FileReport { path: path.to_owned(), status: FileStatus::SignerError(reason.to_owned()) }
+    }));
+    files.extend(check_tree(opts, &m)?);
+    files.extend(check_dirs(opts, &m)?);
+    files.extend(check_policy(&m)?);
+    let signatures = match opts.minisig {
+        Some(ref minisig) => vec![check_minisig(opts, minisig, signed.as_bytes())?],
+        None => check_signatures(kdroot, key, opts, &m.signatures, &m.signer_names, signed.as_bytes())?
+    };
+    let timestamp = if opts.check_tsa {
+        Some(check_timestamp(&m)?)
+    } else {
+        None
+    };
+    let report = VerifyReport {
+        manifest: manifest.clone(),
+        files: files,
+        signatures: signatures,
+        timestamp: timestamp,
+        allowed_mismatches: opts.allow_mismatch,
+        canonical: canonical
+    };
+    info!(valid = report.is_valid(), "manifest verified");
+    Ok(report)
+}
+
+pub fn verify(_verbose: bool,
+              _status_fd: &Option<u32>,
+              kdroot: &Option<String>,
+              key: &Option<String>,
+              opts: &VerifyOptions,
+              manifest: &PathBuf) -> Result<()> {
+    verify_manifest(kdroot, key, opts, manifest)?.check()
+}
+
+/// Verifies the manifests concurrently, running at most `jobs` at a time, and
+/// returns the report for each manifest in the order they were given.
+pub fn verify_all(kdroot: &Option<String>,
+                  key: &Option<String>,
+                  opts: &VerifyOptions,
+                  manifests: &Vec<PathBuf>,
+                  jobs: usize) -> Result<Vec<(PathBuf, Result<VerifyReport>)>> {
+
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(jobs)
+        .build()
+        .map_err(|e| Error::Syntax(format!("{}", e)))?;
+
+    Ok(pool.install(|| {
+        manifests.par_iter()
+            .map(|m| (m.clone(), verify_manifest(kdroot, key, opts, m)))
+            .collect()
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use std::path::Path;
+
+    fn statuses(opts: &VerifyOptions, m: &Manifest) -> Vec<FileStatus> {
+        check_files(opts, m).unwrap().into_iter().map(|report| report.status).collect()
+    }
+
+    fn manifest_of(files: &[(&str, String)]) -> Manifest {
+        let mut m = Manifest::new(&[], DigestEncoding::default()).unwrap();
+        m.files = files.iter().map(|(path, sigil)| (path.to_string(), sigil.to_owned())).collect();
+        m
+    }
+
+    #[test]
+    fn rejects_a_disallowed_algorithm() {
+        let sigil = manifest::digest_sigil(&[1; 32], fs::Algorithm::Blake3, DigestEncoding::default());
+        let m = manifest_of(&[("weak", sigil)]);
+        let opts = VerifyOptions { allowed_algorithms: Some("sha512_256".parse().unwrap()), ..Default::default() };
+        match check_files(&opts, &m) {
+            Err(Error::InvalidMeta(msg)) => assert_eq!(msg, "the blake3 digest algorithm is not allowed in weak"),
+            other => panic!("unexpected result: {:?}", other.map(|_| ()))
+        }
+    }
+
+    #[test]
+    fn rejects_an_unknown_algorithm() {
+        let m = manifest_of(&[("old", "&AAAA.md5".to_string())]);
+        match check_files(&VerifyOptions::default(), &m) {
+            Err(Error::InvalidMeta(msg)) => assert_eq!(msg, "unknown digest algorithm: md5"),
+            other => panic!("unexpected result: {:?}", other.map(|_| ()))
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinks_signed_by_content_verify() {
+        let dir = testing::scratch_dir("symlink-content");
+        testing::write(&dir.join("a"), b"contents");
+        std::os::unix::fs::symlink(dir.join("a"), dir.join("link")).unwrap();
+        let hashed = Hasher::new().progress(false).symlink_mode(SymlinkMode::Content).hash(vec![dir]).unwrap();
+        assert_eq!(hashed.hashes.len(), 2);
+        let m = Manifest::new(&hashed.hashes, DigestEncoding::default()).unwrap();
+        assert_eq!(statuses(&VerifyOptions::default(), &m), vec![FileStatus::Ok, FileStatus::Ok]);
+    }
+
+    #[test]
+    fn compact_and_pretty_manifests_both_verify() {
+        let dir = testing::scratch_dir("compact");
+        testing::write(&dir.join("tree").join("a"), b"first");
+        testing::write(&dir.join("tree").join("b"), b"second");
+        let hashed = Hasher::new().progress(false).hash_sorted(vec![dir.join("tree")]).unwrap();
+        let mut m = Manifest::new(&hashed.hashes, DigestEncoding::default()).unwrap();
+        let (pk, sk) = sign::gen_keypair();
+        let pkid = identity::pkid_from_public_key(&pk);
+        let sig = sign::sign_detached(manifest::signing_input(&m.context, m.signed_bytes()).as_bytes(), &sk);
+        m.signatures = vec![(pkid.to_owned(), signature_sigil(&sig.0))];
+
+        let pretty = dir.join("pretty.json");
+        let compact = dir.join("compact.json");
+        std::fs::write(&pretty, m.to_json()).unwrap();
+        std::fs::write(&compact, m.to_compact_json()).unwrap();
+        assert_ne!(std::fs::read(&pretty).unwrap(), std::fs::read(&compact).unwrap());
+        for path in &[pretty, compact] {
+            let report = verify_manifest(&None, &Some(pkid.to_owned()), &VerifyOptions::default(), path).unwrap();
+            assert!(report.canonical, "{} isn't canonical", path.display());
+            assert_eq!(report.signatures.len(), 1);
+            assert_eq!(report.signatures[0].status, SignatureStatus::Valid);
+            assert!(report.is_valid(), "{} doesn't verify: {:?}", path.display(), report.check());
+        }
+    }
+
+    // writes the manifest and its minisign signature and public key the way
+    // `bs sign --format minisign` does
+    fn minisigned(dir: &Path, m: &Manifest) -> VerifyOptions {
+        let (pk, sk) = sign::gen_keypair();
+        let contents = m.signed_bytes();
+        std::fs::write(dir.join("manifest.json"), &contents).unwrap();
+        let minisig = minisign::sign(contents.as_bytes(), &sk, &pk, "timestamp:1600000000").unwrap();
+        std::fs::write(dir.join("manifest.json.minisig"), minisig).unwrap();
+        std::fs::write(dir.join("minisign.pub"), minisign::public_key(&pk)).unwrap();
+        VerifyOptions {
+            minisig: Some(dir.join("manifest.json.minisig")),
+            minisign_key: Some(dir.join("minisign.pub")),
+            ..Default::default()
+        }
+    }
+
+    fn minisign_error(opts: &VerifyOptions, manifest: &PathBuf) -> Error {
+        match verify_manifest(&None, &None, opts, manifest) {
+            Err(e) => e,
+            Ok(_) => panic!("the minisigned manifest was verified")
+        }
+    }
+
+    #[test]
+    fn minisigned_manifests_are_checked_like_signed_ones() {
+        let dir = testing::scratch_dir("minisign");
+        testing::write(&dir.join("tree").join("a"), b"first");
+        let hashed = Hasher::new().progress(false).hash_sorted(vec![dir.join("tree")]).unwrap();
+        let mut m = Manifest::new(&hashed.hashes, DigestEncoding::default()).unwrap();
+        let manifest = dir.join("manifest.json");
+
+        let report = verify_manifest(&None, &None, &minisigned(&dir, &m), &manifest).unwrap();
+        assert_eq!(report.signatures[0].status, SignatureStatus::Valid);
+        assert!(report.is_valid(), "{:?}", report.check());
+
+        // the context separates minisign signatures too
+        m.context = Some("release".to_string());
+        let opts = minisigned(&dir, &m);
+        assert_eq!(minisign_error(&opts, &manifest),
+                   Error::SignatureInvalid("the manifest was signed for the context \"release\", not no context"
+                                           .to_string()));
+        let release = VerifyOptions { context: Some("release".to_string()), ..opts };
+        assert!(verify_manifest(&None, &None, &release, &manifest).unwrap().is_valid());
+
+        // a manifest digest that doesn't match the files is caught before
+        // the signature is trusted
+        m.context = None;
+        m.set_manifest_digest(DigestEncoding::default());
+        let listed = m.files[0].1.clone();
+        m.files.push((dir.join("tree").join("b").to_string_lossy().into_owned(), listed));
+        assert_eq!(minisign_error(&minisigned(&dir, &m), &manifest),
+                   Error::InvalidMeta("the manifest digest doesn't match the files it lists".to_string()));
+    }
+
+    #[test]
+    fn slash_separated_paths_verify_under_prepend() {
+        let dir = testing::scratch_dir("prepend");
+        let root = dir.join("checkout");
+        testing::write(&root.join("sub").join("a"), b"contents");
+        let hashed = Hasher::new().progress(false).hash(vec![root.join("sub").join("a")]).unwrap();
+        let mut m = Manifest::new(&hashed.hashes, DigestEncoding::default()).unwrap();
+        // recorded the way every platform writes it
+        m.files[0].0 = "sub/a".to_string();
+
+        let opts = VerifyOptions { prepend: Some(root), ..Default::default() };
+        assert_eq!(statuses(&opts, &m), vec![FileStatus::Ok]);
+        let elsewhere = VerifyOptions { prepend: Some(dir), ..Default::default() };
+        assert_eq!(statuses(&elsewhere, &m), vec![FileStatus::Missing]);
+    }
+
+    #[test]
+    fn decomposed_names_are_found_for_normalized_paths() {
+        // a file whose name is stored decomposed, the way macOS hands it out
+        let dir = testing::scratch_dir("normalize");
+        testing::write(&dir.join("cafe\u{301}").join("nai\u{308}ve"), b"contents");
+        let hashed = Hasher::new().progress(false).hash(vec![dir.join("cafe\u{301}")]).unwrap();
+        let mut m = Manifest::new(&hashed.hashes, DigestEncoding::default()).unwrap();
+        m.normalize_paths().unwrap();
+        let composed = format!("{}", dir.join("caf\u{e9}").join("na\u{ef}ve").display());
+        assert_eq!(m.files[0].0, composed);
+
+        let normalizing = VerifyOptions { normalize_paths: true, ..Default::default() };
+        assert_eq!(statuses(&normalizing, &m), vec![FileStatus::Ok]);
+        assert_eq!(fs::find_normalized(Path::new(&composed)), dir.join("cafe\u{301}").join("nai\u{308}ve"));
+        if !Path::new(&composed).exists() {
+            // the file system doesn't normalize names itself
+            assert_eq!(statuses(&VerifyOptions::default(), &m), vec![FileStatus::Missing]);
+        }
+    }
+
+    #[test]
+    fn unmatched_paths_are_left_alone() {
+        let dir = testing::scratch_dir("normalize-missing");
+        testing::write(&dir.join("file"), b"contents");
+        assert_eq!(fs::find_normalized(&dir.join("file")), dir.join("file"));
+        let missing = dir.join("caf\u{e9}").join("file");
+        assert_eq!(fs::find_normalized(&missing), missing);
+    }
+
+    fn signature_sigil(data: &[u8]) -> String {
+        format!("{}.sig.ed25519", encode_config(data, URL_SAFE))
+    }
+
+    fn signer_pkid(data: &[u8]) -> String {
+        format!("@{}.ed25519", encode_config(data, URL_SAFE))
+    }
+
+    fn assert_invalid(checked: Result<bool>, expected: &str) {
+        match checked {
+            Err(Error::SignatureInvalid(msg)) => assert_eq!(msg, expected),
+            other => panic!("unexpected result: {:?}", other)
+        }
+    }
+
+    #[test]
+    fn signatures_of_the_right_length_are_checked() {
+        let (pk, sk) = sign::gen_keypair();
+        let sig = sign::sign_detached(b"manifest", &sk);
+        let pkid = signer_pkid(&pk.0);
+        assert_eq!(check_signature(b"manifest", &pkid, &signature_sigil(&sig.0)).unwrap(), true);
+        assert_eq!(check_signature(b"tampered", &pkid, &signature_sigil(&sig.0)).unwrap(), false);
+    }
+
+    #[test]
+    fn rejects_truncated_and_oversized_signatures() {
+        let (pk, sk) = sign::gen_keypair();
+        let sig = sign::sign_detached(b"manifest", &sk);
+        let pkid = signer_pkid(&pk.0);
+        assert_invalid(check_signature(b"manifest", &pkid, &signature_sigil(&sig.0[..SIGNATUREBYTES - 1])),
+                       "the ed25519 signature is 63 bytes instead of 64");
+        let mut oversized = sig.0.to_vec();
+        oversized.push(0);
+        assert_invalid(check_signature(b"manifest", &pkid, &signature_sigil(&oversized)),
+                       "the ed25519 signature is 65 bytes instead of 64");
+        assert_invalid(check_signature(b"manifest", &pkid, &signature_sigil(&[])),
+                       "not an ed25519 signature, the sigil must be .sig.ed25519");
+        assert_invalid(check_signature(b"manifest", &pkid, &format!("{}.sig.rsa", encode_config(&sig.0[..], URL_SAFE))),
+                       "not an ed25519 signature, the sigil must be .sig.ed25519");
+    }
+
+    #[test]
+    fn rejects_truncated_and_oversized_keys() {
+        let (pk, sk) = sign::gen_keypair();
+        let sig = signature_sigil(&sign::sign_detached(b"manifest", &sk).0);
+        assert_invalid(check_signature(b"manifest", &signer_pkid(&pk.0[..PUBLICKEYBYTES - 1]), &sig),
+                       "the signer's ed25519 key is 31 bytes instead of 32");
+        let mut oversized = pk.0.to_vec();
+        oversized.push(0);
+        assert_invalid(check_signature(b"manifest", &signer_pkid(&oversized), &sig),
+                       "the signer's ed25519 key is 33 bytes instead of 32");
+        // a whole secret key in place of the public one
+        assert_invalid(check_signature(b"manifest", &signer_pkid(&sk.0), &sig),
+                       "the signer's ed25519 key is 64 bytes instead of 32");
+        let pkid = format!("@{}.x25519", encode_config(&pk.0[..], URL_SAFE));
+        assert_invalid(check_signature(b"manifest", &pkid, &sig),
+                       &format!("the signer {} is not an @<key>.ed25519 pkid", pkid));
+    }
+}